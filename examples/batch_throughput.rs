@@ -0,0 +1,21 @@
+//! Throughput of the batched simulation loop; run with `cargo run --release --example batch_throughput`
+
+use std::time::Instant;
+use csim::cache::Cache;
+use csim::rng::Rng;
+use csim::valgrind::parse;
+
+const ACCESSES: usize = 2_000_000;
+
+fn main() {
+    // Loads of one byte scattered over 2^20 blocks, so nearly all of them miss
+    let mut rng = Rng::new(0);
+    let trace: Vec<String> = (0..ACCESSES).map(|_| format!(" L {:x},1", rng.below(1 << 20) << 4)).collect();
+    let traces = parse(&trace.join("\n")).unwrap();
+
+    let mut cache = Cache::new(7, 4, 6).unwrap();
+    let start = Instant::now();
+    cache.operate_cache(traces);
+    let elapsed = start.elapsed();
+    println!("{:>10.0} accesses/s", ACCESSES as f64 / elapsed.as_secs_f64());
+}
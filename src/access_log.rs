@@ -0,0 +1,235 @@
+//! Log of every access and its outcome
+// One whitespace-separated record per access, after a `#` header line:
+//     index op address set tag outcome latency
+// with the address and tag in hex. Records are written as accesses are
+// simulated, so a store held in a store buffer is logged when it drains.
+// A CSV log has the same columns, less the latency, and a plain header row.
+
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Write};
+use crate::cache::AccessOutcome;
+use crate::valgrind::{MemoryAccess, Operation};
+
+const HEADER: &str = "# index op address set tag outcome latency";
+const CSV_HEADER: &str = "index,op,address,set,tag,outcome";
+
+pub struct AccessRecord {
+    pub operation: Operation,
+    pub address: u64,
+    pub set: u64,
+    pub tag: u64,
+    pub outcome: AccessOutcome,
+    /// Cycles taken by the access under the latency model
+    pub latency: u64,
+}
+
+pub struct AccessLog {
+    out: Box<dyn Write>,
+    records: u64,
+    error: Option<io::Error>,
+    csv: bool,
+}
+
+impl fmt::Debug for AccessLog {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("AccessLog").field("records", &self.records).finish()
+    }
+}
+
+impl AccessLog {
+    pub fn new(out: Box<dyn Write>) -> AccessLog {
+        let mut log = AccessLog { out, records: 0, error: None, csv: false };
+        log.write_line(HEADER);
+        log
+    }
+
+    /// A log of comma-separated rows, for spreadsheets and data frames
+    pub fn csv(out: Box<dyn Write>) -> AccessLog {
+        let mut log = AccessLog { out, records: 0, error: None, csv: true };
+        log.write_line(CSV_HEADER);
+        log
+    }
+
+    pub fn record(&mut self, record: &AccessRecord) {
+        let line = if self.csv {
+            format!(
+                "{},{},{:x},{},{:x},{}",
+                self.records,
+                operation_code(record.operation),
+                record.address,
+                record.set,
+                record.tag,
+                outcome_name(record.outcome),
+            )
+        } else {
+            format!(
+                "{} {} {:x} {} {:x} {} {}",
+                self.records,
+                operation_code(record.operation),
+                record.address,
+                record.set,
+                record.tag,
+                outcome_name(record.outcome),
+                record.latency,
+            )
+        };
+        self.records += 1;
+        self.write_line(&line);
+    }
+
+    /// Flush the log, reporting the first error hit while writing it
+    pub fn finish(mut self) -> io::Result<()> {
+        match self.error.take() {
+            Some(error) => Err(error),
+            None => self.out.flush(),
+        }
+    }
+
+    fn write_line(&mut self, line: &str) {
+        // Keep the first failure to report from `finish` rather than abandoning the run
+        if self.error.is_none() {
+            if let Err(error) = writeln!(self.out, "{}", line) {
+                self.error = Some(error);
+            }
+        }
+    }
+}
+
+/// Read back the address stream of a log, to replay it under another geometry
+// Stores are logged as they reach the cache, so with a store buffer the
+// replayed stream is the one the cache saw rather than the program's order.
+pub fn parse_addresses(input: &str) -> Result<Vec<MemoryAccess>, Box<dyn Error>> {
+    let mut traces = vec![];
+    for (index, line) in input.lines().enumerate() {
+        if line.starts_with('#') || line.trim().is_empty() {
+            continue;
+        }
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let (operation, address) = match fields.as_slice() {
+            [_, code, address, ..] => (operation_from_code(code), u64::from_str_radix(address, 16).ok()),
+            _ => (None, None),
+        };
+        match (operation, address) {
+            (Some(operation), Some(address)) => traces.push(MemoryAccess::new(operation, address)),
+            _ => return Err(format!("Error: Malformed access log record on line {}: \"{}\"", index + 1, line).into()),
+        }
+    }
+    Ok(traces)
+}
+
+fn operation_from_code(code: &str) -> Option<Operation> {
+    match code {
+        "L" => Some(Operation::Load),
+        "S" => Some(Operation::Store),
+        "M" => Some(Operation::Modify),
+        "I" => Some(Operation::Instruction),
+        _ => None,
+    }
+}
+
+pub(crate) fn operation_code(operation: Operation) -> &'static str {
+    match operation {
+        Operation::Load => "L",
+        Operation::Store => "S",
+        Operation::Modify => "M",
+        Operation::Instruction => "I",
+    }
+}
+
+pub(crate) fn outcome_name(outcome: AccessOutcome) -> &'static str {
+    match outcome {
+        AccessOutcome::Hit => "hit",
+        AccessOutcome::Miss => "miss",
+        AccessOutcome::MissEviction => "miss-eviction",
+        AccessOutcome::VictimHit => "victim-hit",
+        AccessOutcome::Forwarded => "forwarded",
+        AccessOutcome::WriteAround => "write-around",
+        AccessOutcome::Bypass => "bypass",
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use crate::cache::Cache;
+    use crate::latency::LatencyModel;
+    use crate::valgrind::parse;
+    use std::fs;
+
+    /// Simulate `trace` on an s/E/b cache, returning its stats and its access log
+    fn logged_run(trace: &str, set_bits: u8, lines: u32, block_bits: u8) -> (crate::cache::Statistics, String) {
+        let buffer = SharedBuffer::default();
+        let mut cache = Cache::new(set_bits, lines, block_bits).unwrap();
+        cache.set_access_log(AccessLog::new(Box::new(buffer.clone())));
+        cache.operate_cache(parse(trace).unwrap());
+        cache.take_access_log().unwrap().finish().unwrap();
+
+        let log = String::from_utf8(buffer.0.borrow().clone()).unwrap();
+        (cache.stats, log)
+    }
+
+    /// A writer whose contents outlive the log that owns it
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn latency_column() {
+        let buffer = SharedBuffer::default();
+        let mut cache = Cache::new(1, 1, 4).unwrap();
+        cache.set_latency(LatencyModel::new(2, 50, false, 4));
+        cache.set_access_log(AccessLog::new(Box::new(buffer.clone())));
+        cache.operate_cache(parse(" L 10,1\n L 18,1\n S 30,1").unwrap());
+        cache.take_access_log().unwrap().finish().unwrap();
+
+        let output = String::from_utf8(buffer.0.borrow().clone()).unwrap();
+        assert_eq!(output, "\
+# index op address set tag outcome latency
+0 L 10 1 0 miss 52
+1 L 18 1 0 hit 2
+2 S 30 1 1 miss-eviction 52
+");
+    }
+
+    #[test]
+    fn csv_rows() {
+        let buffer = SharedBuffer::default();
+        let mut cache = Cache::new(1, 1, 4).unwrap();
+        cache.set_access_log(AccessLog::csv(Box::new(buffer.clone())));
+        cache.operate_cache(parse(" L 10,1\n M 30,1").unwrap());
+        cache.take_access_log().unwrap().finish().unwrap();
+
+        let output = String::from_utf8(buffer.0.borrow().clone()).unwrap();
+        assert_eq!(output, "\
+index,op,address,set,tag,outcome
+0,L,10,1,0,miss
+1,L,30,1,1,miss-eviction
+2,S,30,1,1,hit
+");
+    }
+
+    #[test]
+    fn replay_under_new_geometry() {
+        let trace = fs::read_to_string("test/trans.trace").unwrap();
+        let (_, log) = logged_run(&trace, 2, 1, 3);
+
+        let mut replayed = Cache::new(1, 2, 4).unwrap();
+        replayed.operate_cache(parse_addresses(&log).unwrap());
+        let (direct, _) = logged_run(&trace, 1, 2, 4);
+        assert_eq!(replayed.stats, direct);
+
+        assert!(parse_addresses("0 L zz 0 0 miss 1").is_err());
+    }
+}
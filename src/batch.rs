@@ -0,0 +1,46 @@
+//! Simulate several traces one after another, reporting each and their total
+// A shared cache carries each trace's blocks over into the next, as when the
+// programs traced run back to back; otherwise the cache is reset before each
+// trace, as if it ran alone.
+
+use crate::cache::{Cache, Statistics};
+use crate::valgrind::MemoryAccess;
+
+/// Statistics of each of `traces` in turn, and of all of them together
+pub fn simulate_all(cache: &mut Cache, traces: Vec<Vec<MemoryAccess>>, shared: bool) -> (Vec<Statistics>, Statistics) {
+    let mut each = Vec::with_capacity(traces.len());
+    let mut total = Statistics::default();
+
+    for trace in traces {
+        if !shared {
+            cache.reset();
+        }
+        let before = cache.stats;
+        cache.operate_cache(trace);
+        let stats = cache.stats.since(&before);
+        total = total + stats;
+        each.push(stats);
+    }
+    (each, total)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::valgrind::parse;
+
+    #[test]
+    fn totals_cold_and_shared_runs() {
+        // The second trace reloads the first's blocks, which only a shared cache still holds
+        let traces = || vec![parse(" L 0,1\n L 10,1").unwrap(), parse(" L 0,1\n L 10,1\n L 20,1").unwrap()];
+
+        let (each, total) = simulate_all(&mut Cache::new(0, 2, 4).unwrap(), traces(), false);
+        assert_eq!(each[0], Statistics { hit: 0, miss: 2, eviction: 0, ..Statistics::default() });
+        assert_eq!(each[1], Statistics { hit: 0, miss: 3, eviction: 1, ..Statistics::default() });
+        assert_eq!(total, Statistics { hit: 0, miss: 5, eviction: 1, ..Statistics::default() });
+
+        let (each, total) = simulate_all(&mut Cache::new(0, 2, 4).unwrap(), traces(), true);
+        assert_eq!(each[1], Statistics { hit: 2, miss: 1, eviction: 1, ..Statistics::default() });
+        assert_eq!(total, Statistics { hit: 2, miss: 3, eviction: 1, ..Statistics::default() });
+    }
+}
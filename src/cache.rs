@@ -1,5 +1,28 @@
-use std::time::Instant;
-use crate::valgrind::MemoryAccess;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::error::Error;
+use std::ops::Range;
+use crate::access_log::{self, AccessLog, AccessRecord};
+use crate::columnar::Columns;
+use crate::dram::RowBuffer;
+use crate::energy::EnergyModel;
+use crate::ports::PortModel;
+use crate::progress::Progress;
+use crate::events::{Event, EventLog};
+use crate::insertion::Insertion;
+use crate::latency::LatencyModel;
+use crate::policy::{Lru, ReplacementPolicy};
+use crate::translate::PageTable;
+use crate::store_buffer::{DrainPolicy, StoreBuffer};
+use crate::valgrind::{MemoryAccess, Operation, Phase};
+use crate::victim::VictimCache;
+use crate::write_back::WriteBackBuffer;
+
+/// Heatmap shades from cold to hot
+const HEATMAP_SHADES: [char; 10] = [' ', '.', ':', '-', '=', '+', '*', '#', '%', '@'];
+const HEATMAP_CELL_WIDTH: usize = 8;
+
+/// Number of accesses decomposed together before any are simulated
+const BATCH_SIZE: usize = 1024;
 
 #[derive(Debug)]
 pub struct Cache {
@@ -7,21 +30,269 @@ pub struct Cache {
     sets: Box<[Set]>,
     set_bits: u8,
     block_bits: u8,
+    #[allow(dead_code)]
     tag_bits: u8,
-    num_lines: u8,
+    addr_bits: u8,
+    #[allow(dead_code)]
+    num_lines: u32,
+    latency: Option<LatencyModel>,
+    policy: Box<dyn ReplacementPolicy>,
+    victim: Option<VictimCache>,
+    store_buffer: Option<StoreBuffer>,
+    clock: Clock,
+    insertion: Insertion,
+    evicted_tags: Option<HashMap<u64, u64>>,
+    /// Addresses of the blocks the latest access evicted
+    evicted: Vec<u64>,
+    events: EventLog,
+    access_log: Option<AccessLog>,
+    columns: Option<Columns>,
+    physical_tags: Option<PageTable>,
+    dram: Option<RowBuffer>,
+    miss_pcs: Option<HashMap<u64, u64>>,
+    thread_stats: Option<BTreeMap<u32, ThreadStatistics>>,
+    operation_stats: Option<OperationStatistics>,
+    /// Blocks accessed so far, once misses are classified
+    seen_blocks: Option<HashSet<u64>>,
+    /// Data of the blocks written back to memory, once data is modelled
+    memory: Option<HashMap<u64, Box<[u8]>>>,
+    /// Each access and its outcome, in the words of the CS:APP reference simulator's -v
+    verbose: Option<String>,
+    count_dirty_evictions: bool,
+    write_allocate: bool,
+    count_store_misses: bool,
+    op_weights: OpWeights,
+    /// Number of sets when it need not be a power of two, indexing by modulo
+    modulo_sets: Option<u64>,
+    /// Index sets by the set bits XORed with every set-width slice of the tag
+    xor_fold: bool,
+    flush_every: Option<u64>,
+    dispatched: u64,
+    flushes: u64,
+    ports: Option<PortModel>,
+    energy: Option<EnergyModel>,
+    progress: Option<Progress>,
+    /// Accesses between snapshots of the statistics
+    snapshot_every: Option<u64>,
+    /// The statistics as they stood at the end of each interval
+    snapshots: Vec<Statistics>,
+    snapshot_accesses: u64,
+    /// Blocks, as (set, tag), whose lines are locked once filled
+    locked: HashSet<(u64, u64)>,
+    bypasses: u64,
+    write_back: Option<WriteBackBuffer>,
+    byte_valid: bool,
+    exclude_cold_sets: bool,
+    /// Fetch the next block along on every demand miss
+    prefetch_next_line: bool,
 }
 
 #[derive(Debug)]
-struct Set {
+pub struct Set {
     lines: Box<[Line]>,
+    accesses: u64,
+    misses: u64,
+    /// Whether the set has taken a miss, for excluding each set's first
+    touched: bool,
+    /// Line holding each tag, kept for sets too wide to scan on every access
+    index: Option<HashMap<u64, usize>>,
+}
+
+impl Set {
+    pub fn lines(&self) -> &[Line] {
+        &self.lines
+    }
+
+    /// Number of lines holding a block
+    pub fn occupancy(&self) -> usize {
+        self.lines.iter().filter(|line| line.valid).count()
+    }
+
+    /// Tags of the blocks the set holds, in line order
+    pub fn tags(&self) -> impl Iterator<Item = u64> + '_ {
+        self.lines.iter().filter(|line| line.valid).map(|line| line.tag)
+    }
+
+    /// The line holding the block with `tag`
+    fn find(&self, tag: u64) -> Option<usize> {
+        match &self.index {
+            Some(index) => index.get(&tag).copied(),
+            None => self.lines.iter().position(|line| line.valid && line.tag == tag),
+        }
+    }
+
+    /// Keep the index in step with line `id` taking the block with `tag` in place of `evicted`
+    fn indexed_fill(&mut self, id: usize, evicted: Option<u64>, tag: u64) {
+        if let Some(index) = &mut self.index {
+            if let Some(evicted) = evicted {
+                index.remove(&evicted);
+            }
+            index.insert(tag, id);
+        }
+    }
+
+    fn clear_index(&mut self) {
+        if let Some(index) = &mut self.index {
+            index.clear();
+        }
+    }
 }
 
 #[derive(Debug)]
-struct Line {
+pub struct Line {
     valid: bool,
     tag: u64,
+    /// The block's bytes, kept up to date only under data modelling
     block: Box<[u8]>,
-    access_time: Instant,
+    access_time: u64,
+    /// Stamp of the fill that brought the block in, unchanged by hits
+    insert_order: u64,
+    /// Hits since the block was filled
+    access_count: u64,
+    /// Filled by a prefetch and not yet hit by a demand access
+    prefetched: bool,
+    ready: u64,
+    /// Evictions the line must be chosen for before it leaves, from its fill's operation weight
+    chances: u32,
+    /// Never chosen for eviction
+    locked: bool,
+    /// Written since it was filled, so evicting it writes the block back
+    dirty: bool,
+    /// Validity of each byte, under byte-granular validity; empty otherwise
+    valid_bytes: Vec<bool>,
+}
+
+impl Line {
+    pub(crate) fn new(block_bytes: usize) -> Line {
+        Line {
+            valid: false,
+            tag: 0,
+            block: vec![0; block_bytes].into_boxed_slice(),
+            access_time: 0,
+            insert_order: 0,
+            access_count: 0,
+            prefetched: false,
+            ready: 0,
+            chances: 1,
+            locked: false,
+            dirty: false,
+            valid_bytes: vec![],
+        }
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.valid
+    }
+
+    pub fn tag(&self) -> u64 {
+        self.tag
+    }
+
+    pub fn last_access(&self) -> u64 {
+        self.access_time
+    }
+
+    pub fn insert_order(&self) -> u64 {
+        self.insert_order
+    }
+
+    pub fn access_count(&self) -> u64 {
+        self.access_count
+    }
+
+    /// Whether the line is locked, and so never chosen for eviction
+    pub fn is_locked(&self) -> bool {
+        self.locked
+    }
+
+    /// Bring the block identified by `tag` into this line at time `now`
+    pub(crate) fn fill(&mut self, tag: u64, now: u64) {
+        self.valid = true;
+        self.tag = tag;
+        self.access_time = now;
+        self.insert_order = now;
+        self.access_count = 0;
+    }
+
+    pub(crate) fn invalidate(&mut self) {
+        self.valid = false;
+    }
+
+    /// Return the line to the state `new` leaves it in, keeping its storage
+    fn clear(&mut self) {
+        self.valid = false;
+        self.tag = 0;
+        self.block.iter_mut().for_each(|byte| *byte = 0);
+        self.access_time = 0;
+        self.insert_order = 0;
+        self.access_count = 0;
+        self.prefetched = false;
+        self.ready = 0;
+        self.chances = 1;
+        self.locked = false;
+        self.dirty = false;
+        self.valid_bytes.iter_mut().for_each(|byte| *byte = false);
+    }
+}
+
+/// The number of bits indexing `bytes` bytes, or an error naming `what` unless it's a power of two
+pub fn bits_for_bytes(bytes: u64, what: &str) -> Result<u8, String> {
+    if bytes.is_power_of_two() {
+        Ok(bytes.trailing_zeros() as u8)
+    } else {
+        Err(format!("Error: {} of {} bytes isn't a power of two", what, bytes))
+    }
+}
+
+/// The bits of an `addr_bits`-wide address left for the tag once the set index and block
+/// offset are taken
+pub fn tag_bits(addr_bits: u8, set_bits: u8, block_bits: u8) -> u8 {
+    addr_bits - (set_bits + block_bits)
+}
+
+/// Most lines a cache may have across all of its sets
+pub const MAX_CACHE_LINES: u64 = 1 << 22;
+
+/// Most bytes of blocks a cache may hold, which every line allocates whether or not it
+/// models data
+pub const MAX_DATA_BYTES: u128 = 1 << 30;
+
+/// Bytes of blocks held by `num_sets` sets of `num_lines` lines of `2^block_bits` bytes
+// Wide enough for any geometry, so it's checked before anything is allocated
+pub fn data_bytes(num_sets: u64, num_lines: u32, block_bits: u8) -> u128 {
+    (u128::from(num_sets) * u128::from(num_lines)) << block_bits
+}
+
+/// Explain why a cache of `num_sets` sets of `num_lines` lines of `2^block_bits` bytes is too
+/// large to build, if it is
+pub fn check_size(num_sets: u64, num_lines: u32, block_bits: u8) -> Result<(), Box<dyn Error>> {
+    if u128::from(num_sets) * u128::from(num_lines) > u128::from(MAX_CACHE_LINES) {
+        return Err(format!(
+            "Error: {} sets of {} lines is more than the {} lines a cache may have",
+            num_sets, num_lines, MAX_CACHE_LINES,
+        ).into());
+    }
+    let bytes = data_bytes(num_sets, num_lines, block_bits);
+    if bytes > MAX_DATA_BYTES {
+        return Err(format!("Error: A cache of {} data bytes is more than the {} a cache may hold", bytes, MAX_DATA_BYTES).into());
+    }
+    Ok(())
+}
+
+/// Associativity above which sets keep a map of their tags by default
+const TAG_INDEX_ABOVE: u32 = 16;
+
+/// Source of the recency stamps LRU orders lines by
+// A count of the stamps taken rather than a time: every stamp is distinct and
+// ordering is independent of how fast the host runs.
+#[derive(Debug, Default)]
+struct Clock(u64);
+
+impl Clock {
+    fn now(&mut self) -> u64 {
+        self.0 += 1;
+        self.0
+    }
 }
 
 #[derive(PartialEq, Debug)]
@@ -31,144 +302,1567 @@ struct AddressPartition {
     block: u64,
 }
 
-#[derive(Debug)]
+/// How many evictions a line filled by a load or a store survives, less one
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OpWeights {
+    pub load: u32,
+    pub store: u32,
+}
+
+impl Default for OpWeights {
+    fn default() -> OpWeights {
+        OpWeights { load: 1, store: 1 }
+    }
+}
+
+impl OpWeights {
+    /// Parse weights such as `load:1,store:2`; an operation left out weighs 1
+    pub fn parse(spec: &str) -> Result<OpWeights, Box<dyn std::error::Error>> {
+        let mut weights = OpWeights::default();
+        for field in spec.split(',') {
+            let (operation, weight) = match field.split_once(':') {
+                Some((operation, weight)) => (operation.trim(), weight.trim().parse::<u32>()?),
+                None => return Err(format!("Error: Malformed operation weight \"{}\"", field).into()),
+            };
+            if weight == 0 {
+                return Err("Error: Operation weights must be at least 1".into());
+            }
+            match operation {
+                "load" => weights.load = weight,
+                "store" => weights.store = weight,
+                _ => return Err(format!("Error: Unknown operation \"{}\" in weights", operation).into()),
+            }
+        }
+        Ok(weights)
+    }
+
+    fn chances(&self, operation: Operation) -> u32 {
+        match operation {
+            Operation::Store => self.store,
+            _ => self.load,
+        }
+    }
+}
+
+impl Statistics {
+    pub fn hits(&self) -> u32 {
+        self.hit
+    }
+
+    pub fn misses(&self) -> u32 {
+        self.miss
+    }
+
+    pub fn evictions(&self) -> u32 {
+        self.eviction
+    }
+
+    pub fn victim_hits(&self) -> u32 {
+        self.victim_hit
+    }
+
+    pub fn forwarded(&self) -> u32 {
+        self.forwarded
+    }
+
+    pub fn dirty_evictions(&self) -> u32 {
+        self.dirty_evictions
+    }
+
+    pub fn prefetches(&self) -> u32 {
+        self.prefetches
+    }
+
+    pub fn prefetch_hits(&self) -> u32 {
+        self.prefetch_hits
+    }
+
+    pub fn compulsory_misses(&self) -> u32 {
+        self.compulsory_misses
+    }
+
+    pub fn conflict_capacity_misses(&self) -> u32 {
+        self.conflict_capacity_misses
+    }
+
+    /// The counts accumulated since `earlier` was taken
+    pub fn since(&self, earlier: &Statistics) -> Statistics {
+        Statistics {
+            hit: self.hit - earlier.hit,
+            miss: self.miss - earlier.miss,
+            eviction: self.eviction - earlier.eviction,
+            victim_hit: self.victim_hit - earlier.victim_hit,
+            forwarded: self.forwarded - earlier.forwarded,
+            dirty_evictions: self.dirty_evictions - earlier.dirty_evictions,
+            prefetches: self.prefetches - earlier.prefetches,
+            prefetch_hits: self.prefetch_hits - earlier.prefetch_hits,
+            compulsory_misses: self.compulsory_misses - earlier.compulsory_misses,
+            conflict_capacity_misses: self.conflict_capacity_misses - earlier.conflict_capacity_misses,
+        }
+    }
+
+    /// Accesses that hit or missed
+    pub fn accesses(&self) -> u64 {
+        self.hit as u64 + self.miss as u64
+    }
+
+    /// Average memory access time in cycles: every access pays `hit_time`, and misses `miss_penalty` more
+    pub fn amat(&self, hit_time: u64, miss_penalty: u64) -> f64 {
+        hit_time as f64 + self.miss_rate() * miss_penalty as f64
+    }
+
+    /// Fraction of accesses that hit, or zero before any access
+    pub fn hit_rate(&self) -> f64 {
+        match self.hit + self.miss {
+            0 => 0.0,
+            total => self.hit as f64 / total as f64,
+        }
+    }
+
+    pub fn miss_rate(&self) -> f64 {
+        match self.hit + self.miss {
+            0 => 0.0,
+            total => self.miss as f64 / total as f64,
+        }
+    }
+
+    /// The counts and miss rate as a JSON object, e.g. `{"hits":10,"misses":3,"evictions":2,"miss_rate":0.23076923076923078}`
+    pub fn to_json(&self) -> String {
+        format!(
+            "{{\"hits\":{},\"misses\":{},\"evictions\":{},\"miss_rate\":{}}}",
+            self.hit, self.miss, self.eviction, self.miss_rate(),
+        )
+    }
+
+    /// Misses per thousand of the `instructions` the trace covers
+    pub fn mpki(&self, instructions: u64) -> f64 {
+        self.miss as f64 / (instructions as f64 / 1000.0)
+    }
+}
+
+// The summary line of the CS:APP reference simulator, which graders compare against
+impl std::fmt::Display for Statistics {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "hits:{} misses:{} evictions:{}", self.hit, self.miss, self.eviction)
+    }
+}
+
+impl std::ops::Add for Statistics {
+    type Output = Statistics;
+
+    fn add(self, other: Statistics) -> Statistics {
+        Statistics {
+            hit: self.hit + other.hit,
+            miss: self.miss + other.miss,
+            eviction: self.eviction + other.eviction,
+            victim_hit: self.victim_hit + other.victim_hit,
+            forwarded: self.forwarded + other.forwarded,
+            dirty_evictions: self.dirty_evictions + other.dirty_evictions,
+            prefetches: self.prefetches + other.prefetches,
+            prefetch_hits: self.prefetch_hits + other.prefetch_hits,
+            compulsory_misses: self.compulsory_misses + other.compulsory_misses,
+            conflict_capacity_misses: self.conflict_capacity_misses + other.conflict_capacity_misses,
+        }
+    }
+}
+
+/// Format a rate with `precision` decimal places, as every rate in the output is
+pub fn format_rate(rate: f64, precision: usize) -> String {
+    format!("{:.*}", precision, rate)
+}
+
+/// Hits and misses of the accesses made by one thread, or of one kind of operation
+#[derive(Debug, Default, PartialEq)]
+pub struct ThreadStatistics {
+    pub hit: u32,
+    pub miss: u32,
+}
+
+/// Hits and misses of loads and of stores, apart
+#[derive(Debug, Default, PartialEq)]
+pub struct OperationStatistics {
+    pub load: ThreadStatistics,
+    pub store: ThreadStatistics,
+}
+
+/// What became of a single access
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AccessOutcome {
+    Hit,
+    /// Filled an invalid line
+    Miss,
+    /// Filled a line by evicting another block
+    MissEviction,
+    /// Found in the victim cache and swapped back in
+    VictimHit,
+    /// Served by a pending store in the store buffer
+    Forwarded,
+    /// A missed store written to memory without allocating a line
+    WriteAround,
+    /// A miss left unfilled because every line of its set is locked
+    Bypass,
+}
+
+#[derive(PartialEq, Debug, Clone, Copy, Default)]
 pub struct Statistics {
-    hit: u32,
-    miss: u32,
-    eviction: u32,
+    pub(crate) hit: u32,
+    pub(crate) miss: u32,
+    pub(crate) eviction: u32,
+    pub(crate) victim_hit: u32,
+    pub(crate) forwarded: u32,
+    /// Evictions of dirty lines, counted only once `track_dirty_evictions` is called
+    pub(crate) dirty_evictions: u32,
+    /// Blocks filled by the prefetcher, which count as neither hits nor misses
+    pub(crate) prefetches: u32,
+    /// Demand hits on lines the prefetcher filled, the first hit on each
+    pub(crate) prefetch_hits: u32,
+    /// Misses on a block's first access, counted only once `classify_misses` is called
+    pub(crate) compulsory_misses: u32,
+    /// Misses on a block accessed before: the conflict and capacity misses
+    pub(crate) conflict_capacity_misses: u32,
+}
+
+/// What `operate_cache` leaves the statistics at, with the figures derived from them
+// A copy: the cache's own `stats` stay the ones later runs add to
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct RunReport {
+    pub stats: Statistics,
+    /// Accesses that hit or missed
+    pub accesses: u64,
+    pub miss_rate: f64,
+}
+
+impl RunReport {
+    pub fn new(stats: Statistics) -> RunReport {
+        RunReport { stats, accesses: stats.accesses(), miss_rate: stats.miss_rate() }
+    }
+}
+
+/// Named configuration of a `Cache`, built into one by `build`
+// Defaults to a direct-mapped cache of one set of one-byte blocks, under LRU
+#[derive(Debug)]
+pub struct CacheBuilder {
+    set_bits: u8,
+    num_lines: u32,
+    block_bits: u8,
+    policy: Option<Box<dyn ReplacementPolicy>>,
+    write_back: bool,
+    next_line_prefetch: bool,
+    tag_index_above: u32,
+    /// Block size in bytes, in place of `block_bits` and checked by `build`
+    block_bytes: Option<u64>,
+}
+
+impl Default for CacheBuilder {
+    fn default() -> CacheBuilder {
+        CacheBuilder::new()
+    }
+}
+
+impl CacheBuilder {
+    pub fn new() -> CacheBuilder {
+        CacheBuilder {
+            set_bits: 0,
+            num_lines: 1,
+            block_bits: 0,
+            policy: None,
+            write_back: false,
+            next_line_prefetch: false,
+            tag_index_above: TAG_INDEX_ABOVE,
+            block_bytes: None,
+        }
+    }
+
+    /// Number of set index bits, for `2^set_bits` sets
+    pub fn set_bits(mut self, set_bits: u8) -> CacheBuilder {
+        self.set_bits = set_bits;
+        self
+    }
+
+    /// Number of lines per set
+    pub fn associativity(mut self, num_lines: u32) -> CacheBuilder {
+        self.num_lines = num_lines;
+        self
+    }
+
+    /// Number of block offset bits, for blocks of `2^block_bits` bytes
+    pub fn block_bits(mut self, block_bits: u8) -> CacheBuilder {
+        self.block_bits = block_bits;
+        self
+    }
+
+    /// Size of a block in bytes, a power of two, in place of `block_bits`
+    pub fn block_bytes(mut self, bytes: u64) -> CacheBuilder {
+        self.block_bytes = Some(bytes);
+        self
+    }
+
+    pub fn policy(mut self, policy: Box<dyn ReplacementPolicy>) -> CacheBuilder {
+        self.policy = Some(policy);
+        self
+    }
+
+    /// Count evictions of dirty lines, as `track_dirty_evictions` does
+    pub fn write_back(mut self, write_back: bool) -> CacheBuilder {
+        self.write_back = write_back;
+        self
+    }
+
+    /// Fetch the next block along on every demand miss, as `set_next_line_prefetch` does
+    pub fn next_line_prefetch(mut self, prefetch: bool) -> CacheBuilder {
+        self.next_line_prefetch = prefetch;
+        self
+    }
+
+    /// Look hits up through a map of tags, rather than by scanning the set, in caches of more
+    /// than `num_lines` lines per set; the statistics are the same either way
+    pub fn tag_index_above(mut self, num_lines: u32) -> CacheBuilder {
+        self.tag_index_above = num_lines;
+        self
+    }
+
+    /// Construct the empty, cold cache, or explain why its geometry can't be built
+    pub fn build(self) -> Result<Cache, Box<dyn Error>> {
+        let CacheBuilder { set_bits, num_lines, mut block_bits, policy, write_back, next_line_prefetch, tag_index_above, block_bytes } = self;
+        if let Some(bytes) = block_bytes {
+            block_bits = bits_for_bytes(bytes, "Block size")?;
+        }
+        if num_lines == 0 {
+            return Err("Error: A cache needs at least one line per set".into());
+        }
+        if u32::from(set_bits) + u32::from(block_bits) >= 64 {
+            return Err(format!(
+                "Error: {} set bits and {} block bits leave no tag bits in a 64-bit address",
+                set_bits, block_bits,
+            ).into());
+        }
+        check_size(1 << set_bits, num_lines, block_bits)?;
+
+        let total_sets = 1_usize << set_bits;
+        let total_bytes = 1_usize << block_bits;
+
+        Ok(Cache {
+            sets: Cache::build_sets(total_sets, num_lines, total_bytes, num_lines > tag_index_above),
+            set_bits,
+            block_bits,
+            tag_bits: tag_bits(64, set_bits, block_bits),
+            addr_bits: 64,
+            num_lines,
+            latency: None,
+            policy: policy.unwrap_or_else(|| Box::new(Lru)),
+            victim: None,
+            store_buffer: None,
+            clock: Clock::default(),
+            insertion: Insertion::Mru,
+            evicted_tags: None,
+            evicted: vec![],
+            events: EventLog::default(),
+            access_log: None,
+            columns: None,
+            physical_tags: None,
+            dram: None,
+            miss_pcs: None,
+            thread_stats: None,
+            operation_stats: None,
+            seen_blocks: None,
+            memory: None,
+            verbose: None,
+            count_dirty_evictions: write_back,
+            write_allocate: true,
+            count_store_misses: true,
+            op_weights: OpWeights::default(),
+            modulo_sets: None,
+            xor_fold: false,
+            flush_every: None,
+            dispatched: 0,
+            flushes: 0,
+            ports: None,
+            energy: None,
+            progress: None,
+            snapshot_every: None,
+            snapshots: vec![],
+            snapshot_accesses: 0,
+            locked: HashSet::new(),
+            bypasses: 0,
+            write_back: None,
+            byte_valid: false,
+            exclude_cold_sets: false,
+            prefetch_next_line: next_line_prefetch,
+            stats: Statistics::default(),
+        })
+    }
 }
 
 impl Cache {
-    /// Construct an empty, cold cache
-    pub fn new(set_bits: u8, num_lines: u8, block_bits: u8) -> Cache {
-        let total_sets = 2_u8.pow(set_bits as u32);
-        let total_bytes = 2_u8.pow(block_bits as u32);
+    /// Construct an empty, cold cache, or explain why the geometry can't be built
+    pub fn new(set_bits: u8, num_lines: u32, block_bits: u8) -> Result<Cache, Box<dyn Error>> {
+        CacheBuilder::new().set_bits(set_bits).associativity(num_lines).block_bits(block_bits).build()
+    }
+
+    /// A cache of `num_sets` sets, any number and not only a power of two, each of `num_lines`
+    /// lines of `2^block_bits` bytes. A block goes to set `block_address % num_sets`.
+    pub fn with_sets(num_sets: u64, num_lines: u32, block_bits: u8) -> Result<Cache, Box<dyn Error>> {
+        if num_sets == 0 {
+            return Err("Error: A cache needs at least one set".into());
+        }
+        check_size(num_sets, num_lines, block_bits)?;
+        let mut cache = Cache::new(0, num_lines, block_bits)?;
+        let indexed = cache.sets[0].index.is_some();
+        cache.sets = Cache::build_sets(num_sets as usize, num_lines, 1 << block_bits, indexed);
+        cache.tag_bits = tag_bits(64, 0, block_bits);
+        cache.modulo_sets = Some(num_sets);
+        Ok(cache)
+    }
 
-        let mut sets: Vec<Set> = Vec::with_capacity(total_sets as usize);
+    fn build_sets(total_sets: usize, num_lines: u32, block_bytes: usize, indexed: bool) -> Box<[Set]> {
+        let mut sets: Vec<Set> = Vec::with_capacity(total_sets);
 
         for _ in 0..total_sets {
             let mut cache_lines = Vec::with_capacity(num_lines as usize);
             for _ in 0..num_lines {
-                let mut bytes: Vec<u8> = Vec::with_capacity(total_bytes as usize);
-                bytes.resize(total_bytes as usize, 0);
-
-                cache_lines.push(Line {
-                    valid: false,
-                    tag: 0,
-                    block: bytes.into_boxed_slice(),
-                    access_time: Instant::now(),
-                })
+                cache_lines.push(Line::new(block_bytes));
             }
-            sets.push(Set { lines: cache_lines.into_boxed_slice() }); 
+            let index = indexed.then(|| HashMap::with_capacity(num_lines as usize));
+            sets.push(Set { lines: cache_lines.into_boxed_slice(), accesses: 0, misses: 0, touched: false, index });
         }
+        sets.into_boxed_slice()
+    }
 
-        Cache { 
-            sets: sets.into_boxed_slice(),
-            set_bits: set_bits,
-            block_bits: block_bits,
-            tag_bits: 64_u8 - (set_bits + block_bits),
-            num_lines: num_lines,
-            stats: Statistics { hit: 0, miss: 0, eviction: 0 },
+    /// Treat addresses as `addr_bits` wide, ignoring any bits above them, or explain why the
+    /// set index and block offset don't fit in them
+    pub fn set_addr_bits(&mut self, addr_bits: u8) -> Result<(), Box<dyn Error>> {
+        if addr_bits == 0 || addr_bits > 64 {
+            return Err("Error: Addresses must be between 1 and 64 bits wide".into());
+        }
+        if self.set_bits + self.block_bits > addr_bits {
+            return Err(format!("Error: {} set bits and {} block bits don't fit in a {}-bit address", self.set_bits, self.block_bits, addr_bits).into());
         }
+        self.addr_bits = addr_bits;
+        self.tag_bits = tag_bits(addr_bits, self.set_bits, self.block_bits);
+        Ok(())
     }
 
-    /// Iterate over the memory access stream and simulate cache accesses
-    pub fn operate_cache(&mut self, traces: Vec<MemoryAccess>) {
-        for trace in traces {
-            let parts = self.decompose(trace.address);
-            
-            match self.attempt_cache_hit(&parts) {
-                true => continue,
-                false => {},
-            }
-           
-            match self.attempt_cache_store(&parts) {
-                true => continue,
-                false => {},
-            }
-            
-            self.evict_cache_block(&parts);
+    /// Leave each set's first miss out of the statistics, as unavoidable
+    pub fn set_exclude_cold_sets(&mut self) {
+        self.exclude_cold_sets = true;
+    }
+
+    /// Keep a valid bit for every byte of a line, so a load hits only if all its bytes were
+    /// fetched or written. A store that misses allocates its line without fetching the block.
+    pub fn set_byte_valid(&mut self) {
+        self.byte_valid = true;
+        let block_bytes = 1 << self.block_bits;
+        for line in self.sets.iter_mut().flat_map(|set| set.lines.iter_mut()) {
+            line.valid_bytes = vec![false; block_bytes];
         }
     }
 
-    fn attempt_cache_hit(&mut self, parts: &AddressPartition) -> bool {
-        for line in self.sets[parts.set as usize].lines.iter_mut() {
-            if line.valid == true && line.tag == parts.tag {
-                self.stats.hit += 1;
-                line.access_time = Instant::now();
-                return true;
-            } else {
-                self.stats.miss += 1;
-                return false;
-            }
+    /// Index sets by virtual address but tag lines by the physical address `table` maps it to
+    pub fn set_virtual_index(&mut self, table: PageTable) {
+        self.physical_tags = Some(table);
+    }
+
+    /// Spread blocks across sets by XOR-folding the tag into the set index
+    pub fn set_xor_fold(&mut self) {
+        self.xor_fold = true;
+    }
+
+    /// On each demand miss, also fill the block after the one missed, if it isn't resident
+    pub fn set_next_line_prefetch(&mut self) {
+        self.prefetch_next_line = true;
+    }
+
+    /// Model access latency alongside the hit and miss counts
+    pub fn set_latency(&mut self, model: LatencyModel) {
+        self.latency = Some(model);
+    }
+
+    pub fn latency(&self) -> Option<&LatencyModel> {
+        self.latency.as_ref()
+    }
+
+    /// Replace lines with `policy` rather than LRU
+    pub fn set_policy(&mut self, policy: Box<dyn ReplacementPolicy>) {
+        self.policy = policy;
+    }
+
+    /// Place newly filled lines in the recency order according to `insertion`
+    pub fn set_insertion(&mut self, insertion: Insertion) {
+        self.insertion = insertion;
+    }
+
+    pub fn insertion(&self) -> &Insertion {
+        &self.insertion
+    }
+
+    /// Count how often each tag is evicted, for `top_evicted`
+    pub fn track_evicted_tags(&mut self) {
+        self.evicted_tags.get_or_insert_with(HashMap::new);
+    }
+
+    /// The `n` most frequently evicted tags with their eviction counts, most evicted first
+    pub fn top_evicted(&self, n: usize) -> Vec<(u64, u64)> {
+        let mut counts: Vec<(u64, u64)> = match &self.evicted_tags {
+            Some(tags) if n > 0 => tags.iter().map(|(&tag, &count)| (tag, count)).collect(),
+            _ => return vec![],
+        };
+        // Ties go to the lower tag so the report is stable
+        let order = |a: &(u64, u64), b: &(u64, u64)| b.1.cmp(&a.1).then(a.0.cmp(&b.0));
+
+        if n < counts.len() {
+            counts.select_nth_unstable_by(n - 1, order);
+            counts.truncate(n);
         }
-        false
+        counts.sort_unstable_by(order);
+        counts
     }
 
-    fn attempt_cache_store(&mut self, parts: &AddressPartition) -> bool {
-        for line in self.sets[parts.set as usize].lines.iter_mut() {
-            if line.valid == false {
-                line.valid = true;
-                line.tag = parts.tag;
-                return true;
-            }
+    /// Count misses per instruction, for `folded_misses`
+    pub fn track_miss_pcs(&mut self) {
+        self.miss_pcs.get_or_insert_with(HashMap::new);
+    }
+
+    /// Misses per instruction in the folded-stack format of flamegraph tools,
+    /// one `pc count` line per instruction in address order
+    pub fn folded_misses(&self) -> String {
+        let mut counts: Vec<(&u64, &u64)> = self.miss_pcs.iter().flatten().collect();
+        counts.sort_unstable();
+        counts.iter().map(|(pc, count)| format!("{:#x} {}\n", pc, count)).collect()
+    }
+
+    /// Count the evictions of lines written since their fill, as a write-back cache writes them back
+    pub fn track_dirty_evictions(&mut self) {
+        self.count_dirty_evictions = true;
+    }
+
+    /// Count misses on a block's first access apart from the rest, as compulsory misses
+    pub fn classify_misses(&mut self) {
+        self.seen_blocks.get_or_insert_with(HashSet::new);
+    }
+
+    pub fn classifies_misses(&self) -> bool {
+        self.seen_blocks.is_some()
+    }
+
+    /// Count hits and misses separately for each thread of the trace
+    pub fn track_threads(&mut self) {
+        self.thread_stats.get_or_insert_with(BTreeMap::new);
+    }
+
+    /// Hits and misses of each thread, in thread order
+    // Accesses without a thread ID, victim hits and forwarded loads aren't counted
+    pub fn thread_stats(&self) -> impl Iterator<Item = (&u32, &ThreadStatistics)> {
+        self.thread_stats.iter().flatten()
+    }
+
+    /// Count hits and misses of loads separately from those of stores
+    pub fn track_operations(&mut self) {
+        self.operation_stats.get_or_insert_with(OperationStatistics::default);
+    }
+
+    /// Hits and misses of loads and of stores, if tracked
+    // Counted like the thread statistics, and instruction fetches aren't counted
+    pub fn operation_stats(&self) -> Option<&OperationStatistics> {
+        self.operation_stats.as_ref()
+    }
+
+    /// Describe every access from here on and its outcome, a line per trace line
+    pub fn set_verbose(&mut self) {
+        self.verbose = Some(String::new());
+    }
+
+    /// The accesses described since `set_verbose`
+    pub fn take_verbose(&mut self) -> Option<String> {
+        self.verbose.take()
+    }
+
+    /// Keep the data of each block, so stored bytes can be read back with `peek_byte`
+    // Traces carry no data, so only `store_byte` writes any. Dirty lines are
+    // written back to a map of memory blocks on eviction and refilled from it.
+    pub fn set_data_modeling(&mut self) {
+        self.memory.get_or_insert_with(HashMap::new);
+    }
+
+    /// Simulate a one-byte store of `value` to `address`, writing the value under data modelling
+    pub fn store_byte(&mut self, address: u64, value: u8) -> AccessOutcome {
+        let outcome = self.access_trace(&MemoryAccess::new(Operation::Store, address));
+        let parts = self.decompose(address);
+        let block_address = self.block_address(parts.tag, parts.set);
+        let block_bytes = 1 << self.block_bits;
+        let line = self.sets[parts.set as usize].lines.iter_mut().find(|line| line.valid && line.tag == parts.tag);
+
+        match (line, &mut self.memory) {
+            (_, None) => {}
+            (Some(line), Some(_)) => line.block[parts.block as usize] = value,
+            // Written around the cache
+            (None, Some(memory)) => memory.entry(block_address).or_insert_with(|| vec![0; block_bytes].into_boxed_slice())[parts.block as usize] = value,
         }
-        false
+        outcome
     }
 
-    fn evict_cache_block(&mut self, parts: &AddressPartition) {
-        let mut initial_time = self.sets[parts.set as usize].lines[0].access_time.clone();
-        let mut id = 0;
-        
-        for (pos, line) in self.sets[parts.set as usize].lines.iter_mut().enumerate().skip(1) {
-            if line.access_time < initial_time {
-                initial_time = line.access_time;
-                id = pos;
+    /// The byte at `address` if its block is cached and data is modelled
+    pub fn peek_byte(&self, address: u64) -> Option<u8> {
+        self.memory.as_ref()?;
+        let parts = self.decompose(address);
+        self.sets[parts.set as usize].lines.iter()
+            .find(|line| line.valid && line.tag == parts.tag)
+            .map(|line| line.block[parts.block as usize])
+    }
+
+    /// The replacement policy's view of each set, one line per set
+    pub fn replacement_state(&self) -> String {
+        self.sets.iter().enumerate().map(|(index, set)| {
+            if set.lines.iter().any(Line::is_valid) {
+                format!("set {}: {}\n", index, self.policy.describe_state(index as u64, &set.lines))
+            } else {
+                format!("set {}: empty\n", index)
             }
-        }
+        }).collect()
+    }
 
-        self.sets[parts.set as usize].lines[id].valid = true;
-        self.sets[parts.set as usize].lines[id].tag = parts.tag;
-        self.sets[parts.set as usize].lines[id].access_time = Instant::now(); 
-        self.stats.eviction += 1;
+    /// Every valid line's tag and recency stamp, one line per non-empty set in index order
+    pub fn dump(&self) -> String {
+        self.sets.iter().enumerate().filter(|(_, set)| set.lines.iter().any(Line::is_valid)).map(|(index, set)| {
+            let lines: Vec<String> = set.lines.iter().enumerate().filter(|(_, line)| line.valid)
+                .map(|(id, line)| format!("line {} tag {:#x} stamp {}", id, line.tag, line.access_time))
+                .collect();
+            format!("set {}: {}\n", index, lines.join(", "))
+        }).collect()
     }
 
-    /// Decompose a 64-bit memory address into its constituent tag, set, and block bits
-    fn decompose(&self, address: u64) -> AddressPartition {
-        Cache::place_block(address, self.set_bits, self.block_bits)
+    /// Log every event affecting set `index`
+    pub fn trace_set(&mut self, index: u64) {
+        self.events = EventLog::for_set(index);
     }
 
-    fn place_block(address: u64, set_bits: u8, block_bits: u8) -> AddressPartition {
-        let tag_bits = 64 - (set_bits + block_bits);
-        AddressPartition {
-            tag: address >> (set_bits + block_bits),
-            set: (address << tag_bits) >> (tag_bits + block_bits),
-            block: (address << (tag_bits + set_bits)) >> (tag_bits + set_bits),
-        }
+    /// Events logged for the set chosen by `trace_set`
+    pub fn events(&self) -> &[Event] {
+        self.events.events()
     }
-}
 
-#[cfg(test)]
-mod test {
-    use super::*;
+    /// Record every access and its outcome to `log`
+    pub fn set_access_log(&mut self, log: AccessLog) {
+        self.access_log = Some(log);
+    }
 
-    #[test]
-    fn address_decomposition() {
-        // (full address, set bits, block bits)
-        let addresses: [(u64, u8, u8); 2] = [
-            (0xFFFF_FFFF_FF_DEF_ABC, 12, 12),
-            (0x12A8_FFFF_DEAD_CFFE, 16, 16),
-        ];
-        
-        // (tag, set, block)
-        let parts: [(u64, u64, u64); 2] = [
-            (0xFFFF_FFFF_FF, 0xDEF, 0xABC),
+    /// Stop logging accesses, returning the log
+    pub fn take_access_log(&mut self) -> Option<AccessLog> {
+        self.access_log.take()
+    }
+
+    /// Collect every access and its outcome into columns
+    pub fn set_columns(&mut self, columns: Columns) {
+        self.columns = Some(columns);
+    }
+
+    pub fn take_columns(&mut self) -> Option<Columns> {
+        self.columns.take()
+    }
+
+    /// Write missed stores through to memory instead of filling a line for them;
+    /// `count_misses` decides whether such stores are counted as misses
+    pub fn set_no_write_allocate(&mut self, count_misses: bool) {
+        self.write_allocate = false;
+        self.count_store_misses = count_misses;
+    }
+
+    /// Keep lines filled by loads or stores through extra evictions, whatever the policy
+    pub fn set_op_weights(&mut self, weights: OpWeights) {
+        self.op_weights = weights;
+    }
+
+    /// Invalidate every line after each `period` accesses, as a context switch would
+    pub fn set_flush_every(&mut self, period: u64) {
+        self.flush_every = Some(period);
+    }
+
+    /// Lock the line holding `address` whenever its block is filled
+    pub fn lock(&mut self, address: u64) {
+        let parts = self.decompose(address);
+        self.locked.insert((parts.set, parts.tag));
+    }
+
+    /// Number of misses not filled because every line of their set was locked
+    pub fn bypasses(&self) -> u64 {
+        self.bypasses
+    }
+
+    /// Number of times the cache was flushed
+    pub fn flushes(&self) -> u64 {
+        self.flushes
+    }
+
+    /// Invalidate every line of the cache and its victim cache
+    // Pending stores stay in the store buffer and drain into the empty cache
+    pub fn flush(&mut self) {
+        for set in self.sets.iter_mut() {
+            set.lines.iter_mut().for_each(Line::invalidate);
+            set.clear_index();
+        }
+        if let Some(victim) = &mut self.victim {
+            victim.flush();
+        }
+        self.flushes += 1;
+    }
+
+    /// Return the cache to the cold state it was built in, to simulate another trace with the
+    /// same configuration without reallocating its sets
+    // The access log and columnar record are kept, to write out each trace in turn
+    pub fn reset(&mut self) {
+        for set in self.sets.iter_mut() {
+            set.lines.iter_mut().for_each(Line::clear);
+            set.clear_index();
+            set.accesses = 0;
+            set.misses = 0;
+            set.touched = false;
+        }
+        if let Some(victim) = &mut self.victim {
+            victim.flush();
+        }
+        self.clock = Clock::default();
+        self.dispatched = 0;
+        self.flushes = 0;
+        self.bypasses = 0;
+        if let Some(threads) = &mut self.thread_stats {
+            threads.clear();
+        }
+        if let Some(operations) = &mut self.operation_stats {
+            *operations = OperationStatistics::default();
+        }
+        if let Some(memory) = &mut self.memory {
+            memory.clear();
+        }
+        if let Some(seen) = &mut self.seen_blocks {
+            seen.clear();
+        }
+        if let Some(tags) = &mut self.evicted_tags {
+            tags.clear();
+        }
+        if let Some(pcs) = &mut self.miss_pcs {
+            pcs.clear();
+        }
+        self.evicted.clear();
+        self.events.clear();
+        if let Some(buffer) = &mut self.store_buffer {
+            buffer.clear();
+        }
+        if let Some(latency) = &mut self.latency {
+            latency.reset();
+        }
+        if let Some(dram) = &mut self.dram {
+            dram.reset();
+        }
+        if let Some(ports) = &mut self.ports {
+            ports.reset();
+        }
+        if let Some(energy) = &mut self.energy {
+            energy.reset();
+        }
+        if let Some(buffer) = &mut self.write_back {
+            buffer.reset();
+        }
+        if let Some(progress) = &mut self.progress {
+            progress.reset();
+        }
+        self.snapshots.clear();
+        self.snapshot_accesses = 0;
+        self.stats = Statistics::default();
+    }
+
+    /// Send dirty evictions through a finite write-back buffer
+    pub fn set_write_back_buffer(&mut self, buffer: WriteBackBuffer) {
+        self.write_back = Some(buffer);
+    }
+
+    pub fn write_back_buffer(&self) -> Option<&WriteBackBuffer> {
+        self.write_back.as_ref()
+    }
+
+    /// Estimate the energy of the run from the costs of its events
+    pub fn set_energy(&mut self, energy: EnergyModel) {
+        self.energy = Some(energy);
+    }
+
+    pub fn energy(&self) -> Option<&EnergyModel> {
+        self.energy.as_ref()
+    }
+
+    /// Report on stderr as the accesses of `operate_cache` go by
+    pub fn set_progress(&mut self, progress: Progress) {
+        self.progress = Some(progress);
+    }
+
+    pub fn progress(&self) -> Option<&Progress> {
+        self.progress.as_ref()
+    }
+
+    /// Take a copy of the statistics after every `every` accesses of `operate_cache`
+    pub fn set_snapshot_interval(&mut self, every: u64) {
+        self.snapshot_every = Some(every);
+    }
+
+    /// What each interval added to the statistics, ending with any accesses since the last whole one
+    // Copies of the cumulative counts are kept, and differenced only here
+    pub fn snapshots(&self) -> Vec<Statistics> {
+        let mut intervals = self.snapshots.clone();
+        match self.snapshot_every {
+            Some(every) if !self.snapshot_accesses.is_multiple_of(every) => intervals.push(self.stats),
+            _ => {}
+        }
+        let mut before = Statistics::default();
+        intervals.iter().map(|&stats| {
+            let interval = stats.since(&before);
+            before = stats;
+            interval
+        }).collect()
+    }
+
+    /// Count structural stalls on the cache's read and write ports
+    pub fn set_ports(&mut self, ports: PortModel) {
+        self.ports = Some(ports);
+    }
+
+    pub fn ports(&self) -> Option<&PortModel> {
+        self.ports.as_ref()
+    }
+
+    /// Serve misses from a DRAM bank, counting its row buffer hits and conflicts
+    pub fn set_row_buffer(&mut self, dram: RowBuffer) {
+        self.dram = Some(dram);
+    }
+
+    pub fn row_buffer(&self) -> Option<&RowBuffer> {
+        self.dram.as_ref()
+    }
+
+    /// Catch evicted blocks in a fully-associative victim cache
+    pub fn set_victim_cache(&mut self, victim: VictimCache) {
+        self.victim = Some(victim);
+    }
+
+    /// Hold stores in a store buffer before they reach the cache
+    pub fn set_store_buffer(&mut self, buffer: StoreBuffer) {
+        self.store_buffer = Some(buffer);
+    }
+
+    pub fn store_buffer(&self) -> Option<&StoreBuffer> {
+        self.store_buffer.as_ref()
+    }
+
+    /// Iterate over the memory access stream and simulate cache accesses, reporting the statistics they leave
+    pub fn operate_cache(&mut self, traces: Vec<MemoryAccess>) -> RunReport {
+        self.operate_stream(traces)
+    }
+
+    /// Simulate `traces` to warm the cache, then forget what they counted
+    pub fn warm(&mut self, traces: Vec<MemoryAccess>) {
+        self.operate_cache(traces);
+        self.stats = Statistics::default();
+        self.snapshots.clear();
+        self.snapshot_accesses = 0;
+        for set in self.sets.iter_mut() {
+            set.accesses = 0;
+            set.misses = 0;
+        }
+    }
+
+    /// Simulate the trace `times` times over, returning each iteration's share of the statistics
+    // The cache is left warm between iterations, so later iterations show
+    // the steady state without the cold-start misses of the first.
+    pub fn operate_repeated(&mut self, traces: Vec<MemoryAccess>, times: usize) -> Vec<Statistics> {
+        let mut shares = Vec::with_capacity(times);
+        for _ in 0..times {
+            let before = self.stats;
+            self.operate_cache(traces.clone());
+            shares.push(self.stats.since(&before));
+        }
+        shares
+    }
+
+    /// Simulate the trace phase by phase, returning each phase's share of the statistics
+    // Accesses ahead of the first marker form an unnamed phase. Each phase
+    // drains the store buffer before the next begins.
+    pub fn operate_phases(&mut self, mut traces: Vec<MemoryAccess>, phases: &[Phase]) -> Vec<(String, Statistics)> {
+        let mut bounds: Vec<(&str, usize)> = vec![("", 0)];
+        bounds.extend(phases.iter().map(|phase| (phase.name.as_str(), phase.start)));
+
+        let mut results = vec![];
+        for (index, &(name, start)) in bounds.iter().enumerate().rev() {
+            let phase = traces.split_off(start);
+            if index > 0 || !phase.is_empty() {
+                results.push((name, phase));
+            }
+        }
+
+        results.into_iter().rev().map(|(name, phase)| {
+            let before = self.stats;
+            self.operate_cache(phase);
+            (name.to_string(), self.stats.since(&before))
+        }).collect()
+    }
+
+    /// Simulate accesses as they arrive, holding at most a batch of them at a time
+    pub fn operate_stream(&mut self, traces: impl IntoIterator<Item = MemoryAccess>) -> RunReport {
+        // Decomposing a whole batch up front keeps the trace and the sets
+        // from competing for the host's cache on every access
+        let mut traces = traces.into_iter();
+        let mut batch = Vec::with_capacity(BATCH_SIZE);
+        let mut batch_parts = Vec::with_capacity(BATCH_SIZE);
+
+        loop {
+            batch.clear();
+            batch.extend(traces.by_ref().take(BATCH_SIZE));
+            if batch.is_empty() {
+                break;
+            }
+            batch_parts.clear();
+            batch_parts.extend(batch.iter().map(|trace| self.decompose(trace.address)));
+
+            for (trace, parts) in batch.iter().zip(&batch_parts) {
+                self.dispatch(trace, parts);
+                if let Some(progress) = &mut self.progress {
+                    progress.tick();
+                }
+                if let Some(every) = self.snapshot_every {
+                    self.snapshot_accesses += 1;
+                    if self.snapshot_accesses.is_multiple_of(every) {
+                        self.snapshots.push(self.stats);
+                    }
+                }
+            }
+        }
+        self.drain_store_buffer();
+        RunReport::new(self.stats)
+    }
+
+    /// Simulate an access of `size` bytes, 0 if unknown, straight into the cache, returning what
+    /// became of it
+    // Skips the store buffer, ports and flushes that `dispatch` models ahead of the cache
+    pub fn access(&mut self, operation: Operation, address: u64, size: u8) -> AccessOutcome {
+        let parts = self.decompose(address);
+        self.reference(operation, address, size, &parts)
+    }
+
+    /// `access` a trace's access, counting it also by instruction, thread and operation
+    pub fn access_trace(&mut self, trace: &MemoryAccess) -> AccessOutcome {
+        let outcome = self.access(trace.operation, trace.address, trace.size());
+        self.attribute(trace, outcome);
+        outcome
+    }
+
+    /// Route an access through the store buffer, if any, on its way to the cache
+    fn dispatch(&mut self, trace: &MemoryAccess, parts: &AddressPartition) {
+        if let Some(period) = self.flush_every {
+            if self.dispatched > 0 && self.dispatched.is_multiple_of(period) {
+                self.flush();
+            }
+        }
+        self.dispatched += 1;
+        if let Some(ports) = &mut self.ports {
+            ports.access(trace.operation);
+        }
+        if let Some(buffer) = &mut self.write_back {
+            buffer.tick();
+        }
+
+        let buffer = match &mut self.store_buffer {
+            Some(buffer) => buffer,
+            None => {
+                let outcome = self.reference(trace.operation, trace.address, trace.size(), parts);
+                return self.attribute(trace, outcome);
+            }
+        };
+
+        let mut drained = None;
+        match trace.operation {
+            Operation::Store => drained = buffer.push(trace.address),
+            Operation::Load if buffer.forwards(trace.address) => {
+                self.stats.forwarded += 1;
+                self.log(trace.operation, trace.address, parts, AccessOutcome::Forwarded);
+            }
+            _ => {
+                let outcome = self.reference(trace.operation, trace.address, trace.size(), parts);
+                self.attribute(trace, outcome);
+            }
+        }
+
+        if let Some(buffer) = &mut self.store_buffer {
+            if drained.is_none() && buffer.drain_policy() == DrainPolicy::Eager {
+                drained = buffer.pop();
+            }
+        }
+        if let Some(address) = drained {
+            let parts = self.decompose(address);
+            self.reference(Operation::Store, address, 0, &parts);
+        }
+    }
+
+    /// Write every pending store into the cache
+    fn drain_store_buffer(&mut self) {
+        while let Some(address) = self.store_buffer.as_mut().and_then(StoreBuffer::pop) {
+            let parts = self.decompose(address);
+            self.reference(Operation::Store, address, 0, &parts);
+        }
+    }
+
+    /// Look up an access in the cache and log its outcome
+    // `size` is 0 where unknown, as for stores drained from a store buffer
+    fn reference(&mut self, operation: Operation, address: u64, size: u8, parts: &AddressPartition) -> AccessOutcome {
+        self.evicted.clear();
+        let block_address = self.block_address(parts.tag, parts.set);
+        let first_access = self.seen_blocks.as_mut().map(|seen| seen.insert(block_address));
+        let misses = self.stats.miss;
+        let outcome = self.simulate(operation, size, parts);
+        // Classify only the misses counted, whichever way the access missed
+        match first_access {
+            Some(true) if self.stats.miss > misses => self.stats.compulsory_misses += 1,
+            Some(false) if self.stats.miss > misses => self.stats.conflict_capacity_misses += 1,
+            _ => {}
+        }
+        if let Some(energy) = &mut self.energy {
+            energy.access(outcome);
+        }
+        if self.prefetch_next_line && matches!(outcome, AccessOutcome::Miss | AccessOutcome::MissEviction) {
+            let next = self.block_address(parts.tag, parts.set).wrapping_add(1);
+            self.prefetch(next);
+        }
+        self.log(operation, address, parts, outcome);
+        outcome
+    }
+
+    /// Charge a hit or miss to the instruction and thread that made the access
+    // Stores drained from a store buffer have lost both and go unattributed
+    fn attribute(&mut self, trace: &MemoryAccess, outcome: AccessOutcome) {
+        if let Some(verbose) = &mut self.verbose {
+            Cache::describe(verbose, trace, outcome);
+        }
+        let missed = match outcome {
+            AccessOutcome::Hit => false,
+            AccessOutcome::Miss | AccessOutcome::MissEviction | AccessOutcome::Bypass => true,
+            AccessOutcome::WriteAround if self.count_store_misses => true,
+            _ => return,
+        };
+
+        if let (Some(counts), Some(pc), true) = (&mut self.miss_pcs, trace.pc, missed) {
+            *counts.entry(pc).or_insert(0) += 1;
+        }
+        if let (Some(threads), Some(thread)) = (&mut self.thread_stats, trace.thread) {
+            let stats = threads.entry(thread).or_default();
+            if missed {
+                stats.miss += 1;
+            } else {
+                stats.hit += 1;
+            }
+        }
+        if let Some(operations) = &mut self.operation_stats {
+            let stats = match trace.operation {
+                Operation::Load => &mut operations.load,
+                Operation::Store => &mut operations.store,
+                _ => return,
+            };
+            if missed {
+                stats.miss += 1;
+            } else {
+                stats.hit += 1;
+            }
+        }
+    }
+
+    /// Append the access and its outcome, as `L 10,1 miss` or, for a modify, `M 20,1 miss hit`
+    // A modify's load starts its line and its store, dispatched next, ends it
+    fn describe(verbose: &mut String, trace: &MemoryAccess, outcome: AccessOutcome) {
+        let outcome = match outcome {
+            AccessOutcome::Hit | AccessOutcome::Forwarded => "hit",
+            AccessOutcome::Miss | AccessOutcome::WriteAround | AccessOutcome::Bypass => "miss",
+            AccessOutcome::MissEviction => "miss eviction",
+            AccessOutcome::VictimHit => "victim hit",
+        };
+        match (trace.is_modify(), trace.operation) {
+            (true, Operation::Load) => verbose.push_str(&format!("M {:x},{} {}", trace.address, trace.size(), outcome)),
+            (true, _) => verbose.push_str(&format!(" {}\n", outcome)),
+            (false, operation) => verbose.push_str(&format!(
+                "{} {:x},{} {}\n", access_log::operation_code(operation), trace.address, trace.size(), outcome,
+            )),
+        }
+    }
+
+    fn log(&mut self, operation: Operation, address: u64, parts: &AddressPartition, outcome: AccessOutcome) {
+        if self.access_log.is_none() && self.columns.is_none() {
+            return;
+        }
+        let latency = match (&self.latency, outcome) {
+            (_, AccessOutcome::Forwarded) | (None, _) => 0,
+            (Some(model), _) => model.last_latency(),
+        };
+        let record = AccessRecord { operation, address, set: parts.set, tag: parts.tag, outcome, latency };
+        if let Some(log) = &mut self.access_log {
+            log.record(&record);
+        }
+        if let Some(columns) = &mut self.columns {
+            columns.record(&record);
+        }
+    }
+
+    fn simulate(&mut self, operation: Operation, size: u8, parts: &AddressPartition) -> AccessOutcome {
+        let block_address = self.block_address(parts.tag, parts.set);
+        self.sets[parts.set as usize].accesses += 1;
+        self.events.emit(Event::Access { set: parts.set, tag: parts.tag });
+        self.policy.access(block_address);
+
+        // The bytes of the block the access touches; one of unknown size touches the byte at its address
+        let start = parts.block as usize;
+        let bytes = start..(start + size.max(1) as usize).min(1 << self.block_bits);
+        if self.byte_valid {
+            if let Some(outcome) = self.attempt_partial_hit(operation, parts, &bytes) {
+                return outcome;
+            }
+        }
+
+        if self.attempt_cache_hit(operation, parts) {
+            return AccessOutcome::Hit;
+        }
+
+        if operation == Operation::Store && !self.write_allocate {
+            return self.write_around(block_address, parts);
+        }
+
+        // A block found in the victim cache is swapped back in rather than refetched
+        let outcome = if self.victim.as_mut().is_some_and(|victim| victim.take(block_address)) {
+            self.stats.victim_hit += 1;
+            AccessOutcome::VictimHit
+        } else {
+            let set = &mut self.sets[parts.set as usize];
+            if set.touched || !self.exclude_cold_sets {
+                self.stats.miss += 1;
+            }
+            set.touched = true;
+            set.misses += 1;
+            self.insertion.record_miss(parts.set);
+            if let Some(dram) = &mut self.dram {
+                dram.access(block_address << self.block_bits);
+            }
+            AccessOutcome::MissEviction
+        };
+
+        if let Some(id) = self.attempt_cache_store(operation, parts) {
+            self.fill_bytes(parts.set, id, operation, bytes);
+            return match outcome {
+                AccessOutcome::MissEviction => AccessOutcome::Miss,
+                outcome => outcome,
+            };
+        }
+
+        if self.sets[parts.set as usize].lines.iter().all(|line| line.locked) {
+            self.bypasses += 1;
+            if let Some(model) = &mut self.latency {
+                model.miss();
+            }
+            return AccessOutcome::Bypass;
+        }
+
+        let id = self.evict_cache_block(operation, parts);
+        self.fill_bytes(parts.set, id, operation, bytes);
+        outcome
+    }
+
+    /// Serve an access to a resident line some of whose `bytes` aren't valid: a store
+    /// validates them and hits, and a load misses and fetches the rest of the block
+    fn attempt_partial_hit(&mut self, operation: Operation, parts: &AddressPartition, bytes: &Range<usize>) -> Option<AccessOutcome> {
+        let id = self.sets[parts.set as usize].lines.iter().position(|line| {
+            line.valid && line.tag == parts.tag && !line.valid_bytes[bytes.clone()].iter().all(|&valid| valid)
+        })?;
+        let now = self.clock.now();
+        let line = &mut self.sets[parts.set as usize].lines[id];
+        line.access_time = now;
+
+        if operation == Operation::Store {
+            line.valid_bytes[bytes.clone()].iter_mut().for_each(|valid| *valid = true);
+            line.dirty = true;
+            line.access_count += 1;
+            self.stats.hit += 1;
+            self.events.emit(Event::Hit { set: parts.set, line: id, tag: parts.tag });
+            if let Some(model) = &mut self.latency {
+                model.hit(line.ready);
+            }
+            return Some(AccessOutcome::Hit);
+        }
+
+        line.valid_bytes.iter_mut().for_each(|valid| *valid = true);
+        self.stats.miss += 1;
+        self.sets[parts.set as usize].misses += 1;
+        self.events.emit(Event::Fill { set: parts.set, line: id, tag: parts.tag });
+        if let Some(model) = &mut self.latency {
+            self.sets[parts.set as usize].lines[id].ready = model.miss();
+        }
+        Some(AccessOutcome::Miss)
+    }
+
+    /// Mark the bytes a fill brought in: a store allocates only the bytes it writes
+    fn fill_bytes(&mut self, set: u64, id: usize, operation: Operation, bytes: Range<usize>) {
+        if !self.byte_valid {
+            return;
+        }
+        let valid_bytes = &mut self.sets[set as usize].lines[id].valid_bytes;
+        let fetched = operation != Operation::Store;
+        valid_bytes.iter_mut().for_each(|valid| *valid = fetched);
+        valid_bytes[bytes].iter_mut().for_each(|valid| *valid = true);
+    }
+
+    /// Warn when the run was trivial: nothing was evicted because the trace's
+    /// `distinct_blocks` all fit in the cache at once
+    pub fn footprint_warning(&self, distinct_blocks: usize) -> Option<String> {
+        let capacity: usize = self.sets.iter().map(|set| set.lines.len()).sum();
+        if self.stats.eviction == 0 && distinct_blocks < capacity {
+            Some(format!(
+                "warning: cache never evicted; trace footprint fits entirely ({} blocks in {} lines), try a smaller cache",
+                distinct_blocks, capacity,
+            ))
+        } else {
+            None
+        }
+    }
+
+    /// Warn when no address of `traces` reaches the tag: the set index and block offset take
+    /// every bit the addresses use, so no two blocks can conflict
+    pub fn tag_warning(&self, traces: &[MemoryAccess]) -> Option<String> {
+        if traces.is_empty() || traces.iter().any(|trace| self.decompose(trace.address).tag != 0) {
+            return None;
+        }
+        Some(String::from(
+            "warning: every address has tag 0, so no two blocks of the trace share a set; -s and -b may take more bits than its addresses have",
+        ))
+    }
+
+    pub fn block_bytes(&self) -> u64 {
+        1 << self.block_bits
+    }
+
+    /// Addresses of the blocks the latest access evicted, for a level keeping track of this one
+    pub fn evicted(&self) -> &[u64] {
+        &self.evicted
+    }
+
+    /// Place the block holding `address` in the cache as another level hands it over, counting
+    /// no hit or miss; filling it may evict another
+    pub fn install(&mut self, address: u64) {
+        self.evicted.clear();
+        self.fill_block(address >> self.block_bits);
+    }
+
+    /// Drop the block holding `address`, as another level takes it back, saying whether it was
+    /// cached
+    pub fn invalidate(&mut self, address: u64) -> bool {
+        let parts = self.decompose(address);
+        let set = &mut self.sets[parts.set as usize];
+        match set.find(parts.tag) {
+            Some(id) => {
+                set.lines[id].invalidate();
+                if let Some(index) = &mut set.index {
+                    index.remove(&parts.tag);
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Number of lines holding a block
+    pub fn valid_lines(&self) -> usize {
+        self.sets.iter().flat_map(|set| set.lines.iter()).filter(|line| line.valid).count()
+    }
+
+    /// The sets in index order, for reports of their contents
+    pub fn sets(&self) -> impl Iterator<Item = &Set> {
+        self.sets.iter()
+    }
+
+    /// Number of accesses that mapped to each set
+    pub fn set_accesses(&self) -> Vec<u64> {
+        self.sets.iter().map(|set| set.accesses).collect()
+    }
+
+    /// Fraction of each set's accesses that missed, or zero for a set never accessed
+    pub fn set_miss_rates(&self) -> Vec<f64> {
+        self.sets.iter().map(|set| {
+            if set.accesses == 0 {
+                0.0
+            } else {
+                set.misses as f64 / set.accesses as f64
+            }
+        }).collect()
+    }
+
+    /// Render per-set access counts as rows of shaded characters, hottest set darkest
+    pub fn heatmap(&self) -> String {
+        let counts = self.set_accesses();
+        let max = counts.iter().copied().max().unwrap_or(0);
+        let width = (counts.len().saturating_sub(1)).to_string().len();
+
+        let mut map = String::new();
+        for (index, &count) in counts.iter().enumerate() {
+            let cell: String = std::iter::repeat_n(Cache::shade(count, max), HEATMAP_CELL_WIDTH).collect();
+            map.push_str(&format!("{:>width$} |{}| {}\n", index, cell, count, width = width));
+        }
+        map
+    }
+
+    fn shade(count: u64, max: u64) -> char {
+        if count == 0 {
+            return HEATMAP_SHADES[0];
+        }
+        // Any access at all lands on at least the lightest visible shade
+        let levels = (HEATMAP_SHADES.len() - 1) as u64;
+        let level = (count * levels).div_ceil(max);
+        HEATMAP_SHADES[level as usize]
+    }
+
+    /// Write a missed store straight through to memory without allocating a line
+    fn write_around(&mut self, block_address: u64, parts: &AddressPartition) -> AccessOutcome {
+        if self.count_store_misses {
+            self.stats.miss += 1;
+            self.sets[parts.set as usize].misses += 1;
+        }
+        if let Some(dram) = &mut self.dram {
+            dram.access(block_address << self.block_bits);
+        }
+        // The write is posted to memory, so the access itself does not wait on it
+        if let Some(model) = &mut self.latency {
+            model.hit(0);
+        }
+        AccessOutcome::WriteAround
+    }
+
+    /// Fill the block `block_address` without counting a hit or miss
+    fn prefetch(&mut self, block_address: u64) {
+        if let Some((set, id)) = self.fill_block(block_address) {
+            self.stats.prefetches += 1;
+            self.sets[set as usize].lines[id].prefetched = true;
+        }
+    }
+
+    /// Fill the block numbered `block_address` unless it's cached already or its set is all
+    /// locked, returning the set and line it went to
+    fn fill_block(&mut self, block_address: u64) -> Option<(u64, usize)> {
+        let parts = self.decompose(block_address << self.block_bits);
+        let set = &self.sets[parts.set as usize];
+        if set.find(parts.tag).is_some() || set.lines.iter().all(|line| line.locked) {
+            return None;
+        }
+
+        let id = match self.attempt_cache_store(Operation::Load, &parts) {
+            Some(id) => id,
+            None => self.evict_cache_block(Operation::Load, &parts),
+        };
+        let block_bytes = 1 << self.block_bits;
+        self.fill_bytes(parts.set, id, Operation::Load, 0..block_bytes);
+        Some((parts.set, id))
+    }
+
+    fn attempt_cache_hit(&mut self, operation: Operation, parts: &AddressPartition) -> bool {
+        let set = &mut self.sets[parts.set as usize];
+        let id = match set.find(parts.tag) {
+            Some(id) => id,
+            None => return false,
+        };
+        let line = &mut set.lines[id];
+        self.stats.hit += 1;
+        line.dirty |= operation == Operation::Store;
+        self.events.emit(Event::Hit { set: parts.set, line: id, tag: parts.tag });
+        line.access_time = self.clock.now();
+        line.access_count += 1;
+        if line.prefetched {
+            line.prefetched = false;
+            self.stats.prefetch_hits += 1;
+        }
+        if let Some(model) = &mut self.latency {
+            model.hit(line.ready);
+        }
+        true
+    }
+
+    fn attempt_cache_store(&mut self, operation: Operation, parts: &AddressPartition) -> Option<usize> {
+        let chances = self.op_weights.chances(operation);
+        let id = self.sets[parts.set as usize].lines.iter().position(|line| !line.valid)?;
+        let line = &mut self.sets[parts.set as usize].lines[id];
+        let now = self.clock.now();
+        line.valid = true;
+        line.tag = parts.tag;
+        line.access_time = self.insertion.stamp(parts.set, now);
+        line.insert_order = now;
+        line.access_count = 0;
+        line.prefetched = false;
+        line.chances = chances;
+        line.locked = self.locked.contains(&(parts.set, parts.tag));
+        line.dirty = operation == Operation::Store;
+        self.events.emit(Event::Fill { set: parts.set, line: id, tag: parts.tag });
+        if let Some(model) = &mut self.latency {
+            line.ready = model.miss();
+        }
+        self.sets[parts.set as usize].indexed_fill(id, None, parts.tag);
+        self.load_block(parts, id);
+        Some(id)
+    }
+
+    /// Bring the data of the block just filled into line `id`, under data modelling
+    fn load_block(&mut self, parts: &AddressPartition, id: usize) {
+        let block_address = self.block_address(parts.tag, parts.set);
+        if let Some(memory) = &self.memory {
+            let block = &mut self.sets[parts.set as usize].lines[id].block;
+            match memory.get(&block_address) {
+                Some(data) => block.copy_from_slice(data),
+                None => block.iter_mut().for_each(|byte| *byte = 0),
+            }
+        }
+    }
+
+    fn evict_cache_block(&mut self, operation: Operation, parts: &AddressPartition) -> usize {
+        let set = &mut self.sets[parts.set as usize];
+        // The policy passes over locked lines; the caller bypasses a set with no other
+        let id = loop {
+            let id = self.policy.choose_victim(&set.lines);
+            let line = &mut set.lines[id];
+            if line.chances <= 1 {
+                break id;
+            }
+            // A weighted line survives this eviction as though it had just been filled,
+            // so that FIFO moves on to another line
+            line.chances -= 1;
+            let now = self.clock.now();
+            line.access_time = now;
+            line.insert_order = now;
+        };
+        let evicted_tag = self.sets[parts.set as usize].lines[id].tag;
+        let evicted_block = self.block_address(evicted_tag, parts.set);
+        if self.sets[parts.set as usize].lines[id].dirty {
+            if let Some(memory) = &mut self.memory {
+                memory.insert(evicted_block, self.sets[parts.set as usize].lines[id].block.clone());
+            }
+            if self.count_dirty_evictions {
+                self.stats.dirty_evictions += 1;
+            }
+            if let Some(buffer) = &mut self.write_back {
+                buffer.enqueue();
+            }
+            if let Some(energy) = &mut self.energy {
+                energy.writeback();
+            }
+        }
+
+        if let Some(victim) = &mut self.victim {
+            victim.insert(evicted_block);
+        }
+        self.evicted.push(evicted_block << self.block_bits);
+        if let Some(tags) = &mut self.evicted_tags {
+            *tags.entry(evicted_tag).or_insert(0) += 1;
+        }
+        self.events.emit(Event::Eviction { set: parts.set, line: id, victim: evicted_tag });
+        self.events.emit(Event::Fill { set: parts.set, line: id, tag: parts.tag });
+
+        self.sets[parts.set as usize].lines[id].valid = true;
+        self.sets[parts.set as usize].lines[id].tag = parts.tag;
+        self.sets[parts.set as usize].indexed_fill(id, Some(evicted_tag), parts.tag);
+        let now = self.clock.now();
+        self.sets[parts.set as usize].lines[id].access_time = self.insertion.stamp(parts.set, now);
+        self.sets[parts.set as usize].lines[id].insert_order = now;
+        self.sets[parts.set as usize].lines[id].access_count = 0;
+        self.sets[parts.set as usize].lines[id].prefetched = false;
+        self.sets[parts.set as usize].lines[id].chances = self.op_weights.chances(operation);
+        self.sets[parts.set as usize].lines[id].locked = self.locked.contains(&(parts.set, parts.tag));
+        self.sets[parts.set as usize].lines[id].dirty = operation == Operation::Store;
+        if let Some(model) = &mut self.latency {
+            self.sets[parts.set as usize].lines[id].ready = model.miss();
+        }
+        self.load_block(parts, id);
+        self.stats.eviction += 1;
+        id
+    }
+
+    /// The number of the block with `tag` in `set`, undoing `decompose`
+    fn block_address(&self, tag: u64, set: u64) -> u64 {
+        match self.modulo_sets {
+            Some(num_sets) => tag * num_sets + set,
+            None if self.xor_fold => (tag << self.set_bits) | (set ^ self.fold(tag)),
+            None => (tag << self.set_bits) | set,
+        }
+    }
+
+    /// XOR of the tag's slices as wide as the set index
+    fn fold(&self, mut tag: u64) -> u64 {
+        if self.set_bits == 0 {
+            return 0;
+        }
+        let mut folded = 0;
+        while tag != 0 {
+            folded ^= tag & ((1 << self.set_bits) - 1);
+            tag >>= self.set_bits;
+        }
+        folded
+    }
+
+    /// Decompose a memory address into its constituent tag, set, and block bits
+    fn decompose(&self, address: u64) -> AddressPartition {
+        if let Some(num_sets) = self.modulo_sets {
+            let address = address & (u64::MAX >> (64 - self.addr_bits));
+            let block_address = address >> self.block_bits;
+            return AddressPartition {
+                tag: block_address / num_sets,
+                set: block_address % num_sets,
+                block: address & ((1 << self.block_bits) - 1),
+            };
+        }
+
+        let mut parts = Cache::place_block(address, self.set_bits, self.block_bits, self.addr_bits);
+        if self.xor_fold {
+            // The tag is kept whole, so the block stays identifiable
+            parts.set ^= self.fold(parts.tag);
+        }
+
+        // Virtually indexed, physically tagged: the tag is every physical bit above
+        // the page offset that the index does not already take from the page offset
+        if let Some(table) = &self.physical_tags {
+            // Strict tables are checked against the whole trace before the run
+            let physical = table.translate(address).unwrap_or(address) & (u64::MAX >> (64 - self.addr_bits));
+            parts.tag = physical >> (self.set_bits + self.block_bits).min(table.page_bits());
+        }
+        parts
+    }
+
+    fn place_block(address: u64, set_bits: u8, block_bits: u8, addr_bits: u8) -> AddressPartition {
+        let address = address & (u64::MAX >> (64 - addr_bits));
+        // Any of the fields may be empty or the whole address, where a plain shift would overflow
+        let low_bits = |bits: u8| u64::MAX.checked_shr(64 - bits as u32).unwrap_or(0);
+        AddressPartition {
+            tag: address.checked_shr((set_bits + block_bits) as u32).unwrap_or(0),
+            set: address.checked_shr(block_bits as u32).unwrap_or(0) & low_bits(set_bits),
+            block: address & low_bits(block_bits),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::policy::Random;
+    use crate::valgrind::parse;
+
+    #[test]
+    #[allow(clippy::unusual_byte_groupings)]
+    fn address_decomposition() {
+        // (full address, set bits, block bits)
+        let addresses: [(u64, u8, u8); 2] = [
+            (0xFFFF_FFFF_FF_DEF_ABC, 12, 12),
+            (0x12A8_FFFF_DEAD_CFFE, 16, 16),
+        ];
+        
+        // (tag, set, block)
+        let parts: [(u64, u64, u64); 2] = [
+            (0xFFFF_FFFF_FF, 0xDEF, 0xABC),
             (0x12A8_FFFF, 0xDEAD, 0xCFFE),
         ];
         
         for i in 0..addresses.len() {
             assert_eq!(
-                Cache::place_block(addresses[i].0, addresses[i].1, addresses[i].2), 
+                Cache::place_block(addresses[i].0, addresses[i].1, addresses[i].2, 64), 
                 AddressPartition {
                     tag: parts[i].0,
                     set: parts[i].1,
@@ -177,4 +1871,806 @@ mod test {
             );
         }
     }
+
+    #[test]
+    fn statistics_in_csapp_format() {
+        let stats = Statistics { hit: 167, miss: 71, eviction: 67, ..Statistics::default() };
+        assert_eq!((stats.hits(), stats.misses(), stats.evictions()), (167, 71, 67));
+        assert_eq!(stats.to_string(), "hits:167 misses:71 evictions:67");
+        assert_eq!(stats.accesses(), 238);
+        assert_eq!(stats.amat(1, 238), 72.0);
+        assert_eq!(Statistics::default().amat(1, 100), 1.0);
+        assert_eq!(Statistics::default().to_json(), r#"{"hits":0,"misses":0,"evictions":0,"miss_rate":0}"#);
+    }
+
+    #[test]
+    fn dirty_evictions_counted() {
+        // The store's line is evicted dirty by 0x10, whose own clean line 0x20 evicts
+        let trace = " S 0,1\n L 10,1\n L 20,1";
+        let mut cache = Cache::new(0, 1, 4).unwrap();
+        cache.track_dirty_evictions();
+        cache.operate_cache(parse(trace).unwrap());
+        assert_eq!((cache.stats.eviction, cache.stats.dirty_evictions), (2, 1));
+
+        let mut untracked = Cache::new(0, 1, 4).unwrap();
+        untracked.operate_cache(parse(trace).unwrap());
+        assert_eq!(untracked.stats.dirty_evictions, 0);
+    }
+
+    #[test]
+    fn next_line_prefetch_on_ascending_stream() {
+        // Each miss brings in the next block, which the access after it hits
+        let trace: Vec<String> = (0..16).map(|block| format!(" L {:x},1", block << 4)).collect();
+        let trace = parse(&trace.join("\n")).unwrap();
+
+        let mut plain = Cache::new(2, 2, 4).unwrap();
+        plain.operate_cache(trace.clone());
+        assert_eq!((plain.stats.hit, plain.stats.miss), (0, 16));
+
+        let mut prefetching = Cache::new(2, 2, 4).unwrap();
+        prefetching.set_next_line_prefetch();
+        prefetching.operate_cache(trace);
+        let stats = prefetching.stats;
+        assert_eq!((stats.hit, stats.miss, stats.prefetches, stats.prefetch_hits), (8, 8, 8, 8));
+    }
+
+    #[test]
+    fn run_report_matches_stats() {
+        let mut cache = Cache::new(1, 1, 4).unwrap();
+        let report = cache.operate_cache(parse(" L 0,1\n L 0,1\n S 10,1\n L 20,1").unwrap());
+        assert_eq!(report.stats, cache.stats);
+        assert_eq!((report.accesses, report.miss_rate), (4, 0.75));
+
+        // A second run reports the totals of both
+        let report = cache.operate_cache(parse(" L 0,1").unwrap());
+        assert_eq!(report, RunReport::new(Statistics { hit: 1, miss: 4, eviction: 2, ..Statistics::default() }));
+    }
+
+    #[test]
+    fn snapshots_follow_hit_rate() {
+        // Eight blocks thrash the one line, then the last of them is loaded eight times more
+        let trace: String = (0..8).chain([7; 8]).map(|block| format!(" L {:x},1\n", block << 4)).collect();
+        let snapshots = |every| {
+            let mut cache = Cache::new(0, 1, 4).unwrap();
+            cache.set_snapshot_interval(every);
+            cache.operate_cache(parse(&trace).unwrap());
+            cache.snapshots()
+        };
+
+        assert_eq!(snapshots(8), [
+            Statistics { hit: 0, miss: 8, eviction: 7, ..Statistics::default() },
+            Statistics { hit: 8, miss: 0, eviction: 0, ..Statistics::default() },
+        ]);
+        // The six accesses after the last whole interval come last
+        assert_eq!(snapshots(10), [
+            Statistics { hit: 2, miss: 8, eviction: 7, ..Statistics::default() },
+            Statistics { hit: 6, miss: 0, eviction: 0, ..Statistics::default() },
+        ]);
+    }
+
+    #[test]
+    fn thirty_two_bit_addresses() {
+        assert_eq!(
+            Cache::place_block(0xFFFF_FFFF, 4, 4, 32),
+            AddressPartition { tag: 0xFF_FFFF, set: 0xF, block: 0xF },
+        );
+
+        // Bits above the 32nd are not part of the tag, so 0x1_0000_0010 is 0x10
+        let mut cache = Cache::new(4, 1, 4).unwrap();
+        cache.set_addr_bits(32).unwrap();
+        assert_eq!(cache.tag_bits, 24);
+        cache.operate_cache(parse(" L 10,1\n L 100000010,1").unwrap());
+        assert_eq!(cache.stats, Statistics { hit: 1, miss: 1, eviction: 0, ..Statistics::default() });
+    }
+
+    #[test]
+    fn no_tag_bits() {
+        assert_eq!(
+            Cache::place_block(0xDEAD_BEEF_0123_4567, 4, 60, 64),
+            AddressPartition { tag: 0, set: 0xD, block: 0xEAD_BEEF_0123_4567 },
+        );
+
+        // With 6-bit addresses, the set and block bits leave no tag, so 0x40
+        // aliases 0x0: each set holds the one block it can tell apart
+        let mut cache = Cache::new(2, 1, 4).unwrap();
+        cache.set_addr_bits(6).unwrap();
+        cache.operate_cache(parse(" L 0,1\n L 40,1\n L 10,1").unwrap());
+        assert_eq!(cache.stats, Statistics { hit: 1, miss: 2, eviction: 0, ..Statistics::default() });
+    }
+
+    #[test]
+    fn equal_tags_in_other_sets_never_hit() {
+        // 0x110 and 0x120 share tag 4 but map to sets 1 and 2; a lookup compares
+        // tags only within the one set, so neither may hit the other's line
+        let (a, b) = (Cache::place_block(0x110, 2, 4, 64), Cache::place_block(0x120, 2, 4, 64));
+        assert_eq!((a.tag, a.set), (4, 1));
+        assert_eq!((b.tag, b.set), (4, 2));
+
+        let mut cache = Cache::new(2, 1, 4).unwrap();
+        cache.operate_cache(parse(" L 110,1\n L 120,1\n L 110,1\n L 120,1").unwrap());
+        assert_eq!(cache.stats, Statistics { hit: 2, miss: 2, eviction: 0, ..Statistics::default() });
+        assert_eq!(cache.valid_lines(), 2);
+    }
+
+    #[test]
+    fn reset_forgets_earlier_trace() {
+        let mut cache = Cache::new(1, 1, 4).unwrap();
+        cache.track_evicted_tags();
+        cache.set_energy(EnergyModel::new(crate::energy::EnergyCosts { hit: 1.0, miss: 10.0, writeback: 0.0, fill: 0.0 }));
+        cache.operate_cache(parse(" L 0,1\n L 20,1\n L 0,1\n S 10,1").unwrap());
+        cache.reset();
+        assert_eq!(cache.valid_lines(), 0);
+        assert_eq!(cache.top_evicted(5), vec![]);
+
+        // 0x0 was still cached at the end of the first trace, but misses again here
+        cache.operate_cache(parse(" L 0,1\n L 10,1\n L 0,1").unwrap());
+        assert_eq!(cache.stats, Statistics { hit: 1, miss: 2, eviction: 0, ..Statistics::default() });
+        // Neither the evictions nor the energy of the first trace carry over
+        assert_eq!(cache.top_evicted(5), vec![]);
+        assert_eq!(cache.energy().unwrap().total(), 21.0);
+    }
+
+    #[test]
+    fn dump_lists_valid_lines() {
+        let mut cache = Cache::new(1, 2, 4).unwrap();
+        cache.operate_cache(parse(" L 0,1\n L 20,1\n L 0,1").unwrap());
+        assert_eq!(cache.dump(), "set 0: line 0 tag 0x0 stamp 3, line 1 tag 0x1 stamp 2\n");
+    }
+
+    #[test]
+    fn loads_and_stores_counted_apart() {
+        // The modify of 0x20 is a load that misses and a store that hits
+        let mut cache = Cache::new(0, 1, 4).unwrap();
+        cache.track_operations();
+        cache.operate_cache(parse(" L 0,1\n S 0,1\n M 20,1\n L 0,1\n S 0,1").unwrap());
+
+        let operations = cache.operation_stats().unwrap();
+        assert_eq!(operations.load, ThreadStatistics { hit: 0, miss: 3 });
+        assert_eq!(operations.store, ThreadStatistics { hit: 3, miss: 0 });
+    }
+
+    #[test]
+    fn builder_names_each_knob() {
+        let built = CacheBuilder::new().set_bits(1).block_bits(4).associativity(2).build().unwrap();
+        assert_eq!((built.sets.len(), built.sets[0].lines.len(), built.block_bits), (2, 2, 4));
+
+        let mut cache = CacheBuilder::new().block_bits(4).policy(Box::new(crate::policy::Fifo)).write_back(true).build().unwrap();
+        cache.operate_cache(parse(" S 0,1\n L 10,1").unwrap());
+        assert_eq!(cache.stats.dirty_evictions, 1);
+        assert!(CacheBuilder::new().associativity(0).build().is_err());
+    }
+
+    #[test]
+    fn verbose_matches_reference_simulator() {
+        let mut cache = Cache::new(0, 1, 4).unwrap();
+        cache.set_verbose();
+        cache.operate_cache(parse(" L 10,1\n M 20,1\n L 22,1\n S 18,1").unwrap());
+        assert_eq!(cache.take_verbose().unwrap(), "L 10,1 miss\nM 20,1 miss eviction hit\nL 22,1 hit\nS 18,1 miss eviction\n");
+    }
+
+    #[test]
+    fn stored_byte_reads_back() {
+        let mut cache = Cache::new(1, 1, 4).unwrap();
+        cache.set_data_modeling();
+        cache.store_byte(0x13, 0xAB);
+        assert_eq!((cache.peek_byte(0x13), cache.peek_byte(0x12)), (Some(0xAB), Some(0)));
+
+        // Evicted by the conflicting 0x30 and written back, then refilled
+        cache.operate_cache(parse(" L 30,1").unwrap());
+        assert_eq!(cache.peek_byte(0x13), None);
+        cache.operate_cache(parse(" L 10,1").unwrap());
+        assert_eq!(cache.peek_byte(0x13), Some(0xAB));
+    }
+
+    #[test]
+    fn modify_store_half_hits() {
+        let mut cache = Cache::new(0, 1, 4).unwrap();
+        cache.operate_cache(parse(" M 100,4").unwrap());
+        assert_eq!(cache.stats, Statistics { hit: 1, miss: 1, eviction: 0, ..Statistics::default() });
+
+        // However the load fares, even in a direct-mapped cache it leaves the block for the store
+        let mut cache = Cache::new(0, 1, 4).unwrap();
+        cache.set_verbose();
+        cache.operate_cache(parse(&std::fs::read_to_string("test/trans.trace").unwrap()).unwrap());
+        let modifies: Vec<String> = cache.take_verbose().unwrap().lines().filter(|line| line.starts_with('M')).map(String::from).collect();
+        assert!(!modifies.is_empty() && modifies.iter().all(|line| line.ends_with(" hit")));
+    }
+
+    #[test]
+    fn sets_expose_occupancy() {
+        let mut cache = Cache::new(1, 2, 4).unwrap();
+        cache.operate_cache(parse(" L 0,1\n L 20,1\n L 10,1").unwrap());
+
+        let occupancy: Vec<usize> = cache.sets().map(Set::occupancy).collect();
+        assert_eq!(occupancy, vec![2, 1]);
+        assert_eq!(cache.sets().next().unwrap().tags().collect::<Vec<u64>>(), vec![0, 1]);
+        assert_eq!(cache.sets().flat_map(Set::lines).filter(|line| line.is_valid()).count(), cache.valid_lines());
+    }
+
+    #[test]
+    fn compulsory_misses_classified() {
+        // 0x0 misses cold, is evicted by 0x20, then misses again for want of room
+        let mut cache = Cache::new(1, 1, 4).unwrap();
+        cache.classify_misses();
+        cache.operate_cache(parse(" L 0,1\n L 20,1\n L 0,1\n L 0,1").unwrap());
+        assert_eq!((cache.stats.miss, cache.stats.compulsory_misses, cache.stats.conflict_capacity_misses), (3, 2, 1));
+    }
+
+    #[test]
+    fn block_bytes_to_bits() {
+        assert_eq!(bits_for_bytes(64, "Block size"), Ok(6));
+        assert_eq!(bits_for_bytes(1, "Block size"), Ok(0));
+        assert_eq!(bits_for_bytes(48, "Block size"), Err(String::from("Error: Block size of 48 bytes isn't a power of two")));
+        assert!(bits_for_bytes(0, "Block size").is_err());
+
+        let cache = CacheBuilder::new().block_bytes(64).build().unwrap();
+        assert_eq!(cache.block_bits, 6);
+        assert!(CacheBuilder::new().block_bytes(48).build().is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_geometry() {
+        let error = Cache::new(2, 0, 4).unwrap_err();
+        assert_eq!(error.to_string(), "Error: A cache needs at least one line per set");
+        assert!(Cache::with_sets(3, 0, 4).is_err());
+        let error = Cache::with_sets(0, 1, 4).unwrap_err();
+        assert_eq!(error.to_string(), "Error: A cache needs at least one set");
+
+        let error = Cache::new(60, 1, 4).unwrap_err();
+        assert_eq!(error.to_string(), "Error: 60 set bits and 4 block bits leave no tag bits in a 64-bit address");
+        assert!(Cache::new(200, 1, 100).is_err());
+
+        assert!(Cache::new(4, 2, 4).is_ok());
+    }
+
+    #[test]
+    fn rejects_invalid_addr_bits() {
+        let mut cache = Cache::new(2, 1, 4).unwrap();
+        assert_eq!(cache.set_addr_bits(0).unwrap_err().to_string(), "Error: Addresses must be between 1 and 64 bits wide");
+        assert!(cache.set_addr_bits(65).is_err());
+        let error = cache.set_addr_bits(5).unwrap_err();
+        assert_eq!(error.to_string(), "Error: 2 set bits and 4 block bits don't fit in a 5-bit address");
+
+        // A rejected width leaves the cache as it was
+        assert_eq!(cache.decompose(1 << 40).tag, 1 << 34);
+        cache.set_addr_bits(6).unwrap();
+    }
+
+    #[test]
+    fn rejects_oversized_geometry() {
+        // Too many lines to allocate, and too many bytes, though the bits leave a tag
+        let error = Cache::new(40, 1, 4).unwrap_err();
+        assert_eq!(error.to_string(), "Error: 1099511627776 sets of 1 lines is more than the 4194304 lines a cache may have");
+        let error = Cache::new(4, 1 << 10, 24).unwrap_err();
+        assert_eq!(error.to_string(), "Error: A cache of 274877906944 data bytes is more than the 1073741824 a cache may hold");
+
+        assert!(Cache::with_sets(u64::MAX, 1, 0).is_err());
+        assert_eq!(data_bytes(1 << 10, 1 << 10, 10), MAX_DATA_BYTES);
+    }
+
+    #[test]
+    fn phase_stats_sum_to_totals() {
+        let trace = " L 0,1\n# PHASE warm\n L 0,1\n L 10,1\n# PHASE thrash\n L 20,1\n L 0,1\n L 30,1";
+        let (traces, phases, _) = crate::valgrind::parse_phases(trace, false).unwrap();
+        let mut cache = Cache::new(1, 1, 4).unwrap();
+        let results = cache.operate_phases(traces, &phases);
+
+        let names: Vec<&str> = results.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, vec!["", "warm", "thrash"]);
+        assert_eq!((results[1].1.hit, results[1].1.miss), (1, 1));
+        assert_eq!((results[2].1.hit, results[2].1.miss, results[2].1.eviction), (0, 3, 3));
+
+        let total = results.iter().fold(Statistics::default(), |total, (_, stats)| Statistics {
+            hit: total.hit + stats.hit,
+            miss: total.miss + stats.miss,
+            eviction: total.eviction + stats.eviction,
+            ..total
+        });
+        assert_eq!(total, cache.stats);
+    }
+
+    #[test]
+    fn address_zero_is_simulated() {
+        // Invalid lines hold tag 0 too, which must not pass for a hit
+        let mut cold = Cache::new(1, 2, 4).unwrap();
+        cold.operate_cache(parse(" L 0,1").unwrap());
+        assert_eq!((cold.stats.hit, cold.stats.miss), (0, 1));
+
+        let mut warm = Cache::new(1, 2, 4).unwrap();
+        warm.operate_cache(parse(" L 0,1\n S 0,1").unwrap());
+        assert_eq!((warm.stats.hit, warm.stats.miss), (1, 1));
+    }
+
+    #[test]
+    fn flush_makes_misses_compulsory() {
+        // The same two blocks, flushed after every three accesses
+        let trace = " L 0,1\n L 10,1\n L 0,1\n L 0,1\n L 10,1\n L 0,1";
+        let mut cache = Cache::new(1, 1, 4).unwrap();
+        cache.set_flush_every(3);
+        cache.operate_cache(parse(trace).unwrap());
+
+        assert_eq!(cache.flushes(), 1);
+        assert_eq!((cache.stats.hit, cache.stats.miss, cache.stats.eviction), (2, 4, 0));
+    }
+
+    #[test]
+    fn modulo_set_index() {
+        let cache = Cache::with_sets(5, 1, 4).unwrap();
+        // Block 7 at offset 0xb: 7 = 1 * 5 + 2
+        assert_eq!(cache.decompose(0x7b), AddressPartition { tag: 1, set: 2, block: 0xb });
+        assert_eq!(cache.block_address(1, 2), 7);
+
+        // Blocks 0 and 5 share set 0, while block 4 has set 4 to itself
+        let mut cache = Cache::with_sets(5, 1, 4).unwrap();
+        cache.operate_cache(parse(" L 0,1\n L 40,1\n L 50,1\n L 44,1\n L 0,1").unwrap());
+        assert_eq!(cache.set_accesses(), vec![3, 0, 0, 0, 2]);
+        assert_eq!((cache.stats.hit, cache.stats.miss, cache.stats.eviction), (1, 4, 2));
+    }
+
+    #[test]
+    fn per_set_miss_rates() {
+        // Blocks 0x00 and 0x40 conflict in set 0; sets 1 and 2 are loaded twice each
+        let mut cache = Cache::new(2, 1, 4).unwrap();
+        cache.operate_cache(parse(" L 0,1\n L 40,1\n L 0,1\n L 40,1\n L 10,1\n L 10,1\n L 20,1\n L 20,1").unwrap());
+        assert_eq!(cache.set_miss_rates(), vec![1.0, 0.5, 0.5, 0.0]);
+    }
+
+    #[test]
+    fn critical_word_first_latency() {
+        // 32-byte blocks are four words, so the critical word arrives after a quarter of the fill
+        let trace = " L 0,1\n L 20,1\n L 0,1\n L 20,1";
+        let mut averages = vec![];
+
+        for &critical_word_first in &[false, true] {
+            let mut cache = Cache::new(1, 1, 5).unwrap();
+            cache.set_latency(LatencyModel::new(1, 100, critical_word_first, 5));
+            cache.operate_cache(parse(trace).unwrap());
+            averages.push(cache.latency().unwrap().average_miss_latency());
+        }
+
+        assert_eq!(averages[0], 101.0);
+        assert_eq!(averages[1], 63.0);
+    }
+
+    #[test]
+    fn heatmap_shading() {
+        // Four sets; set 1 is hit repeatedly, set 2 once, sets 0 and 3 never
+        let mut cache = Cache::new(2, 1, 4).unwrap();
+        cache.operate_cache(parse(" L 10,1\n L 10,1\n L 10,1\n L 10,1\n L 20,1").unwrap());
+
+        assert_eq!(cache.set_accesses(), vec![0, 4, 1, 0]);
+        let heatmap = cache.heatmap();
+        let rows: Vec<&str> = heatmap.lines().collect();
+        assert_eq!(rows[0], "0 |        | 0");
+        assert_eq!(rows[1], "1 |@@@@@@@@| 4");
+        assert_eq!(rows[2], "2 |--------| 1");
+    }
+
+    #[test]
+    fn seeded_random_policy() {
+        // Six blocks contending for the four ways of set 0
+        let trace: Vec<String> = (0..200).map(|i| format!(" L {:x},1", ((i * i + i / 5) % 6) << 5)).collect();
+        let trace = trace.join("\n");
+
+        let run = |seed| {
+            let mut cache = Cache::new(1, 4, 4).unwrap();
+            cache.set_policy(Box::new(Random::new(seed)));
+            cache.operate_cache(parse(&trace).unwrap());
+            cache.stats
+        };
+
+        assert_eq!(run(1), run(1));
+        assert_ne!(run(1), run(2));
+    }
+
+    /// Pseudo-random trace touching `blocks` distinct 16-byte blocks
+    fn scattered_trace(accesses: usize, blocks: u64) -> String {
+        let mut rng = crate::rng::Rng::new(0);
+        let trace: Vec<String> = (0..accesses)
+            .map(|_| format!(" L {:x},1", rng.below(blocks) << 4))
+            .collect();
+        trace.join("\n")
+    }
+
+    #[test]
+    fn tag_index_matches_scan() {
+        let trace = parse(&scattered_trace(20_000, 300)).unwrap();
+        let run = |builder: CacheBuilder| {
+            let mut cache = builder.set_bits(1).associativity(64).block_bits(4).build().unwrap();
+            cache.set_flush_every(7_000);
+            cache.operate_cache(trace.clone());
+            (cache.stats, cache.dump())
+        };
+
+        let indexed = run(CacheBuilder::new());
+        assert!(indexed.0.eviction > 0);
+        assert_eq!(indexed, run(CacheBuilder::new().tag_index_above(u32::MAX)));
+    }
+
+    #[test]
+    fn batched_matches_unbatched() {
+        let trace = scattered_trace(5000, 512);
+
+        let mut batched = Cache::new(4, 2, 4).unwrap();
+        batched.operate_cache(parse(&trace).unwrap());
+        let mut unbatched = Cache::new(4, 2, 4).unwrap();
+        unbatched.operate_stream(parse(&trace).unwrap());
+
+        assert_eq!(batched.stats, unbatched.stats);
+        assert_eq!(batched.set_accesses(), unbatched.set_accesses());
+    }
+
+    #[test]
+    fn most_evicted_tag_first() {
+        // Tags 1 and 2 ping-pong in set 0 while tag 3 is thrown out once from set 1
+        let mut cache = Cache::new(1, 1, 4).unwrap();
+        cache.track_evicted_tags();
+        cache.operate_cache(parse(" L 20,1\n L 40,1\n L 20,1\n L 40,1\n L 20,1\n L 70,1\n L 10,1").unwrap());
+
+        assert_eq!(cache.top_evicted(2), vec![(1, 2), (2, 2)]);
+        assert_eq!(cache.top_evicted(5), vec![(1, 2), (2, 2), (3, 1)]);
+        assert_eq!(cache.top_evicted(0), vec![]);
+    }
+
+    #[test]
+    fn detected_32_bit_trace() {
+        let traces = parse(" L ffffffff,1\n S 10,1").unwrap();
+        assert!(crate::valgrind::fits_in_32_bits(&traces));
+
+        let mut cache = Cache::new(4, 1, 4).unwrap();
+        cache.set_addr_bits(32).unwrap();
+        assert_eq!(cache.tag_bits, 24);
+        assert_eq!(cache.decompose(0xFFFF_FFFF).tag, 0xFF_FFFF);
+        // Bits beyond the 32-bit address space are not part of the tag
+        assert_eq!(cache.decompose(0x1_0000_0010), cache.decompose(0x10));
+    }
+
+    /// A trace of loads to `count` distinct blocks, generated as it is read rather than held
+    struct GeneratedTrace {
+        next: u64,
+        count: u64,
+    }
+
+    impl std::io::Read for GeneratedTrace {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            // Each line is at most 22 bytes, so a read of at least that much makes progress
+            let mut written = 0;
+            while self.next < self.count && buf.len() - written >= 22 {
+                let line = format!(" L {:x},1\n", self.next << 4);
+                buf[written..written + line.len()].copy_from_slice(line.as_bytes());
+                written += line.len();
+                self.next += 1;
+            }
+            Ok(written)
+        }
+    }
+
+    #[test]
+    fn stream_without_holding_trace() {
+        let count = 1 << 18;
+        let trace = std::io::BufReader::new(GeneratedTrace { next: 0, count });
+        let mut cache = Cache::new(2, 2, 4).unwrap();
+        cache.operate_stream(crate::valgrind::TraceReader::new(trace, false).map(Result::unwrap));
+
+        assert_eq!((cache.stats.hit, cache.stats.miss, cache.stats.eviction), (0, count as u32, count as u32 - 8));
+    }
+
+    #[test]
+    fn store_lines_outlive_load_lines() {
+        // A is stored, B loaded, then C evicts one of them before A is read again
+        let trace = " S 0,1\n L 20,1\n L 40,1\n L 0,1";
+        let run = |weights| {
+            let mut cache = Cache::new(1, 2, 4).unwrap();
+            cache.set_op_weights(weights);
+            cache.operate_cache(parse(trace).unwrap());
+            cache.stats.hit
+        };
+
+        assert_eq!(run(OpWeights::default()), 0);
+        assert_eq!(run(OpWeights::parse("load:1,store:2").unwrap()), 1);
+        assert!(OpWeights::parse("store:0").is_err());
+    }
+
+    #[test]
+    fn trivial_footprint() {
+        let traces = parse(" L 0,1\n S 40,1\n L 0,1").unwrap();
+        let blocks = crate::valgrind::distinct_blocks(&traces, 4);
+        let mut cache = Cache::new(4, 4, 4).unwrap();
+        cache.operate_cache(traces);
+        assert_eq!(blocks, 2);
+        assert!(cache.footprint_warning(blocks).unwrap().starts_with("warning: cache never evicted"));
+
+        let mut small = Cache::new(1, 1, 4).unwrap();
+        small.operate_cache(parse(" L 0,1\n L 20,1").unwrap());
+        assert_eq!(small.footprint_warning(2), None);
+    }
+
+    #[test]
+    fn single_access_outcomes() {
+        // One set of one line, driven an access at a time
+        let mut cache = Cache::new(0, 1, 4).unwrap();
+        let mut access = |operation, address| cache.access(operation, address, 4);
+        assert_eq!(access(Operation::Load, 0x10), AccessOutcome::Miss);
+        assert_eq!(access(Operation::Store, 0x1c), AccessOutcome::Hit);
+        assert_eq!(access(Operation::Load, 0x20), AccessOutcome::MissEviction);
+        assert_eq!(cache.stats, Statistics { hit: 1, miss: 2, eviction: 1, ..Statistics::default() });
+    }
+
+    #[test]
+    fn wider_than_255_lines() {
+        let mut cache = Cache::new(0, 1024, 4).unwrap();
+        assert_eq!(cache.sets().next().unwrap().lines().len(), 1024);
+
+        // 1024 blocks fit at once, so only the first pass over them misses
+        let trace: Vec<String> = (0..2048).map(|i| format!(" L {:x},1", (i % 1024) << 4)).collect();
+        cache.operate_cache(parse(&trace.join("\n")).unwrap());
+        assert_eq!((cache.stats.hit, cache.stats.miss, cache.stats.eviction), (1024, 1024, 0));
+    }
+
+    #[test]
+    fn addresses_short_of_tag() {
+        // 16-bit addresses under 20 set bits and 4 block bits
+        let traces = parse(" L 0,1\n S ffff,1\n L 1234,1").unwrap();
+        let wide = Cache::new(20, 1, 4).unwrap();
+        assert!(wide.tag_warning(&traces).unwrap().starts_with("warning: every address has tag 0"));
+        assert_eq!(Cache::new(4, 1, 4).unwrap().tag_warning(&traces), None);
+    }
+
+    #[test]
+    fn lru_state_dump() {
+        // Blocks A, B then A again in set 0 leave A the most recently used
+        let mut cache = Cache::new(1, 2, 4).unwrap();
+        cache.operate_cache(parse(" L 0,1\n L 20,1\n L 0,1").unwrap());
+
+        assert_eq!(cache.replacement_state(), "\
+set 0: most recent first: line 0 (0x0), line 1 (0x1)
+set 1: empty
+");
+    }
+
+    #[test]
+    fn interleaved_threads() {
+        // Thread 1 streams through set 0 while thread 2 reuses one block in set 1
+        let trace = "1 L 0,1\n2 L 10,1\n1 L 20,1\n2 L 10,1\n1 L 0,1\n2 L 10,1";
+        let mut cache = Cache::new(1, 1, 4).unwrap();
+        cache.track_threads();
+        cache.operate_cache(parse(trace).unwrap());
+
+        let threads: Vec<(&u32, &ThreadStatistics)> = cache.thread_stats().collect();
+        assert_eq!(threads, vec![
+            (&1, &ThreadStatistics { hit: 0, miss: 3 }),
+            (&2, &ThreadStatistics { hit: 2, miss: 1 }),
+        ]);
+        assert_eq!((cache.stats.hit, cache.stats.miss), (2, 4));
+    }
+
+    #[test]
+    fn folded_miss_attribution() {
+        // The load at 0x400 misses twice and the store at 0x404 once; 0x408 only hits
+        let trace = "I 400,4\n L 0,1\nI 404,4\n S 20,1\nI 408,4\n L 20,1\nI 400,4\n L 0,1";
+        let mut cache = Cache::new(1, 1, 4).unwrap();
+        cache.track_miss_pcs();
+        cache.operate_cache(parse(trace).unwrap());
+
+        assert_eq!(cache.folded_misses(), "0x400 2\n0x404 1\n");
+    }
+
+    #[test]
+    fn store_miss_convention() {
+        let trace = " S 0,1\n S 20,1\n L 0,1\n S 0,1";
+        let run = |count_misses| {
+            let mut cache = Cache::new(1, 1, 4).unwrap();
+            cache.set_no_write_allocate(count_misses);
+            cache.operate_cache(parse(trace).unwrap());
+            cache.stats
+        };
+
+        // Neither missed store allocates, so the load misses too and nothing is evicted
+        let counted = run(true);
+        assert_eq!((counted.hit, counted.miss, counted.eviction), (1, 3, 0));
+        let uncounted = run(false);
+        assert_eq!((uncounted.hit, uncounted.miss, uncounted.eviction), (1, 1, 0));
+    }
+
+    #[test]
+    fn write_allocation_fills_lines() {
+        // Stores to four blocks of four sets, then a load of the first
+        let trace = " S 0,1\n S 10,1\n S 20,1\n S 30,1\n L 0,1";
+        let mut allocating = Cache::new(2, 1, 4).unwrap();
+        allocating.operate_cache(parse(trace).unwrap());
+        assert_eq!((allocating.valid_lines(), allocating.stats.hit), (4, 1));
+
+        let mut around = Cache::new(2, 1, 4).unwrap();
+        around.set_no_write_allocate(true);
+        around.operate_cache(parse(trace).unwrap());
+        assert_eq!((around.valid_lines(), around.stats.hit), (1, 0));
+    }
+
+    #[test]
+    fn vipt_alias() {
+        // 16-byte pages and 64-byte sets of 16-byte blocks: two index bits lie above the page
+        // offset. Virtual pages 1 and 2 both map to physical page 3.
+        let trace = " L 10,1\n L 20,1";
+        let table = || PageTable::parse("1 3\n2 3\n", 4, false).unwrap();
+
+        let mut pipt = Cache::new(2, 1, 4).unwrap();
+        let mut traces = parse(trace).unwrap();
+        table().translate_all(&mut traces).unwrap();
+        pipt.operate_cache(traces);
+        assert_eq!(pipt.stats.hit, 1);
+
+        // Indexed virtually, the one physical block is cached twice, in sets 1 and 2
+        let mut vipt = Cache::new(2, 1, 4).unwrap();
+        vipt.set_virtual_index(table());
+        vipt.operate_cache(parse(trace).unwrap());
+        assert_eq!(vipt.stats.hit, 0);
+        assert_eq!(vipt.set_accesses(), vec![0, 1, 1, 0]);
+    }
+
+    #[test]
+    fn single_set_event_log() {
+        let mut cache = Cache::new(1, 1, 4).unwrap();
+        cache.trace_set(1);
+        // Only the accesses to set 1 (0x10 and 0x30) are logged
+        cache.operate_cache(parse(" L 10,1\n L 20,1\n L 10,1\n L 30,1").unwrap());
+
+        let log: Vec<String> = cache.events().iter().map(|event| event.to_string()).collect();
+        assert_eq!(log, vec![
+            "set 1: access 0x0",
+            "set 1: fill 0x0 into line 0",
+            "set 1: access 0x0",
+            "set 1: hit 0x0 in line 0",
+            "set 1: access 0x1",
+            "set 1: evict 0x0 from line 0",
+            "set 1: fill 0x1 into line 0",
+        ]);
+    }
+
+    #[test]
+    fn rates_honour_precision() {
+        let stats = Statistics { hit: 2, miss: 1, ..Statistics::default() };
+        assert_eq!(format_rate(stats.hit_rate(), 2), "0.67");
+        assert_eq!(format_rate(stats.miss_rate(), 6), "0.333333");
+    }
+
+    #[test]
+    fn repeats_reach_steady_state() {
+        let mut cache = Cache::new(1, 1, 4).unwrap();
+        let shares = cache.operate_repeated(parse(" L 0,1\n L 10,1\n L 20,1\n L 0,1").unwrap(), 4);
+
+        // Blocks 0x0 and 0x20 keep evicting each other, but 0x10 only misses cold
+        assert_eq!(shares[0].miss, 4);
+        assert!(shares[1..].iter().all(|share| *share == shares[1]));
+        assert_eq!(shares[1].miss, 2);
+    }
+
+    #[test]
+    fn locked_lines_are_never_evicted() {
+        // Every block maps to set 0 of a two-way cache
+        let mut cache = Cache::new(1, 2, 4).unwrap();
+        cache.lock(0x0);
+        cache.operate_cache(parse(" L 0,1\n L 20,1\n L 40,1\n L 60,1\n L 0,1").unwrap());
+        assert_eq!(cache.stats, Statistics { hit: 1, miss: 4, eviction: 2, ..Statistics::default() });
+
+        // With both lines locked, a third block can only bypass the set
+        let mut cache = Cache::new(1, 2, 4).unwrap();
+        cache.lock(0x0);
+        cache.lock(0x20);
+        cache.operate_cache(parse(" L 0,1\n L 20,1\n L 40,1\n L 0,1").unwrap());
+        assert_eq!(cache.bypasses(), 1);
+        assert_eq!(cache.stats, Statistics { hit: 1, miss: 3, eviction: 0, ..Statistics::default() });
+    }
+
+    #[test]
+    fn every_policy_evicts_around_a_locked_line() {
+        // Under MRU the locked A is the most recent line when C arrives, and under LFU
+        // the least hit; the one unlocked line must go under every policy
+        let traces = [
+            (" L 10,1\n L 0,1\n L 0,1\n L 20,1\n L 30,1", Statistics { hit: 1, miss: 4, eviction: 2, ..Statistics::default() }),
+            (" L 0,1\n L 10,1\n L 10,1\n L 20,1\n L 0,1", Statistics { hit: 2, miss: 3, eviction: 1, ..Statistics::default() }),
+        ];
+        for (trace, stats) in traces {
+            let trace = parse(trace).unwrap();
+            for name in ["lru", "mru", "lfu", "fifo", "opt"] {
+                let mut cache = Cache::new(0, 2, 4).unwrap();
+                match name {
+                    "opt" => cache.set_policy(Box::new(crate::policy::Opt::new(&trace, 0, 4))),
+                    name => cache.set_policy(crate::policy::from_name(name, 0).unwrap()),
+                }
+                cache.lock(0x0);
+                cache.operate_cache(trace.clone());
+                assert_eq!(cache.stats, stats, "{}", name);
+            }
+        }
+    }
+
+    #[test]
+    fn mpki_from_instruction_count() {
+        let stats = Statistics { hit: 100, miss: 30, ..Statistics::default() };
+        assert_eq!(stats.mpki(12_000), 2.5);
+        assert_eq!(format_rate(stats.mpki(7_000), 2), "4.29");
+    }
+
+    #[test]
+    fn warming_leaves_counters_clear() {
+        let warmup = " L 0,1\n L 10,1";
+        let window = " L 0,1\n L 10,1\n L 20,1";
+
+        let mut cold = Cache::new(1, 1, 4).unwrap();
+        cold.operate_cache(parse(window).unwrap());
+        assert_eq!(cold.stats, Statistics { hit: 0, miss: 3, eviction: 1, ..Statistics::default() });
+
+        let mut warm = Cache::new(1, 1, 4).unwrap();
+        warm.warm(parse(warmup).unwrap());
+        warm.operate_cache(parse(window).unwrap());
+        assert_eq!(warm.stats, Statistics { hit: 2, miss: 1, eviction: 1, ..Statistics::default() });
+    }
+
+    #[test]
+    fn xor_fold_spreads_strided_accesses() {
+        // A stride of the whole index span sends every block to set 0
+        let trace: Vec<String> = (0..8).map(|i| format!(" L {:x},1", (i % 4) * 0x40)).collect();
+        let traces = || parse(&trace.join("\n")).unwrap();
+
+        let mut plain = Cache::new(2, 1, 4).unwrap();
+        plain.operate_cache(traces());
+        assert_eq!(plain.stats.miss, 8);
+
+        let mut folded = Cache::new(2, 1, 4).unwrap();
+        folded.set_xor_fold();
+        folded.operate_cache(traces());
+        assert_eq!(folded.stats.miss, 4);
+        assert_eq!(folded.set_accesses(), vec![2, 2, 2, 2]);
+    }
+
+    #[test]
+    fn byte_valid_load_misses_unwritten_bytes() {
+        let mut cache = Cache::new(1, 1, 4).unwrap();
+        cache.set_byte_valid();
+        // The store allocates bytes 0-3 only: the first load stays within
+        // them, the second reaches bytes 4 and 5 and fetches the block, and
+        // the third then finds every byte valid
+        cache.operate_cache(parse(" S 0,4\n L 0,4\n L 2,4\n L 4,4").unwrap());
+        assert_eq!(cache.stats, Statistics { hit: 2, miss: 2, eviction: 0, ..Statistics::default() });
+    }
+
+    #[test]
+    fn cold_set_misses_excluded() {
+        let mut cache = Cache::new(1, 1, 4).unwrap();
+        cache.set_exclude_cold_sets();
+        // Sets 0 and 1 each take a first miss, then set 0 a conflict miss
+        cache.operate_cache(parse(" L 0,1\n L 10,1\n L 0,1\n L 20,1").unwrap());
+        assert_eq!(cache.stats, Statistics { hit: 1, miss: 1, eviction: 1, ..Statistics::default() });
+    }
+
+    #[test]
+    fn hit_in_any_line_of_the_set() {
+        // Two tags fill both lines of set 0; the repeat finds the second in line 1
+        let mut cache = Cache::new(1, 2, 4).unwrap();
+        cache.operate_cache(parse(" L 0,1\n L 20,1\n L 20,1").unwrap());
+        assert_eq!(cache.stats, Statistics { hit: 1, miss: 2, eviction: 0, ..Statistics::default() });
+    }
+
+    #[test]
+    fn wide_set_and_block_bits() {
+        let cache = Cache::new(12, 1, 6).unwrap();
+        assert_eq!(cache.set_accesses().len(), 4096);
+    }
+
+    #[test]
+    fn cold_fills_are_stamped() {
+        // Block 0x0 is filled and used before 0x20 fills the other line,
+        // so 0x40 must evict 0x0 and leave 0x20 to hit
+        let mut cache = Cache::new(1, 2, 4).unwrap();
+        cache.operate_cache(parse(" L 0,1\n L 0,1\n L 20,1\n L 40,1\n L 20,1").unwrap());
+        assert_eq!(cache.stats, Statistics { hit: 2, miss: 3, eviction: 1, ..Statistics::default() });
+    }
+
+    #[test]
+    fn stamps_strictly_ordered() {
+        let mut cache = Cache::new(1, 2, 4).unwrap();
+        cache.operate_cache(parse(" L 0,1\n L 20,1").unwrap());
+        let lines = &cache.sets[0].lines;
+        assert!(lines[0].last_access() < lines[1].last_access());
+    }
 }
@@ -0,0 +1,142 @@
+//! Per-access records laid out column by column, for loading into dataframes
+// The file is little-endian throughout:
+//     the magic bytes "CSIMCOL1", then the column and row counts as u32 and u64
+//     for each column: its name's length as a u8, the name, a width byte (1 or
+//     8), then one value of that many bytes per row
+// The columns are index, op, address, set, tag and outcome. Operations are
+// stored as their trace letter in ASCII, and outcomes as their position in
+// `OUTCOMES`.
+
+use std::convert::TryInto;
+use std::error::Error;
+use crate::access_log::{operation_code, outcome_name, AccessRecord};
+
+const MAGIC: &[u8; 8] = b"CSIMCOL1";
+
+/// Outcome names, in the order their codes count from 0
+pub const OUTCOMES: [&str; 7] = ["hit", "miss", "miss-eviction", "victim-hit", "forwarded", "write-around", "bypass"];
+
+#[derive(Debug, PartialEq)]
+pub enum Column {
+    Bytes(Vec<u8>),
+    Words(Vec<u64>),
+}
+
+#[derive(Debug, Default)]
+pub struct Columns {
+    op: Vec<u8>,
+    address: Vec<u64>,
+    set: Vec<u64>,
+    tag: Vec<u64>,
+    outcome: Vec<u8>,
+}
+
+impl Columns {
+    pub fn record(&mut self, record: &AccessRecord) {
+        self.op.push(operation_code(record.operation).as_bytes()[0]);
+        self.address.push(record.address);
+        self.set.push(record.set);
+        self.tag.push(record.tag);
+        let name = outcome_name(record.outcome);
+        self.outcome.push(OUTCOMES.iter().position(|&outcome| outcome == name).unwrap() as u8);
+    }
+
+    /// The file's bytes, in the layout described above
+    pub fn encode(self) -> Vec<u8> {
+        let rows = self.op.len();
+        let columns = [
+            ("index", Column::Words((0..rows as u64).collect())),
+            ("op", Column::Bytes(self.op)),
+            ("address", Column::Words(self.address)),
+            ("set", Column::Words(self.set)),
+            ("tag", Column::Words(self.tag)),
+            ("outcome", Column::Bytes(self.outcome)),
+        ];
+
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend((columns.len() as u32).to_le_bytes());
+        bytes.extend((rows as u64).to_le_bytes());
+        for (name, column) in &columns {
+            bytes.push(name.len() as u8);
+            bytes.extend(name.as_bytes());
+            match column {
+                Column::Bytes(values) => {
+                    bytes.push(1);
+                    bytes.extend(values);
+                }
+                Column::Words(values) => {
+                    bytes.push(8);
+                    values.iter().for_each(|value| bytes.extend(value.to_le_bytes()));
+                }
+            }
+        }
+        bytes
+    }
+}
+
+/// Read back the named columns of a file written by `Columns::encode`
+pub fn decode(bytes: &[u8]) -> Result<Vec<(String, Column)>, Box<dyn Error>> {
+    const TRUNCATED: &str = "Error: Columnar file is truncated";
+    let mut at: usize = 0;
+    // A corrupt header can ask for more rows than any file holds, so the ends are checked
+    let mut take = |count: usize| -> Result<&[u8], Box<dyn Error>> {
+        let end = at.checked_add(count).ok_or(TRUNCATED)?;
+        let taken = bytes.get(at..end).ok_or(TRUNCATED)?;
+        at = end;
+        Ok(taken)
+    };
+
+    if take(MAGIC.len())? != MAGIC {
+        return Err("Error: Not a columnar access file".into());
+    }
+    let count = u32::from_le_bytes(take(4)?.try_into()?);
+    let rows: usize = u64::from_le_bytes(take(8)?.try_into()?).try_into().map_err(|_| TRUNCATED)?;
+
+    let mut columns = vec![];
+    for _ in 0..count {
+        let length = take(1)?[0] as usize;
+        let name = String::from_utf8(take(length)?.to_vec())?;
+        let column = match take(1)?[0] {
+            1 => Column::Bytes(take(rows)?.to_vec()),
+            8 => Column::Words(take(rows.checked_mul(8).ok_or(TRUNCATED)?)?.chunks(8).map(|word| u64::from_le_bytes(word.try_into().unwrap())).collect()),
+            width => return Err(format!("Error: Column {} has unsupported width {}", name, width).into()),
+        };
+        columns.push((name, column));
+    }
+    Ok(columns)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cache::Cache;
+    use crate::valgrind::parse;
+
+    #[test]
+    fn round_trip_in_access_order() {
+        let mut cache = Cache::new(1, 1, 4).unwrap();
+        cache.set_columns(Columns::default());
+        cache.operate_cache(parse(" L 0,1\n S 24,1\n L 4,1").unwrap());
+        let columns = decode(&cache.take_columns().unwrap().encode()).unwrap();
+
+        let names: Vec<&str> = columns.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, ["index", "op", "address", "set", "tag", "outcome"]);
+        assert_eq!(columns[0].1, Column::Words(vec![0, 1, 2]));
+        assert_eq!(columns[1].1, Column::Bytes(b"LSL".to_vec()));
+        assert_eq!(columns[2].1, Column::Words(vec![0x0, 0x24, 0x4]));
+        assert_eq!(columns[3].1, Column::Words(vec![0, 0, 0]));
+        assert_eq!(columns[4].1, Column::Words(vec![0, 1, 0]));
+        assert_eq!(columns[5].1, Column::Bytes(vec![1, 2, 2]));
+    }
+
+    #[test]
+    fn corrupt_row_count_is_truncation() {
+        for width in [1, 8] {
+            let mut bytes = MAGIC.to_vec();
+            bytes.extend(1_u32.to_le_bytes());
+            bytes.extend(u64::MAX.to_le_bytes());
+            bytes.extend([1, b'a', width, 0, 0]);
+            assert_eq!(decode(&bytes).unwrap_err().to_string(), "Error: Columnar file is truncated");
+        }
+    }
+}
@@ -0,0 +1,49 @@
+//! Simulate one trace under several replacement policies at once
+// Each policy gets a cache of the same geometry, and every access goes to
+// each cache in turn, so the trace is parsed and walked only once.
+
+use std::error::Error;
+use crate::cache::{Cache, Statistics};
+use crate::policy;
+use crate::valgrind::MemoryAccess;
+
+/// The policies `--compare-policies` runs side by side
+pub const POLICIES: [&str; 3] = ["lru", "fifo", "random"];
+
+/// Statistics of the trace under each of `POLICIES`, in that order
+pub fn compare_policies(set_bits: u8, lines: u32, block_bits: u8, traces: &[MemoryAccess], seed: u64) -> Result<Vec<(&'static str, Statistics)>, Box<dyn Error>> {
+    let mut caches = Vec::with_capacity(POLICIES.len());
+    for name in POLICIES {
+        let mut cache = Cache::new(set_bits, lines, block_bits)?;
+        cache.set_policy(policy::from_name(name, seed).unwrap());
+        caches.push(cache);
+    }
+
+    for trace in traces {
+        for cache in caches.iter_mut() {
+            cache.access_trace(trace);
+        }
+    }
+    Ok(POLICIES.iter().zip(caches).map(|(&name, cache)| (name, cache.stats)).collect())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::valgrind::parse;
+
+    #[test]
+    fn one_row_per_policy() {
+        // A, B, A, C into one two-way set: LRU evicts B for C, FIFO evicts A
+        let trace = parse(" L 0,1\n L 10,1\n L 0,1\n L 20,1\n L 10,1\n L 0,1").unwrap();
+        let rows = compare_policies(0, 2, 4, &trace, 42).unwrap();
+        let names: Vec<&str> = rows.iter().map(|(name, _)| *name).collect();
+        assert_eq!(names, POLICIES);
+
+        let mut lru = Cache::new(0, 2, 4).unwrap();
+        lru.operate_cache(trace.clone());
+        assert_eq!(rows[0].1, lru.stats);
+        assert_eq!(rows[1].1, Statistics { hit: 2, miss: 4, eviction: 2, ..Statistics::default() });
+        assert!(rows.iter().all(|(_, stats)| stats.accesses() == 6));
+    }
+}
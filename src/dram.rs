@@ -0,0 +1,60 @@
+//! Model the row buffer of a DRAM bank behind the cache
+// Misses are served by a single bank holding one row open at a time. A miss
+// to the open row is a row hit; with no row open it is a row miss, and to a
+// different row a row conflict, which has to close the open row first.
+
+#[derive(Debug, Default, PartialEq)]
+pub struct RowStatistics {
+    pub hit: u32,
+    pub miss: u32,
+    pub conflict: u32,
+}
+
+#[derive(Debug)]
+pub struct RowBuffer {
+    row_bits: u8,
+    open_row: Option<u64>,
+    pub stats: RowStatistics,
+}
+
+impl RowBuffer {
+    /// A bank whose rows span `2^row_bits` bytes
+    pub fn new(row_bits: u8) -> RowBuffer {
+        RowBuffer { row_bits, open_row: None, stats: RowStatistics::default() }
+    }
+
+    /// Close the open row and forget the statistics
+    pub fn reset(&mut self) {
+        self.open_row = None;
+        self.stats = RowStatistics::default();
+    }
+
+    /// Fetch the block at `address` from memory, leaving its row open
+    pub fn access(&mut self, address: u64) {
+        let row = address.checked_shr(self.row_bits as u32).unwrap_or(0);
+
+        match self.open_row.replace(row) {
+            Some(open) if open == row => self.stats.hit += 1,
+            Some(_) => self.stats.conflict += 1,
+            None => self.stats.miss += 1,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cache::Cache;
+    use crate::valgrind::parse;
+
+    #[test]
+    fn sequential_row_hits() {
+        // 1 KiB rows: eight sequential 16-byte blocks share row 0, then 0x400 opens row 1
+        let mut cache = Cache::new(1, 1, 4).unwrap();
+        cache.set_row_buffer(RowBuffer::new(10));
+        let trace: String = (0..8).map(|block| format!(" L {:x},1\n", block * 0x10)).collect();
+        cache.operate_cache(parse(&format!("{} L 400,1\n L 0,1", trace)).unwrap());
+
+        assert_eq!(cache.row_buffer().unwrap().stats, RowStatistics { hit: 7, miss: 1, conflict: 2 });
+    }
+}
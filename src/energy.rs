@@ -0,0 +1,88 @@
+//! Estimate the energy the cache spends on each kind of event
+// Every event has a fixed cost in arbitrary units, so the estimate is the
+// weighted sum of the event counts. A miss is charged for the lookup, and the
+// fill that follows it, if any, is charged separately; so are writebacks of
+// dirty lines on eviction.
+
+use crate::cache::AccessOutcome;
+
+/// Cost of each kind of event, in the caller's units
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct EnergyCosts {
+    pub hit: f64,
+    pub miss: f64,
+    pub writeback: f64,
+    pub fill: f64,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct EnergyStatistics {
+    pub hits: u64,
+    pub misses: u64,
+    pub writebacks: u64,
+    pub fills: u64,
+}
+
+#[derive(Debug)]
+pub struct EnergyModel {
+    costs: EnergyCosts,
+    pub stats: EnergyStatistics,
+}
+
+impl EnergyModel {
+    pub fn new(costs: EnergyCosts) -> EnergyModel {
+        EnergyModel { costs, stats: EnergyStatistics::default() }
+    }
+
+    /// Forget the events counted so far
+    pub fn reset(&mut self) {
+        self.stats = EnergyStatistics::default();
+    }
+
+    /// Count the events of an access that ended in `outcome`
+    pub fn access(&mut self, outcome: AccessOutcome) {
+        match outcome {
+            AccessOutcome::Hit => self.stats.hits += 1,
+            AccessOutcome::Miss | AccessOutcome::MissEviction => {
+                self.stats.misses += 1;
+                self.stats.fills += 1;
+            }
+            // Swapped in from the victim cache rather than fetched
+            AccessOutcome::VictimHit => self.stats.fills += 1,
+            AccessOutcome::WriteAround | AccessOutcome::Bypass => self.stats.misses += 1,
+            AccessOutcome::Forwarded => {}
+        }
+    }
+
+    pub fn writeback(&mut self) {
+        self.stats.writebacks += 1;
+    }
+
+    /// Estimated energy of every event so far
+    pub fn total(&self) -> f64 {
+        self.stats.hits as f64 * self.costs.hit
+            + self.stats.misses as f64 * self.costs.miss
+            + self.stats.writebacks as f64 * self.costs.writeback
+            + self.stats.fills as f64 * self.costs.fill
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cache::Cache;
+    use crate::valgrind::parse;
+
+    #[test]
+    fn weighted_sum_of_events() {
+        // One set of one line: the store to 0x10 dirties the line 0x0 left, and
+        // the load of 0x0 writes it back on the way in
+        let mut cache = Cache::new(0, 1, 4).unwrap();
+        cache.set_energy(EnergyModel::new(EnergyCosts { hit: 1.0, miss: 10.0, writeback: 100.0, fill: 1000.0 }));
+        cache.operate_cache(parse(" L 0,1\n L 0,1\n S 10,1\n L 0,1").unwrap());
+
+        let energy = cache.energy().unwrap();
+        assert_eq!(energy.stats, EnergyStatistics { hits: 1, misses: 3, writebacks: 1, fills: 3 });
+        assert_eq!(energy.total(), 1.0 + 3.0 * 10.0 + 100.0 + 3.0 * 1000.0);
+    }
+}
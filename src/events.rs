@@ -0,0 +1,62 @@
+//! Log of the events the cache goes through for a single set
+
+use std::fmt;
+
+#[derive(Debug, PartialEq)]
+pub enum Event {
+    /// An access mapped to `set` looking for `tag`
+    Access { set: u64, tag: u64 },
+    /// `tag` was found in `line`
+    Hit { set: u64, line: usize, tag: u64 },
+    /// `tag` was brought into `line`
+    Fill { set: u64, line: usize, tag: u64 },
+    /// `victim` was thrown out of `line` to make room
+    Eviction { set: u64, line: usize, victim: u64 },
+}
+
+impl Event {
+    fn set(&self) -> u64 {
+        match *self {
+            Event::Access { set, .. } | Event::Hit { set, .. } | Event::Fill { set, .. } | Event::Eviction { set, .. } => set,
+        }
+    }
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Event::Access { set, tag } => write!(f, "set {}: access {:#x}", set, tag),
+            Event::Hit { set, line, tag } => write!(f, "set {}: hit {:#x} in line {}", set, tag, line),
+            Event::Fill { set, line, tag } => write!(f, "set {}: fill {:#x} into line {}", set, tag, line),
+            Event::Eviction { set, line, victim } => write!(f, "set {}: evict {:#x} from line {}", set, victim, line),
+        }
+    }
+}
+
+/// Keeps the events of one set, discarding the rest
+#[derive(Debug, Default)]
+pub struct EventLog {
+    set: Option<u64>,
+    events: Vec<Event>,
+}
+
+impl EventLog {
+    pub fn for_set(set: u64) -> EventLog {
+        EventLog { set: Some(set), events: vec![] }
+    }
+
+    /// Forget the events recorded so far, still recording the same set
+    pub fn clear(&mut self) {
+        self.events.clear();
+    }
+
+    pub fn emit(&mut self, event: Event) {
+        if self.set == Some(event.set()) {
+            self.events.push(event);
+        }
+    }
+
+    pub fn events(&self) -> &[Event] {
+        &self.events
+    }
+}
@@ -0,0 +1,121 @@
+//! A finite fully-associative LRU cache with no set structure
+// Any block may occupy any of the `capacity` lines, so every miss it takes is
+// a compulsory or capacity miss. The misses a set-associative cache of the same
+// size takes beyond these are conflict misses.
+
+use std::collections::{BTreeMap, HashMap};
+use crate::cache::Cache;
+use crate::valgrind::MemoryAccess;
+
+#[derive(Debug, Default, PartialEq)]
+pub struct GlobalStatistics {
+    pub hit: u32,
+    pub miss: u32,
+    pub eviction: u32,
+}
+
+#[derive(Debug)]
+pub struct FullyAssociative {
+    capacity: usize,
+    block_bits: u8,
+    /// Last use of each resident block
+    resident: HashMap<u64, u64>,
+    /// Resident blocks by last use, least recent first
+    recency: BTreeMap<u64, u64>,
+    clock: u64,
+    pub stats: GlobalStatistics,
+}
+
+impl FullyAssociative {
+    pub fn new(capacity: usize, block_bits: u8) -> FullyAssociative {
+        FullyAssociative {
+            capacity,
+            block_bits,
+            resident: HashMap::new(),
+            recency: BTreeMap::new(),
+            clock: 0,
+            stats: GlobalStatistics::default(),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn operate(&mut self, traces: &[MemoryAccess]) {
+        for trace in traces {
+            self.access(trace.address >> self.block_bits);
+        }
+    }
+
+    fn access(&mut self, block: u64) {
+        self.clock += 1;
+
+        if let Some(last_use) = self.resident.insert(block, self.clock) {
+            self.recency.remove(&last_use);
+            self.recency.insert(self.clock, block);
+            self.stats.hit += 1;
+            return;
+        }
+
+        self.stats.miss += 1;
+        if self.resident.len() > self.capacity {
+            match self.recency.pop_first() {
+                Some((_, victim)) => {
+                    self.resident.remove(&victim);
+                    self.stats.eviction += 1;
+                }
+                // A capacity of zero holds nothing
+                None => {
+                    self.resident.remove(&block);
+                    return;
+                }
+            }
+        }
+        self.recency.insert(self.clock, block);
+    }
+}
+
+/// The most lines per set `min_assoc_for_zero_conflict` tries
+pub const MAX_SEARCH_ASSOC: u8 = u8::MAX;
+
+/// The fewest lines per set at which an LRU cache of `2^set_bits` sets misses no more often
+/// than a fully-associative one of the same size, or `None` if it takes more than `MAX_SEARCH_ASSOC`
+// Adding ways also adds capacity, so each associativity is compared with a
+// fully-associative cache of its own size.
+pub fn min_assoc_for_zero_conflict(traces: &[MemoryAccess], set_bits: u8, block_bits: u8) -> Option<u8> {
+    (1..=MAX_SEARCH_ASSOC).find(|&lines| {
+        let mut cache = Cache::new(set_bits, u32::from(lines), block_bits).unwrap();
+        cache.operate_cache(traces.to_vec());
+
+        let mut global = FullyAssociative::new((lines as usize) << set_bits, block_bits);
+        global.operate(traces);
+        cache.stats.miss <= global.stats.miss
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::valgrind::parse;
+
+    #[test]
+    fn lru_over_lines() {
+        // Blocks A B C A D B A over 3 lines:
+        //     A miss, B miss, C miss, A hit, D miss (evicts B), B miss (evicts C), A hit
+        let trace = " L 0,1\n L 10,1\n L 20,1\n L 0,1\n L 30,1\n L 10,1\n L 0,1";
+        let mut cache = FullyAssociative::new(3, 4);
+        cache.operate(&parse(trace).unwrap());
+
+        assert_eq!(cache.stats, GlobalStatistics { hit: 2, miss: 5, eviction: 2 });
+    }
+
+    #[test]
+    fn zero_conflict_associativity() {
+        // Three blocks cycling through set 0 of four sets fit a fully-associative
+        // cache from one way per set, but set 0 only from three
+        let trace = " L 0,1\n L 40,1\n L 80,1\n L 0,1\n L 40,1\n L 80,1";
+        assert_eq!(min_assoc_for_zero_conflict(&parse(trace).unwrap(), 2, 4), Some(3));
+        assert_eq!(min_assoc_for_zero_conflict(&parse(trace).unwrap(), 0, 4), Some(1));
+    }
+}
@@ -0,0 +1,610 @@
+//! Decompress gzip files as they're read, so traces can be kept gzipped
+// A DEFLATE decoder (RFC 1951) behind gzip's framing (RFC 1952), written out
+// here to keep getopts the only dependency. Huffman codes are decoded a bit
+// at a time from the number of codes of each length, as zlib's puff does:
+// slower than table lookup, but still faster than the simulator consumes
+// accesses. Concatenated members are read one after another, each checked
+// against the CRC-32 and length in its trailer.
+
+use std::io::{self, BufRead, Read};
+
+/// Bytes a back-reference can reach
+const WINDOW: usize = 1 << 15;
+const MAX_CODE_BITS: usize = 15;
+
+// Base and extra bits of each length code from 257, and of each distance code
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u32; 29] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145,
+    8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u32; 30] = [0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13];
+/// Order in which a dynamic block gives the lengths of its code length code
+const CODE_LENGTH_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+fn malformed(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("Error: Malformed gzip stream: {}", what))
+}
+
+/// The input, read least significant bit first
+struct Bits<R> {
+    input: R,
+    buffer: u32,
+    count: u32,
+}
+
+impl<R: BufRead> Bits<R> {
+    fn byte(&mut self) -> io::Result<u8> {
+        let byte = match self.input.fill_buf()?.first() {
+            Some(&byte) => byte,
+            None => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Error: Truncated gzip stream")),
+        };
+        self.input.consume(1);
+        Ok(byte)
+    }
+
+    fn bits(&mut self, n: u32) -> io::Result<u32> {
+        while self.count < n {
+            self.buffer |= u32::from(self.byte()?) << self.count;
+            self.count += 8;
+        }
+        let value = self.buffer & ((1 << n) - 1);
+        self.buffer >>= n;
+        self.count -= n;
+        Ok(value)
+    }
+
+    /// Skip to the next byte boundary; fewer than 8 bits are ever buffered
+    fn align(&mut self) {
+        self.buffer = 0;
+        self.count = 0;
+    }
+
+    fn word(&mut self) -> io::Result<u32> {
+        let bytes = [self.byte()?, self.byte()?, self.byte()?, self.byte()?];
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    fn at_end(&mut self) -> io::Result<bool> {
+        Ok(self.input.fill_buf()?.is_empty())
+    }
+}
+
+/// A canonical Huffman code: how many codes there are of each length, and the symbols in code order
+struct Huffman {
+    counts: [u16; MAX_CODE_BITS + 1],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    /// The code giving symbol `i` a code of `lengths[i]` bits, or none if 0
+    fn new(lengths: &[u8]) -> io::Result<Huffman> {
+        let mut counts = [0; MAX_CODE_BITS + 1];
+        for &length in lengths.iter().filter(|&&length| length > 0) {
+            counts[length as usize] += 1;
+        }
+        // Incomplete codes are let through, and fail only if an unused code turns up
+        let mut left = 1_i32;
+        for &count in &counts[1..] {
+            left = (left << 1) - i32::from(count);
+            if left < 0 {
+                return Err(malformed("over-subscribed Huffman code"));
+            }
+        }
+
+        let mut offsets = [0; MAX_CODE_BITS + 2];
+        for length in 1..=MAX_CODE_BITS {
+            offsets[length + 1] = offsets[length] + counts[length] as usize;
+        }
+        let mut symbols = vec![0; offsets[MAX_CODE_BITS + 1]];
+        for (symbol, &length) in lengths.iter().enumerate().filter(|(_, &length)| length > 0) {
+            symbols[offsets[length as usize]] = symbol as u16;
+            offsets[length as usize] += 1;
+        }
+        Ok(Huffman { counts, symbols })
+    }
+
+    /// The lengths of the fixed literal/length and distance codes
+    fn fixed() -> io::Result<(Huffman, Huffman)> {
+        let mut lengths = [8; 288];
+        lengths[144..256].iter_mut().for_each(|length| *length = 9);
+        lengths[256..280].iter_mut().for_each(|length| *length = 7);
+        Ok((Huffman::new(&lengths)?, Huffman::new(&[5; 30])?))
+    }
+
+    fn decode<R: BufRead>(&self, bits: &mut Bits<R>) -> io::Result<u16> {
+        // Codes of each length follow on from the last code of the length before
+        let (mut code, mut first, mut index) = (0_i32, 0_i32, 0_i32);
+        for &count in &self.counts[1..] {
+            code |= bits.bits(1)? as i32;
+            let count = i32::from(count);
+            if code - first < count {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(malformed("invalid Huffman code"))
+    }
+}
+
+enum State {
+    Header,
+    Block,
+    /// Bytes left of a stored block
+    Stored(usize),
+    Codes,
+    /// A back-reference being copied out of the window
+    Copy { length: usize, distance: usize },
+    Trailer,
+    Done,
+}
+
+/// The decompressed contents of a gzip stream
+pub struct GzipReader<R> {
+    bits: Bits<R>,
+    state: State,
+    last_block: bool,
+    literals: Huffman,
+    distances: Huffman,
+    window: Box<[u8]>,
+    /// Bytes decompressed from the current member
+    written: u64,
+    crc: u32,
+    crc_table: [u32; 256],
+}
+
+impl<R: BufRead> GzipReader<R> {
+    pub fn new(input: R) -> GzipReader<R> {
+        let mut crc_table = [0; 256];
+        for (byte, entry) in crc_table.iter_mut().enumerate() {
+            *entry = (0..8).fold(byte as u32, |crc, _| if crc & 1 == 1 { 0xedb8_8320 ^ (crc >> 1) } else { crc >> 1 });
+        }
+        GzipReader {
+            bits: Bits { input, buffer: 0, count: 0 },
+            state: State::Header,
+            last_block: false,
+            literals: Huffman { counts: [0; MAX_CODE_BITS + 1], symbols: vec![] },
+            distances: Huffman { counts: [0; MAX_CODE_BITS + 1], symbols: vec![] },
+            window: vec![0; WINDOW].into_boxed_slice(),
+            written: 0,
+            crc: !0,
+            crc_table,
+        }
+    }
+
+    fn header(&mut self) -> io::Result<()> {
+        let mut header = [0; 10];
+        for byte in header.iter_mut() {
+            *byte = self.bits.byte()?;
+        }
+        if header[..2] != [0x1f, 0x8b] {
+            return Err(malformed("not a gzip file"));
+        }
+        if header[2] != 8 {
+            return Err(malformed("unknown compression method"));
+        }
+
+        let flags = header[3];
+        if flags & 0x04 != 0 {
+            let extra = u16::from_le_bytes([self.bits.byte()?, self.bits.byte()?]);
+            for _ in 0..extra {
+                self.bits.byte()?;
+            }
+        }
+        // The original file name, then a comment, each ended by a zero byte
+        for flag in [0x08, 0x10] {
+            if flags & flag != 0 {
+                while self.bits.byte()? != 0 {}
+            }
+        }
+        if flags & 0x02 != 0 {
+            self.bits.byte()?;
+            self.bits.byte()?;
+        }
+
+        self.last_block = false;
+        self.written = 0;
+        self.crc = !0;
+        Ok(())
+    }
+
+    fn block(&mut self) -> io::Result<State> {
+        self.last_block = self.bits.bits(1)? == 1;
+        match self.bits.bits(2)? {
+            0 => {
+                self.bits.align();
+                let length = self.bits.byte()? as u16 | (self.bits.byte()? as u16) << 8;
+                let complement = self.bits.byte()? as u16 | (self.bits.byte()? as u16) << 8;
+                if length != !complement {
+                    return Err(malformed("stored block length doesn't match its complement"));
+                }
+                Ok(State::Stored(length as usize))
+            }
+            1 => {
+                let (literals, distances) = Huffman::fixed()?;
+                self.literals = literals;
+                self.distances = distances;
+                Ok(State::Codes)
+            }
+            2 => {
+                self.dynamic_codes()?;
+                Ok(State::Codes)
+            }
+            _ => Err(malformed("reserved block type")),
+        }
+    }
+
+    /// Read the literal/length and distance codes at the head of a dynamic block
+    fn dynamic_codes(&mut self) -> io::Result<()> {
+        let literals = self.bits.bits(5)? as usize + 257;
+        let distances = self.bits.bits(5)? as usize + 1;
+        let code_lengths = self.bits.bits(4)? as usize + 4;
+        if literals > 286 || distances > 30 {
+            return Err(malformed("too many codes"));
+        }
+
+        let mut lengths = [0; 19];
+        for &symbol in &CODE_LENGTH_ORDER[..code_lengths] {
+            lengths[symbol] = self.bits.bits(3)? as u8;
+        }
+        let length_code = Huffman::new(&lengths)?;
+
+        let mut lengths = vec![0; literals + distances];
+        let mut index = 0;
+        while index < lengths.len() {
+            let symbol = length_code.decode(&mut self.bits)?;
+            let (length, repeat) = match symbol {
+                0..=15 => (symbol as u8, 1),
+                16 if index == 0 => return Err(malformed("repeated length with none before it")),
+                16 => (lengths[index - 1], 3 + self.bits.bits(2)?),
+                17 => (0, 3 + self.bits.bits(3)?),
+                _ => (0, 11 + self.bits.bits(7)?),
+            };
+            let end = index + repeat as usize;
+            if end > lengths.len() {
+                return Err(malformed("code lengths run past their count"));
+            }
+            lengths[index..end].iter_mut().for_each(|each| *each = length);
+            index = end;
+        }
+        if lengths[256] == 0 {
+            return Err(malformed("no end-of-block code"));
+        }
+
+        self.literals = Huffman::new(&lengths[..literals])?;
+        self.distances = Huffman::new(&lengths[literals..])?;
+        Ok(())
+    }
+
+    /// The state after the next literal/length code
+    fn code(&mut self, buf: &mut [u8], n: &mut usize) -> io::Result<State> {
+        let symbol = self.literals.decode(&mut self.bits)? as usize;
+        if symbol < 256 {
+            self.emit(symbol as u8, buf, n);
+            return Ok(State::Codes);
+        }
+        if symbol == 256 {
+            return Ok(State::Block);
+        }
+
+        let symbol = symbol - 257;
+        if symbol >= LENGTH_BASE.len() {
+            return Err(malformed("invalid length code"));
+        }
+        let length = LENGTH_BASE[symbol] as usize + self.bits.bits(LENGTH_EXTRA[symbol])? as usize;
+        let symbol = self.distances.decode(&mut self.bits)? as usize;
+        if symbol >= DISTANCE_BASE.len() {
+            return Err(malformed("invalid distance code"));
+        }
+        let distance = DISTANCE_BASE[symbol] as usize + self.bits.bits(DISTANCE_EXTRA[symbol])? as usize;
+        if distance as u64 > self.written {
+            return Err(malformed("distance reaches back before the start"));
+        }
+        Ok(State::Copy { length, distance })
+    }
+
+    fn trailer(&mut self) -> io::Result<State> {
+        self.bits.align();
+        let crc = self.bits.word()?;
+        let size = self.bits.word()?;
+        if crc != !self.crc || size != self.written as u32 {
+            return Err(malformed("checksum or length doesn't match the contents"));
+        }
+        if self.bits.at_end()? {
+            Ok(State::Done)
+        } else {
+            Ok(State::Header)
+        }
+    }
+
+    fn emit(&mut self, byte: u8, buf: &mut [u8], n: &mut usize) {
+        buf[*n] = byte;
+        *n += 1;
+        self.window[self.written as usize % WINDOW] = byte;
+        self.written += 1;
+        self.crc = self.crc_table[((self.crc ^ u32::from(byte)) & 0xff) as usize] ^ (self.crc >> 8);
+    }
+}
+
+impl<R: BufRead> Read for GzipReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut n = 0;
+        while n < buf.len() {
+            self.state = match self.state {
+                State::Header => {
+                    self.header()?;
+                    State::Block
+                }
+                State::Block if self.last_block => State::Trailer,
+                State::Block => self.block()?,
+                State::Stored(0) => State::Block,
+                State::Stored(left) => {
+                    let byte = self.bits.byte()?;
+                    self.emit(byte, buf, &mut n);
+                    State::Stored(left - 1)
+                }
+                State::Codes => self.code(buf, &mut n)?,
+                State::Copy { length: 0, .. } => State::Codes,
+                State::Copy { length, distance } => {
+                    let byte = self.window[(self.written as usize + WINDOW - distance) % WINDOW];
+                    self.emit(byte, buf, &mut n);
+                    State::Copy { length: length - 1, distance }
+                }
+                State::Trailer => self.trailer()?,
+                State::Done => break,
+            };
+        }
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn decompress(bytes: &[u8]) -> io::Result<Vec<u8>> {
+        let mut contents = vec![];
+        GzipReader::new(bytes).read_to_end(&mut contents)?;
+        Ok(contents)
+    }
+
+    // A compressor just good enough to produce each kind of block, so the streams the
+    // tests read are built from their plain text rather than checked in
+
+    /// Bits written least significant first, as DEFLATE packs them
+    #[derive(Default)]
+    struct BitWriter {
+        bytes: Vec<u8>,
+        used: u32,
+    }
+
+    impl BitWriter {
+        fn bits(&mut self, value: u32, n: u32) {
+            for bit in 0..n {
+                if self.used == 0 {
+                    self.bytes.push(0);
+                }
+                *self.bytes.last_mut().unwrap() |= (((value >> bit) & 1) as u8) << self.used;
+                self.used = (self.used + 1) % 8;
+            }
+        }
+
+        /// A Huffman code, whose first bit is its most significant
+        fn code(&mut self, (code, length): (u32, u32)) {
+            let reversed = (0..length).fold(0, |reversed, bit| (reversed << 1) | ((code >> bit) & 1));
+            self.bits(reversed, length);
+        }
+
+        fn align(&mut self) {
+            self.used = 0;
+        }
+    }
+
+    /// The canonical code of each symbol given its length, as (code, length)
+    fn canonical(lengths: &[u8]) -> Vec<(u32, u32)> {
+        let mut next = [0; MAX_CODE_BITS + 1];
+        let mut code = 0;
+        for (length, first) in next.iter_mut().enumerate().skip(1) {
+            code = (code + lengths.iter().filter(|&&each| each as usize == length - 1 && each > 0).count() as u32) << 1;
+            *first = code;
+        }
+        lengths.iter().map(|&length| {
+            let code = next[length as usize];
+            next[length as usize] += 1;
+            (code, u32::from(length))
+        }).collect()
+    }
+
+    enum Token {
+        Literal(u8),
+        Copy { length: usize, distance: usize },
+    }
+
+    /// The longest earlier match at each position, taken greedily
+    fn tokens(data: &[u8]) -> Vec<Token> {
+        let mut tokens = vec![];
+        let mut position = 0;
+        while position < data.len() {
+            let longest = (1..=position.min(1024)).map(|distance| {
+                let length = (0..258.min(data.len() - position)).take_while(|&i| data[position + i] == data[position + i - distance]).count();
+                (length, distance)
+            }).max_by_key(|&(length, distance)| (length, std::cmp::Reverse(distance)));
+            match longest {
+                Some((length, distance)) if length >= 3 => {
+                    tokens.push(Token::Copy { length, distance });
+                    position += length;
+                }
+                _ => {
+                    tokens.push(Token::Literal(data[position]));
+                    position += 1;
+                }
+            }
+        }
+        tokens
+    }
+
+    /// Index of the code whose base is the greatest not above `value`
+    fn base_code(bases: &[u16], value: usize) -> usize {
+        bases.iter().rposition(|&base| base as usize <= value).unwrap()
+    }
+
+    fn codes(out: &mut BitWriter, data: &[u8], literals: &[(u32, u32)], distances: &[(u32, u32)]) {
+        for token in tokens(data) {
+            match token {
+                Token::Literal(byte) => out.code(literals[byte as usize]),
+                Token::Copy { length, distance } => {
+                    let code = base_code(&LENGTH_BASE, length);
+                    out.code(literals[257 + code]);
+                    out.bits((length - LENGTH_BASE[code] as usize) as u32, LENGTH_EXTRA[code]);
+                    let code = base_code(&DISTANCE_BASE, distance);
+                    out.code(distances[code]);
+                    out.bits((distance - DISTANCE_BASE[code] as usize) as u32, DISTANCE_EXTRA[code]);
+                }
+            }
+        }
+        out.code(literals[256]);
+    }
+
+    #[derive(Clone, Copy)]
+    enum Block {
+        /// Uncompressed, in blocks of at most 1000 bytes
+        Stored,
+        Fixed,
+        Dynamic,
+    }
+
+    fn deflate(data: &[u8], block: Block) -> Vec<u8> {
+        let mut out = BitWriter::default();
+        match block {
+            Block::Stored => {
+                let chunks: Vec<&[u8]> = data.chunks(1000).collect();
+                for (index, chunk) in chunks.iter().enumerate() {
+                    out.bits((index + 1 == chunks.len()) as u32, 1);
+                    out.bits(0, 2);
+                    out.align();
+                    let length = chunk.len() as u16;
+                    out.bytes.extend(length.to_le_bytes().iter().chain(&(!length).to_le_bytes()).chain(chunk.iter()));
+                }
+            }
+            Block::Fixed => {
+                let mut lengths = [8; 288];
+                lengths[144..256].iter_mut().for_each(|length| *length = 9);
+                lengths[256..280].iter_mut().for_each(|length| *length = 7);
+                out.bits(1, 1);
+                out.bits(1, 2);
+                codes(&mut out, data, &canonical(&lengths), &canonical(&[5; 30]));
+            }
+            Block::Dynamic => {
+                // Complete codes of lengths 8 and 9, and 4 and 5, sent through a code
+                // length code of lengths 4 and 5, with runs repeated by symbol 16
+                let lengths: Vec<u8> = (0..286).map(|symbol| if symbol < 226 { 8 } else { 9 })
+                    .chain((0..30).map(|symbol| if symbol < 2 { 4 } else { 5 })).collect();
+                let length_lengths: Vec<u8> = (0..19).map(|symbol| if symbol < 13 { 4 } else { 5 }).collect();
+                let length_code = canonical(&length_lengths);
+
+                out.bits(1, 1);
+                out.bits(2, 2);
+                out.bits(286 - 257, 5);
+                out.bits(30 - 1, 5);
+                out.bits(19 - 4, 4);
+                for &symbol in &CODE_LENGTH_ORDER {
+                    out.bits(u32::from(length_lengths[symbol]), 3);
+                }
+                let mut index = 0;
+                while index < lengths.len() {
+                    let run = lengths[index..].iter().take_while(|&&length| length == lengths[index]).count();
+                    out.code(length_code[lengths[index] as usize]);
+                    let mut left = run - 1;
+                    while left >= 3 {
+                        let repeat = left.min(6);
+                        out.code(length_code[16]);
+                        out.bits(repeat as u32 - 3, 2);
+                        left -= repeat;
+                    }
+                    for _ in 0..left {
+                        out.code(length_code[lengths[index] as usize]);
+                    }
+                    index += run;
+                }
+                codes(&mut out, data, &canonical(&lengths[..286]), &canonical(&lengths[286..]));
+            }
+        }
+        out.bytes
+    }
+
+    fn crc32(data: &[u8]) -> u32 {
+        !data.iter().fold(!0, |crc, &byte| {
+            (0..8).fold(crc ^ u32::from(byte), |crc, _| if crc & 1 == 1 { 0xedb8_8320 ^ (crc >> 1) } else { crc >> 1 })
+        })
+    }
+
+    /// A gzip member holding `data`, naming its original file
+    fn gzip(data: &[u8], block: Block) -> Vec<u8> {
+        let mut member = vec![0x1f, 0x8b, 8, 0x08, 0, 0, 0, 0, 0, 0xff];
+        member.extend(b"trans.trace\0");
+        member.extend(deflate(data, block));
+        member.extend(crc32(data).to_le_bytes());
+        member.extend((data.len() as u32).to_le_bytes());
+        member
+    }
+
+    const TRACE: &[u8] = include_bytes!("../test/trans.trace");
+
+    #[test]
+    fn each_block_type() {
+        for block in [Block::Stored, Block::Fixed, Block::Dynamic] {
+            assert_eq!(decompress(&gzip(TRACE, block)).unwrap(), TRACE);
+        }
+        // Both Huffman blocks really did take back-references
+        assert!(gzip(TRACE, Block::Fixed).len() < TRACE.len() / 2);
+        assert!(gzip(TRACE, Block::Dynamic).len() < TRACE.len() / 2);
+        assert_eq!(decompress(&gzip(b"", Block::Fixed)).unwrap(), b"");
+    }
+
+    #[test]
+    fn members_concatenate() {
+        let bytes = [gzip(b" L 10,1\n", Block::Fixed), gzip(b" S 20,1\n", Block::Stored)].concat();
+        assert_eq!(decompress(&bytes).unwrap(), b" L 10,1\n S 20,1\n");
+    }
+
+    #[test]
+    fn truncation_detected() {
+        let data = b" L 10,1\n S 20,1\n L 10,1\n";
+        for block in [Block::Stored, Block::Fixed, Block::Dynamic] {
+            let member = gzip(data, block);
+            for length in 0..member.len() {
+                assert!(decompress(&member[..length]).is_err(), "{} of {} bytes", length, member.len());
+            }
+        }
+    }
+
+    #[test]
+    fn corruption_detected() {
+        let data = b" L 10,1\n S 20,1\n";
+        let error = |bytes: &[u8]| decompress(bytes).unwrap_err().to_string();
+
+        let mut member = gzip(data, Block::Stored);
+        let contents = member.len() - 9;
+        member[contents] ^= 1;
+        assert!(error(&member).contains("checksum"));
+        let mut member = gzip(data, Block::Dynamic);
+        let size = member.len() - 4;
+        member[size] ^= 1;
+        assert!(error(&member).contains("length"));
+
+        assert!(error(data).contains("not a gzip file"));
+        // Block type 3 is reserved
+        let mut member = gzip(data, Block::Fixed);
+        member[22] |= 0b110;
+        assert!(error(&member).contains("reserved block type"));
+        // The complement of a stored block's length is its next two bytes
+        let mut member = gzip(data, Block::Stored);
+        member[25] ^= 1;
+        assert!(error(&member).contains("complement"));
+    }
+}
@@ -0,0 +1,149 @@
+//! An L1 cache backed by an L2, each of its own geometry
+// Only the accesses that miss in L1 reach L2, so L2's statistics are those of
+// the L1 miss stream. By default neither level constrains the other's
+// contents: L1 evictions are dropped rather than written into L2, and L2
+// evictions leave any copy in L1 behind. `Inclusion` can instead keep L1 a
+// subset of L2, or keep the two levels' contents apart.
+
+use crate::cache::{AccessOutcome, Cache};
+use crate::valgrind::MemoryAccess;
+
+/// How the contents of L1 and L2 relate
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Inclusion {
+    /// Each level fills and evicts on its own
+    NonInclusive,
+    /// Every block in L1 is in L2 too: an L2 eviction invalidates the block in L1
+    Inclusive,
+    /// A block is in at most one level: an L2 hit moves the block up into L1, an L1 miss
+    /// fills only L1, and L1's evictions are written into L2
+    Exclusive,
+}
+
+impl Inclusion {
+    pub fn from_name(name: &str) -> Option<Inclusion> {
+        match name {
+            "non-inclusive" => Some(Inclusion::NonInclusive),
+            "inclusive" => Some(Inclusion::Inclusive),
+            "exclusive" => Some(Inclusion::Exclusive),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct CacheHierarchy {
+    pub l1: Cache,
+    pub l2: Cache,
+    inclusion: Inclusion,
+}
+
+impl CacheHierarchy {
+    pub fn new(l1: Cache, l2: Cache) -> CacheHierarchy {
+        CacheHierarchy { l1, l2, inclusion: Inclusion::NonInclusive }
+    }
+
+    pub fn set_inclusion(&mut self, inclusion: Inclusion) {
+        self.inclusion = inclusion;
+    }
+
+    pub fn operate_cache(&mut self, traces: Vec<MemoryAccess>) {
+        for trace in &traces {
+            let outcome = self.l1.access_trace(trace);
+            match (self.inclusion, outcome) {
+                (_, AccessOutcome::Hit | AccessOutcome::VictimHit | AccessOutcome::Forwarded) => {}
+                // Only a block L1 took is moved out of L2
+                (Inclusion::Exclusive, AccessOutcome::Miss | AccessOutcome::MissEviction) => {
+                    if self.l2.invalidate(trace.address) {
+                        self.l2.stats.hit += 1;
+                    } else {
+                        self.l2.stats.miss += 1;
+                    }
+                    for evicted in self.l1.evicted().to_vec() {
+                        self.l2.install(evicted);
+                    }
+                }
+                (Inclusion::Inclusive, _) => {
+                    self.l2.access_trace(trace);
+                    self.back_invalidate();
+                }
+                _ => {
+                    self.l2.access_trace(trace);
+                }
+            }
+        }
+    }
+
+    /// Invalidate in L1 every part of the blocks L2 just evicted
+    // L1 hits never reach L2, so a block busy in L1 can age out of L2 and be lost from both
+    fn back_invalidate(&mut self) {
+        let (l1_block, l2_block) = (self.l1.block_bytes(), self.l2.block_bytes());
+        for &block in self.l2.evicted() {
+            let mut address = block;
+            while address < block + l2_block {
+                self.l1.invalidate(address);
+                address += l1_block;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cache::Statistics;
+    use crate::valgrind::parse;
+
+    #[test]
+    fn l1_misses_reach_l2() {
+        // A and B thrash the one line of L1, but both fit in the two of L2
+        let mut hierarchy = CacheHierarchy::new(Cache::new(0, 1, 4).unwrap(), Cache::new(0, 2, 4).unwrap());
+        hierarchy.operate_cache(parse(" L 0,1\n L 10,1\n L 0,1\n L 0,1").unwrap());
+
+        assert_eq!(hierarchy.l1.stats, Statistics { hit: 1, miss: 3, eviction: 2, ..Statistics::default() });
+        assert_eq!(hierarchy.l2.stats, Statistics { hit: 1, miss: 2, eviction: 0, ..Statistics::default() });
+    }
+
+    // L1 has a set each for A and B, L2 a single line: B's fill of L2 evicts A
+    fn levels_after(inclusion: Inclusion, trace: &str) -> CacheHierarchy {
+        let mut hierarchy = CacheHierarchy::new(Cache::new(1, 1, 4).unwrap(), Cache::new(0, 1, 4).unwrap());
+        hierarchy.set_inclusion(inclusion);
+        hierarchy.operate_cache(parse(trace).unwrap());
+        hierarchy
+    }
+
+    #[test]
+    fn inclusive_l2_eviction_invalidates_l1() {
+        let trace = " L 0,1\n L 10,1\n L 0,1";
+        let inclusive = levels_after(Inclusion::Inclusive, trace);
+        assert_eq!(inclusive.l1.stats, Statistics { hit: 0, miss: 3, eviction: 0, ..Statistics::default() });
+        let non_inclusive = levels_after(Inclusion::NonInclusive, trace);
+        assert_eq!(non_inclusive.l1.stats, Statistics { hit: 1, miss: 2, eviction: 0, ..Statistics::default() });
+    }
+
+    #[test]
+    fn exclusive_levels_hold_a_block_once() {
+        // A and C thrash L1's set 0: C's fill sends A down to L2, where A's next miss finds it
+        let exclusive = levels_after(Inclusion::Exclusive, " L 0,1\n L 10,1\n L 20,1\n L 0,1");
+        assert_eq!(exclusive.l1.stats, Statistics { hit: 0, miss: 4, eviction: 2, ..Statistics::default() });
+        assert_eq!(exclusive.l2.stats, Statistics { hit: 1, miss: 3, eviction: 0, ..Statistics::default() });
+        // Each block is held once: A and B in L1, C in L2
+        assert_eq!(exclusive.l1.valid_lines() + exclusive.l2.valid_lines(), 3);
+    }
+
+    #[test]
+    fn exclusive_l2_eviction_leaves_l1_alone() {
+        // C sends A down into L2's one line, then D sends C down after it, evicting A from L2
+        let trace = " L 0,1\n L 10,1\n L 20,1\n L 40,1\n L 10,1";
+        let exclusive = levels_after(Inclusion::Exclusive, trace);
+        let mut alone = Cache::new(1, 1, 4).unwrap();
+        alone.operate_cache(parse(trace).unwrap());
+
+        assert_eq!(exclusive.l2.evicted(), [0x0]);
+        assert_eq!(exclusive.l2.dump(), "set 0: line 0 tag 0x2 stamp 2\n");
+        // L1 holds and counts just what it would with no L2 behind it, B's hit included
+        assert_eq!(exclusive.l1.stats, alone.stats);
+        assert_eq!(exclusive.l1.stats.hits(), 1);
+        assert_eq!(exclusive.l1.dump(), alone.dump());
+    }
+}
@@ -0,0 +1,163 @@
+//! Insertion policies deciding the recency position of a newly filled line
+// Under the usual LRU arrangement a fill becomes the most recently used line,
+// so a single pass over more blocks than a set holds flushes the whole
+// working set. LIP inserts at the least recently used position instead, only
+// promoting a line once it is hit, and BIP does the same except for an
+// occasional MRU insertion so the cache can still adapt to a new working set
+// (Qureshi et al., "Adaptive Insertion Policies for High Performance Caching",
+// ISCA 2007).
+//
+// DIP, from the same paper, picks between LRU and BIP insertion at run time by
+// set dueling: a few leader sets always use one or the other, a saturating
+// counter tracks which leaders miss more, and every other set follows the
+// leaders that are missing less.
+
+use crate::rng::Rng;
+
+/// BIP inserts at the MRU position once in this many fills
+const BIP_THROTTLE: u64 = 32;
+
+/// One set in this many leads for LRU insertion and one for BIP
+const DUEL_SPACING: u64 = 32;
+
+/// Width of DIP's policy selection counter
+const PSEL_BITS: u32 = 10;
+
+#[derive(Debug)]
+pub enum Insertion {
+    /// Insert at the most recently used position
+    Mru,
+    /// LRU insertion policy: always insert at the least recently used position
+    Lip,
+    /// Bimodal insertion policy: mostly LIP, occasionally MRU
+    Bip(Rng),
+    /// Dynamic insertion policy: LRU or BIP, whichever set dueling finds misses less
+    Dip(Dip),
+}
+
+#[derive(Debug)]
+pub struct Dip {
+    /// Leader sets are chosen by their index modulo this
+    spacing: u64,
+    /// Counts up on LRU leader misses and down on BIP leader misses
+    psel: u32,
+    rng: Rng,
+}
+
+impl Dip {
+    /// DIP over a cache of `num_sets` sets; with a single set it is plain LRU insertion
+    pub fn new(num_sets: u64, seed: u64) -> Dip {
+        Dip { spacing: num_sets.clamp(1, DUEL_SPACING), psel: 1 << (PSEL_BITS - 1), rng: Rng::new(seed) }
+    }
+
+    /// Whether follower sets currently insert with BIP
+    pub fn prefers_bip(&self) -> bool {
+        self.psel > 1 << (PSEL_BITS - 1)
+    }
+
+    fn uses_bip(&self, set: u64) -> bool {
+        match set % self.spacing {
+            0 => false,
+            offset if offset == self.spacing - 1 => true,
+            _ => self.prefers_bip(),
+        }
+    }
+
+    fn record_miss(&mut self, set: u64) {
+        match set % self.spacing {
+            0 => self.psel = (self.psel + 1).min((1 << PSEL_BITS) - 1),
+            offset if offset == self.spacing - 1 => self.psel = self.psel.saturating_sub(1),
+            _ => {}
+        }
+    }
+}
+
+impl Insertion {
+    /// Build an insertion policy from its command-line name
+    pub fn from_name(name: &str, seed: u64) -> Option<Insertion> {
+        match name {
+            "lru" => Some(Insertion::Mru),
+            "lip" => Some(Insertion::Lip),
+            "bip" => Some(Insertion::Bip(Rng::new(seed))),
+            _ => None,
+        }
+    }
+
+    /// Recency stamp for a line filled into `set` at time `now`
+    pub fn stamp(&mut self, set: u64, now: u64) -> u64 {
+        match self {
+            Insertion::Mru => now,
+            Insertion::Lip => 0,
+            Insertion::Bip(rng) => bimodal(rng, now),
+            Insertion::Dip(dip) if dip.uses_bip(set) => bimodal(&mut dip.rng, now),
+            Insertion::Dip(_) => now,
+        }
+    }
+
+    /// Note a miss in `set`, for policies that adapt to the miss rate
+    pub fn record_miss(&mut self, set: u64) {
+        if let Insertion::Dip(dip) = self {
+            dip.record_miss(set);
+        }
+    }
+}
+
+fn bimodal(rng: &mut Rng, now: u64) -> u64 {
+    if rng.below(BIP_THROTTLE) == 0 { now } else { 0 }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cache::Cache;
+    use crate::valgrind::parse;
+
+    /// Hits on a two-block working set interleaved with scans through eight streaming blocks
+    fn scan_hits(insertion: Insertion) -> u32 {
+        scan_cache(1, insertion).stats.hit
+    }
+
+    /// `scan_hits` in each of `2^set_bits` sets of four lines, returning the cache
+    fn scan_cache(set_bits: u8, insertion: Insertion) -> Cache {
+        let block = |tag: u64, set: u64| ((tag << set_bits) | set) << 4;
+        let mut accesses = vec![];
+        for round in 0..20 {
+            for set in 0..1 << set_bits {
+                accesses.extend_from_slice(&[block(0, set), block(1, set), block(0, set), block(1, set)]);
+                accesses.extend((0..8).map(|i| block(0x100 + round * 8 + i, set)));
+            }
+        }
+        let trace: Vec<String> = accesses.iter().map(|address| format!(" L {:x},1", address)).collect();
+
+        let mut cache = Cache::new(set_bits, 4, 4).unwrap();
+        cache.set_insertion(insertion);
+        cache.operate_cache(parse(&trace.join("\n")).unwrap());
+        cache
+    }
+
+    #[test]
+    fn lip_resists_scans() {
+        assert!(scan_hits(Insertion::Lip) > scan_hits(Insertion::Mru));
+    }
+
+    #[test]
+    fn bip_occasionally_inserts_at_mru() {
+        let mut bip = Insertion::Bip(Rng::new(7));
+        let stamps: Vec<u64> = (1..=1000).map(|now| bip.stamp(0, now)).collect();
+        let promoted = stamps.iter().filter(|&&stamp| stamp != 0).count();
+
+        assert!(promoted > 0 && promoted < 100);
+        assert_eq!(Insertion::Lip.stamp(0, 5), 0);
+        assert_eq!(Insertion::Mru.stamp(0, 5), 5);
+    }
+
+    #[test]
+    fn dip_duels_towards_bip_under_scans() {
+        let dip = scan_cache(3, Insertion::Dip(Dip::new(8, 7)));
+        match &dip.insertion() {
+            Insertion::Dip(state) => assert!(state.prefers_bip()),
+            _ => unreachable!(),
+        }
+        assert!(dip.stats.hit > scan_cache(3, Insertion::Mru).stats.hit);
+    }
+}
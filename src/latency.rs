@@ -0,0 +1,85 @@
+//! Model the effective latency of cache accesses
+// Accesses are serviced one after another by a blocking pipeline: each access
+// advances a cycle counter by its latency. A miss fills the block from memory
+// in `miss_penalty` cycles. With critical-word-first, the word that triggered
+// the miss is delivered ahead of the rest of the block, so the triggering
+// access may proceed early while the fill completes in the background; a
+// later access to the same block during the fill waits for the remainder.
+
+/// Width of a single transfer from memory in bytes
+const WORD_BYTES: u64 = 8;
+
+#[derive(Debug)]
+pub struct LatencyModel {
+    hit_time: u64,
+    miss_penalty: u64,
+    critical_word_first: bool,
+    words_per_block: u64,
+    cycle: u64,
+    miss_cycles: u64,
+    misses: u64,
+    last_latency: u64,
+}
+
+impl LatencyModel {
+    pub fn new(hit_time: u64, miss_penalty: u64, critical_word_first: bool, block_bits: u8) -> LatencyModel {
+        let block_bytes = 1_u64 << block_bits;
+
+        LatencyModel {
+            hit_time,
+            miss_penalty,
+            critical_word_first,
+            words_per_block: (block_bytes / WORD_BYTES).max(1),
+            cycle: 0,
+            miss_cycles: 0,
+            misses: 0,
+            last_latency: 0,
+        }
+    }
+
+    /// Account for a hit on a line whose fill completes at cycle `ready`
+    pub fn hit(&mut self, ready: u64) {
+        let wait = ready.saturating_sub(self.cycle);
+        // Waiting on an in-flight fill is part of the cost of that miss
+        self.miss_cycles += wait;
+        self.last_latency = self.hit_time + wait;
+        self.cycle += self.last_latency;
+    }
+
+    /// Account for a miss, returning the cycle at which the whole block is resident
+    pub fn miss(&mut self) -> u64 {
+        let ready = self.cycle + self.hit_time + self.miss_penalty;
+        let latency = if self.critical_word_first {
+            self.hit_time + self.miss_penalty.div_ceil(self.words_per_block)
+        } else {
+            self.hit_time + self.miss_penalty
+        };
+
+        self.misses += 1;
+        self.miss_cycles += latency;
+        self.last_latency = latency;
+        self.cycle += latency;
+        ready
+    }
+
+    /// Forget the cycles and misses counted so far
+    pub fn reset(&mut self) {
+        self.cycle = 0;
+        self.miss_cycles = 0;
+        self.misses = 0;
+        self.last_latency = 0;
+    }
+
+    /// Cycles taken by the most recent access
+    pub fn last_latency(&self) -> u64 {
+        self.last_latency
+    }
+
+    /// Mean number of cycles a miss stalled its dependent accesses
+    pub fn average_miss_latency(&self) -> f64 {
+        if self.misses == 0 {
+            return 0.0;
+        }
+        self.miss_cycles as f64 / self.misses as f64
+    }
+}
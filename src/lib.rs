@@ -0,0 +1,123 @@
+//! Simulate a CPU cache over Valgrind memory traces
+//!
+//! ```
+//! use csim::{Cache, parse};
+//!
+//! let mut cache = Cache::new(1, 1, 4).unwrap();
+//! cache.operate_cache(parse(" L 0,1\n L 8,1\n L 20,1").unwrap());
+//! assert_eq!((cache.stats.hits(), cache.stats.misses()), (1, 2));
+//! ```
+
+use std::error::Error;
+
+pub mod valgrind;
+pub mod cache;
+pub mod latency;
+pub mod translate;
+pub mod policy;
+pub mod rng;
+pub mod victim;
+pub mod store_buffer;
+pub mod insertion;
+pub mod events;
+pub mod access_log;
+pub mod dram;
+pub mod fully_associative;
+pub mod stats_file;
+pub mod ports;
+pub mod reuse;
+pub mod write_back;
+pub mod split;
+pub mod columnar;
+pub mod selftest;
+pub mod energy;
+pub mod hierarchy;
+pub mod gzip;
+pub mod batch;
+pub mod compare;
+pub mod progress;
+
+pub use cache::{AccessOutcome, Cache, CacheBuilder, RunReport, Statistics};
+pub use valgrind::{parse, MemoryAccess, Operation, ParseError};
+
+/// Shape of the cache to simulate
+#[derive(Debug, Clone, PartialEq)]
+pub struct Geometry {
+    /// Number of set index bits
+    pub set_bits: u8,
+    /// Number of lines per set
+    pub lines: u32,
+    /// Number of block offset bits
+    pub block_bits: u8,
+    /// Replacement policy: `lru`, `mru`, `fifo`, `lfu`, `random`, `opt` or `dip`
+    pub policy: String,
+}
+
+impl Geometry {
+    /// An LRU cache of `2^set_bits` sets of `lines` lines of `2^block_bits` bytes
+    pub fn new(set_bits: u8, lines: u32, block_bits: u8) -> Geometry {
+        Geometry { set_bits, lines, block_bits, policy: String::from("lru") }
+    }
+
+    /// A one-line summary of a run on this geometry, for pasting into a commit message
+    pub fn badge(&self, stats: &Statistics) -> String {
+        format!(
+            "csim: {}% hits (s={} E={} b={})",
+            cache::format_rate(stats.hit_rate() * 100.0, 1), self.set_bits, self.lines, self.block_bits,
+        )
+    }
+}
+
+/// Parse `trace` and run it through a cache of the given geometry
+///
+/// The random policy is seeded with 0 and LRU is ordered by a logical clock,
+/// so equal calls give equal statistics.
+///
+/// ```
+/// let stats = csim::simulate(csim::Geometry::new(1, 1, 4), " L 0,1\n L 8,1\n L 20,1").unwrap();
+/// assert_eq!((stats.hits(), stats.misses(), stats.evictions()), (1, 2, 1));
+/// ```
+pub fn simulate(geometry: Geometry, trace: &str) -> Result<Statistics, Box<dyn Error>> {
+    let traces = valgrind::parse(trace)?;
+
+    let mut cache = cache::Cache::new(geometry.set_bits, geometry.lines, geometry.block_bits)?;
+    let replacement: Box<dyn policy::ReplacementPolicy> = match geometry.policy.as_str() {
+        "opt" => Box::new(policy::Opt::new(&traces, geometry.set_bits, geometry.block_bits)),
+        "dip" => {
+            cache.set_insertion(insertion::Insertion::Dip(insertion::Dip::new(1 << geometry.set_bits, 0)));
+            Box::new(policy::Lru)
+        }
+        name => match policy::from_name(name, 0) {
+            Some(replacement) => replacement,
+            None => return Err(format!("Error: Unknown replacement policy \"{}\"", name).into()),
+        },
+    };
+    cache.set_policy(replacement);
+
+    cache.operate_cache(traces);
+    Ok(cache.stats)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn simulate_end_to_end() {
+        let trace = std::fs::read_to_string("test/trans.trace").unwrap();
+        let stats = simulate(Geometry::new(2, 1, 3), &trace).unwrap();
+        assert_eq!((stats.hit, stats.miss, stats.eviction), (167, 71, 67));
+
+        let mut geometry = Geometry::new(2, 1, 3);
+        geometry.policy = String::from("plru");
+        assert!(simulate(geometry, &trace).is_err());
+    }
+
+    #[test]
+    fn badge_for_known_run() {
+        let trace = std::fs::read_to_string("test/trans.trace").unwrap();
+        let geometry = Geometry::new(2, 1, 3);
+        let stats = simulate(geometry.clone(), &trace).unwrap();
+        assert_eq!(geometry.badge(&stats), "csim: 70.2% hits (s=2 E=1 b=3)");
+    }
+}
@@ -1,51 +1,1935 @@
-/// Simulate a LRU CPU cache
+//! Simulate a LRU CPU cache
 
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
 use std::env;
-use std::fs;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, IsTerminal, Read, Write};
 use std::error::Error;
+use std::time::{SystemTime, UNIX_EPOCH};
 use getopts::Options;
 
-mod valgrind;
-mod cache;
+use csim::{access_log, batch, cache, columnar, compare, dram, energy, fully_associative, gzip, hierarchy, insertion, latency, policy, ports, progress, reuse, selftest, split, stats_file, store_buffer, translate, valgrind, victim, write_back};
+
+/// Options beyond a plain cache and its core counters, which `--lightweight` excludes
+const HEAVYWEIGHT_OPTIONS: [&str; 68] = [
+    "hit-time", "miss-penalty", "critical-word-first", "heatmap", "page-table", "vipt", "victim-size",
+    "store-buffer", "insertion", "top-evicted", "auto-addr-bits", "trace-set", "collect-errors", "access-log", "csv",
+    "no-write-allocate", "dram-row-bits", "flamegraph", "global-assoc", "per-thread-stats",
+    "replacement-state-dump", "stats-binary", "warn-footprint", "op-weight", "coalesce-instructions",
+    "victim-policy", "drain-policy", "store-miss-counts", "sets", "replay-addresses",
+    "flush-every", "skip-null", "phases", "read-ports", "write-ports", "issue-width",
+    "repeat", "repeat-report", "lock", "trace-window", "warm-before", "hash",
+    "reuse-per-block", "wb-buffer", "wb-drain-rate", "byte-valid", "badge", "columnar",
+    "exclude-cold-sets", "set-miss-rates", "warm-trace",
+    "dump-parse", "min-assoc-for-zero-conflict", "energy-hit", "energy-miss", "energy-writeback",
+    "energy-fill", "canonical", "write-back", "prefetch", "split-accesses",
+    "dump", "per-op-stats", "addr-bits", "trace-format",
+    "verbose", "warmup", "classify-misses",
+];
+
+/// Options of a full run that --lightweight takes too, but the other run modes don't
+const STREAMED_OPTIONS: [&str; 4] = ["max-accesses", "progress", "snapshot-interval", "snapshot-file"];
+
+/// Seed for the random policy when `--deterministic` is given without `--seed`
+const DETERMINISTIC_SEED: u64 = 0;
+
+/// Decimal places in reported rates unless `--precision` says otherwise
+const DEFAULT_PRECISION: usize = 2;
+
+/// Most lines per set -E takes, enough for a fully-associative cache of a million lines
+const MAX_LINES: u32 = 1 << 20;
+
+/// Accesses between the reports of `--progress`
+const PROGRESS_EVERY: u64 = 1_000_000;
 
 fn main() -> Result<(), Box<dyn Error>> {
     let argv: Vec<String> = env::args().skip(1).collect();
+    run(&argv, &mut io::stdout())
+}
 
+/// Every option the command line takes
+fn options() -> Options {
     let mut opts = Options::new();
-    opts.reqopt("s", "set", "Number of set index bits", "");
-    opts.reqopt("E", "lines", "Number of lines per set", "");
-    opts.reqopt("b", "block", "Number of block bits", "");
-    opts.reqopt("f", "file", "File containing instruction accesses", "");
+    // Required unless only reading back a stats file
+    opts.optopt("s", "set", "Number of set index bits", "");
+    opts.optopt("E", "lines", "Number of lines per set", "");
+    opts.optopt("b", "block", "Number of block bits", "");
+    opts.optopt("", "block-bytes", "Block size in bytes, a power of two, in place of -b", "");
+    opts.optopt("", "cache-size", "Cache size in bytes, in place of -s", "");
+    opts.optmulti("f", "file", "File containing instruction accesses, decompressed if it ends in .gz; repeat to simulate several", "");
+    opts.optflag("", "shared-cache", "Simulate several -f files in one cache, warm from those before");
+    opts.optflag("", "show-config", "Print the cache's geometry and exit, without a trace");
+    opts.optflag("v", "verbose", "Print each access and its outcome");
+    opts.optopt("", "sets", "Number of sets, not necessarily a power of two, in place of -s", "");
+    opts.optopt("", "hit-time", "Cycles taken by a cache hit", "");
+    opts.optopt("", "miss-penalty", "Cycles taken to fill a block on a miss", "");
+    opts.optflag("", "critical-word-first", "Deliver the missed word before the rest of the block");
+    opts.optflag("", "heatmap", "Print a heatmap of per-set access counts");
+    opts.optflag("", "set-miss-rates", "Print the accesses, misses and miss rate of each set");
+    opts.optopt("", "page-table", "File mapping virtual to physical page numbers", "");
+    opts.optopt("", "page-bits", "Number of page offset bits", "");
+    opts.optflag("", "strict-page-table", "Treat accesses to unmapped pages as errors");
+    opts.optflag("", "vipt", "Index sets by virtual address and tag by physical address");
+    opts.optopt("", "policy", "Replacement policy: lru, mru, fifo, lfu, random, opt or dip", "");
+    opts.optopt("", "seed", "Seed for the random replacement policy", "");
+    opts.optopt("", "victim-size", "Number of entries in a victim cache", "");
+    opts.optopt("", "victim-policy", "Replacement policy for the victim cache", "");
+    opts.optopt("", "store-buffer", "Number of entries in a store buffer", "");
+    opts.optopt("", "drain-policy", "When the store buffer drains: full or eager", "");
+    opts.optflag("", "deterministic", "Make the run independent of entropy");
+    opts.optopt("", "insertion", "Insertion policy: lru, lip or bip", "");
+    opts.optopt("", "top-evicted", "Print the N most frequently evicted tags", "");
+    opts.optflag("", "auto-addr-bits", "Use 32-bit addresses when every access fits in 32 bits");
+    opts.optopt("", "addr-bits", "Width of an address in bits (default 64)", "");
+    opts.optopt("", "trace-set", "Log every event affecting one set", "");
+    opts.optopt("", "trace-format", "Format of the trace: valgrind (default), dinero or pin", "");
+    opts.optflag("", "dump-parse", "Print how each trace line parses before simulating");
+    opts.optflag("", "collect-errors", "Report every malformed trace line, not just the first");
+    opts.optopt("", "access-log", "File to log every access and its latency to", "");
+    opts.optopt("", "csv", "CSV file to write every access and its outcome to", "");
+    opts.optflag("", "no-write-allocate", "Write missed stores to memory without filling a line");
+    opts.optopt("", "store-miss-counts", "Whether no-write-allocate store misses count as misses: true or false", "");
+    opts.optopt("", "dram-row-bits", "Model a DRAM row buffer with 2^N-byte rows behind the cache", "");
+    opts.optopt("", "flamegraph", "File to write misses per instruction to, in folded-stack format", "");
+    opts.optopt("", "global-assoc", "Also simulate a fully-associative LRU cache of N lines", "");
+    opts.optflag("", "min-assoc-for-zero-conflict", "Report the fewest lines per set that take no conflict misses");
+    opts.optflag("", "per-thread-stats", "Report hits and misses for each thread of the trace");
+    opts.optflag("", "per-op-stats", "Report hits and misses of loads and of stores");
+    opts.optflag("", "progress", "Report the accesses simulated on stderr as the run goes");
+    opts.optflag("", "classify-misses", "Report compulsory misses apart from conflict and capacity misses");
+    opts.optflag("", "replacement-state-dump", "Print the replacement policy's state of each set after the run");
+    opts.optflag("", "dump", "Print the tag and recency stamp of every valid line after the run");
+    opts.optopt("", "stats-binary", "File to append a binary record of the run's statistics to", "");
+    opts.optflagopt("", "selftest", "Check the simulator against the cases of a manifest, or the bundled ones", "");
+    opts.optopt("", "read-stats", "Print the records of a binary stats file as a table", "");
+    opts.optflag("", "warn-footprint", "Warn when the whole trace fits in the cache");
+    opts.optopt("", "op-weight", "Evictions survived by load and store lines, e.g. load:1,store:2", "");
+    opts.optflag("", "lightweight", "Stream the trace and keep only the core counters, in memory bounded by the cache size");
+    opts.optflag("", "emit-command", "Print a command line, with every default resolved, that reproduces the run");
+    opts.optopt("", "replay-addresses", "Access log whose address stream to simulate in place of -f", "");
+    opts.optflag("", "stride", "Report the most common stride between consecutive accesses");
+    opts.optopt("", "flush-every", "Invalidate the whole cache every N accesses", "");
+    opts.optflag("", "skip-null", "Drop accesses to address 0");
+    opts.optopt("", "snapshot-interval", "Report hits, misses and evictions of every N accesses", "");
+    opts.optopt("", "snapshot-file", "File to write the --snapshot-interval series to as CSV", "");
+    opts.optflag("", "phases", "Report stats for each phase begun by a `# PHASE <name>` line");
+    opts.optopt("", "repeat", "Simulate the trace this many times over, keeping the cache warm", "");
+    opts.optopt("", "repeat-report", "Statistics to report for --repeat: all (default) or last", "");
+    opts.optopt("", "precision", "Decimal places in reported rates", "");
+    opts.optflag("", "reuse-per-block", "Print the average and maximum reuse distance of each block");
+    opts.optopt("", "hash", "Set index hash: none (default) or xor-fold", "");
+    opts.optopt("", "trace-window", "Simulate only the accesses start:end, by index", "");
+    opts.optflag("", "warm-before", "Warm the cache with the accesses before the trace window");
+    opts.optopt("", "max-accesses", "Simulate only the first N accesses; 0, the default, for all", "");
+    opts.optopt("", "warmup", "Number of leading accesses that warm the cache without being counted", "");
+    opts.optopt("", "warm-trace", "Trace to warm the cache with, uncounted, before the -f trace", "");
+    opts.optopt("", "instruction-count", "Instructions executed, for MPKI, in place of counting I lines", "");
+    opts.optopt("", "mem-budget", "Stream the trace with --lightweight if a full run would need more megabytes", "");
+    opts.optflag("", "exclude-cold-sets", "Don't count the first miss in each set");
+    opts.optopt("", "columnar", "File to write every access and its outcome to, column by column", "");
+    opts.optflag("", "icache", "Simulate instruction fetches in a cache of their own");
+    opts.optopt("", "icache-s", "Number of set index bits of the instruction cache", "");
+    opts.optopt("", "icache-E", "Number of lines per set of the instruction cache", "");
+    opts.optopt("", "icache-b", "Number of block offset bits of the instruction cache", "");
+    opts.optopt("", "l2-s", "Number of set index bits of an L2 behind the cache", "");
+    opts.optopt("", "l2-E", "Number of lines per set of the L2", "");
+    opts.optopt("", "l2-b", "Number of block offset bits of the L2", "");
+    opts.optopt("", "l2-policy", "Inclusion of L1 in the L2: non-inclusive (default), inclusive or exclusive", "");
+    opts.optflag("", "compare-policies", "Simulate the trace under LRU, FIFO and random replacement at once");
+    opts.optflag("", "split-lw", "Send loads and stores to two separate caches of the given shape");
+    opts.optopt("", "format", "Summary format: text (default) or json", "");
+    opts.optflag("", "canonical", "Print every statistic as sorted key: value lines, for golden files");
+    opts.optflag("", "badge", "End with a one-line summary for commit messages");
+    opts.optflag("", "byte-valid", "Keep a valid bit per byte; store misses allocate without fetching");
+    opts.optopt("", "prefetch", "Prefetcher: next-line fetches the block after each missed one", "");
+    opts.optflag("", "write-back", "Count evictions of dirty lines, which a write-back cache writes back");
+    opts.optopt("", "wb-buffer", "Entries in the write-back buffer for dirty evictions", "");
+    opts.optopt("", "wb-drain-rate", "Write-back buffer entries drained per access", "");
+    opts.optopt("", "lock", "File of addresses whose lines are locked once filled", "");
+    opts.optopt("", "energy-hit", "Energy of a hit, for estimating the run's energy", "");
+    opts.optopt("", "energy-miss", "Energy of a miss's lookup", "");
+    opts.optopt("", "energy-writeback", "Energy of writing back a dirty line", "");
+    opts.optopt("", "energy-fill", "Energy of filling a line", "");
+    opts.optopt("", "read-ports", "Number of cache read ports", "");
+    opts.optopt("", "write-ports", "Number of cache write ports", "");
+    opts.optopt("", "issue-width", "Accesses issued to the cache per cycle", "");
+    opts.optflag("", "unified", "Count instruction fetches, as in a unified cache");
+    opts.optflag("", "split-accesses", "Look up every block an access spans, using its size");
+    opts.optflag("", "coalesce-instructions", "Merge consecutive instruction fetches from one block");
+    opts
+}
 
-    let matches = match opts.parse(&argv) {
+/// Simulate the run described by the command-line arguments `argv`, writing its report to `out`
+fn run(argv: &[String], out: &mut dyn Write) -> Result<(), Box<dyn Error>> {
+    let opts = options();
+    let matches = match opts.parse(argv) {
         Ok(m) => m,
         Err(_) => {
-            print_usage();
+            print_usage(out)?;
             return Ok(());
         }
     };
+    if matches.opt_present("block-bytes") || matches.opt_present("cache-size") {
+        return run(&in_bits(argv, &matches)?, out);
+    }
+    if matches.opt_present("selftest") {
+        return run_selftest(matches.opt_str("selftest"), out);
+    }
+    if let Some(path) = matches.opt_str("read-stats") {
+        let records = stats_file::read_all(&fs::read(path)?)?;
+        write!(out, "{}", stats_file::table(&records))?;
+        return Ok(());
+    }
+    let modulo_sets = matches.opt_get::<u64>("sets")?;
+    let replay = matches.opt_str("replay-addresses");
+    if !["E", "b"].iter().all(|name| matches.opt_present(name)) || !(matches.opt_present("s") || modulo_sets.is_some()) {
+        print_usage(out)?;
+        return Ok(());
+    }
+    let geometry_options = ["s", "E", "b", "l2-s", "l2-E", "l2-b", "icache-s", "icache-E", "icache-b"];
+    for name in geometry_options.iter().filter(|name| matches.opt_present(name)) {
+        if let Err(complaint) = geometry_value(name, &matches.opt_str(name).unwrap()) {
+            print_usage(out)?;
+            return Err(complaint.into());
+        }
+    }
+    // The instruction cache takes the data cache's shape unless given its own
+    let or_data = |name: &'static str, data: &'static str| if matches.opt_present(name) { name } else { data };
+    let mut geometries = vec![(["s", "E", "b"], modulo_sets)];
+    if ["l2-s", "l2-E", "l2-b"].iter().all(|name| matches.opt_present(name)) {
+        geometries.push((["l2-s", "l2-E", "l2-b"], None));
+    }
+    if matches.opt_present("icache") {
+        geometries.push(([or_data("icache-s", "s"), or_data("icache-E", "E"), or_data("icache-b", "b")], None));
+    }
+    for (names, num_sets) in geometries {
+        if let Err(complaint) = check_geometry_size(&matches, names, num_sets) {
+            print_usage(out)?;
+            return Err(complaint);
+        }
+    }
+    if matches.opt_present("show-config") {
+        return show_config(&matches, out);
+    }
+    // Values taken for options left off the command line, for --emit-command
+    let mut defaults: Vec<(&str, String)> = vec![];
+    let deterministic = matches.opt_present("deterministic");
+    let unified = matches.opt_present("unified");
+    if matches.opt_count("f") > 1 {
+        return run_files(&matches, unified, out);
+    }
+    if matches.opt_present("shared-cache") {
+        return Err("Error: --shared-cache needs more than one -f".into());
+    }
+    if let Some(megabytes) = matches.opt_get::<f64>("mem-budget")? {
+        if choose_mode(&matches, megabytes)? == RunMode::Lightweight {
+            eprintln!("note: --mem-budget: streaming the trace with --lightweight to stay within {} MB", megabytes);
+            return run_lightweight(argv, &matches, out);
+        }
+    }
+    if matches.opt_present("lightweight") {
+        return run_lightweight(argv, &matches, out);
+    }
+    if ["l2-s", "l2-E", "l2-b", "l2-policy"].iter().any(|name| matches.opt_present(name)) {
+        return run_hierarchy(&matches, unified, out);
+    }
+    if matches.opt_present("icache") {
+        return run_icache(&matches, out);
+    }
+    if matches.opt_present("split-lw") {
+        return run_split(&matches, unified, out);
+    }
+    if matches.opt_present("compare-policies") {
+        return run_compare(&matches, unified, out);
+    }
+    if matches.opt_present("coalesce-instructions") && !unified {
+        return Err("Error: --coalesce-instructions requires --unified".into());
+    }
+
+    let (mut traces, phases, fetches) = if let Some(path) = replay {
+        if matches.opt_present("f") {
+            return Err("Error: -f and --replay-addresses can't both be given".into());
+        }
+        let traces = access_log::parse_addresses(&fs::read_to_string(path)?)?;
+        let fetches = traces.iter().filter(|trace| trace.operation == valgrind::Operation::Instruction).count() as u64;
+        (traces, vec![], fetches)
+    } else {
+        read_trace(&matches, unified, out)?
+    };
+    let mut instructions = matches.opt_get::<u64>("instruction-count")?.unwrap_or(fetches);
+    let repeat = matches.opt_get::<usize>("repeat")?;
+    let report_last = match matches.opt_str("repeat-report").as_deref() {
+        None | Some("all") => false,
+        Some("last") if repeat.is_some() => true,
+        Some("last") => return Err("Error: --repeat-report needs --repeat".into()),
+        Some(other) => return Err(format!("Error: Unknown --repeat-report '{}'", other).into()),
+    };
+    if repeat == Some(0) {
+        return Err("Error: --repeat must be at least 1".into());
+    }
+    let report_phases = matches.opt_present("phases");
+    if report_phases && repeat.is_some() {
+        return Err("Error: --phases can't be combined with --repeat".into());
+    }
+    if report_phases {
+        // Both drop accesses, leaving the phase boundaries pointing at the wrong ones
+        if let Some(name) = ["skip-null", "coalesce-instructions", "split-accesses"].iter().find(|name| matches.opt_present(name)) {
+            return Err(format!("Error: --phases can't be combined with --{}", name).into());
+        }
+    }
+
+    let max_accesses = matches.opt_get_default("max-accesses", 0_usize)?;
+    if max_accesses > 0 {
+        if let Some(name) = ["trace-window", "phases"].iter().find(|name| matches.opt_present(name)) {
+            return Err(format!("Error: --max-accesses can't be combined with --{}", name).into());
+        }
+        if max_accesses < traces.len() {
+            traces.truncate(max_accesses);
+            // The trace's I lines run on past the cap
+            if !matches.opt_present("instruction-count") {
+                instructions = 0;
+            }
+        }
+    }
+
+    // Accesses ahead of the window are kept only to warm the cache
+    let mut warm_accesses = 0;
+    if let Some(window) = matches.opt_str("trace-window") {
+        if report_phases {
+            return Err("Error: --phases can't be combined with --trace-window".into());
+        }
+        let (start, end) = parse_window(&window, traces.len())?;
+        // The trace's I lines can't be matched up with accesses in the window
+        if !matches.opt_present("instruction-count") {
+            instructions = 0;
+        }
+        traces.truncate(end);
+        if matches.opt_present("warm-before") {
+            warm_accesses = start;
+        } else {
+            traces.drain(..start);
+        }
+    } else if matches.opt_present("warm-before") {
+        return Err("Error: --warm-before requires --trace-window".into());
+    }
+    if let Some(count) = matches.opt_get::<usize>("warmup")? {
+        if matches.opt_present("trace-window") {
+            return Err("Error: --warmup can't be combined with --trace-window".into());
+        }
+        if report_phases {
+            return Err("Error: --phases can't be combined with --warmup".into());
+        }
+        if !matches.opt_present("instruction-count") {
+            instructions = 0;
+        }
+        // A warm-up longer than the trace leaves nothing to count
+        warm_accesses = count.min(traces.len());
+    }
+
+    let mut warm_traces = match matches.opt_str("warm-trace") {
+        // Its addresses would need translating ahead of the trace's own
+        Some(_) if matches.opt_present("page-table") => return Err("Error: --warm-trace can't be combined with --page-table".into()),
+        Some(path) => parse_trace(&read_trace_file(&path)?, trace_format(&matches)?, unified)?.0,
+        None => vec![],
+    };
+
+    if matches.opt_present("skip-null") {
+        valgrind::skip_null(&mut traces);
+        valgrind::skip_null(&mut warm_traces);
+    }
+
+    let mut virtual_index = None;
+    if let Some(path) = matches.opt_str("page-table") {
+        let page_bits = matches.opt_get_default("page-bits", 12_u8)?;
+        resolve(&matches, &mut defaults, "page-bits", page_bits);
+        let table = translate::PageTable::parse(&fs::read_to_string(path)?, page_bits, matches.opt_present("strict-page-table"))?;
+        if matches.opt_present("vipt") {
+            // Keep the virtual addresses; the cache translates them for its tags
+            for trace in &traces {
+                table.translate(trace.address)?;
+            }
+            virtual_index = Some(table);
+        } else {
+            table.translate_all(&mut traces)?;
+        }
+    } else if matches.opt_present("vipt") {
+        return Err("Error: --vipt requires --page-table".into());
+    }
+
+    // With --sets, no part of the address is taken as set index bits
+    let set_bits = match modulo_sets {
+        Some(_) => 0,
+        None => bits_value("s", &matches.opt_str("s").unwrap())?,
+    };
+    let lines = geometry_value("E", &matches.opt_str("E").unwrap())?;
+    let block_bits = bits_value("b", &matches.opt_str("b").unwrap())?;
+    if modulo_sets.is_some() {
+        if let Some(name) = ["s", "vipt", "stats-binary", "badge", "min-assoc-for-zero-conflict"].iter().find(|name| matches.opt_present(name)) {
+            return Err(format!("Error: -{} can't be combined with --sets", name).into());
+        }
+        if matches.opt_str("policy").as_deref() == Some("opt") {
+            return Err("Error: --policy opt can't be combined with --sets".into());
+        }
+    }
+
+    if matches.opt_present("coalesce-instructions") {
+        traces = valgrind::coalesce_instructions(traces, block_bits);
+        warm_traces = valgrind::coalesce_instructions(warm_traces, block_bits);
+    }
+    if matches.opt_present("split-accesses") {
+        // Split the accesses that warm the cache apart, to keep count of them
+        let window = valgrind::split_at_blocks(traces.split_off(warm_accesses), block_bits);
+        traces = valgrind::split_at_blocks(traces, block_bits);
+        warm_accesses = traces.len();
+        traces.extend(window);
+        warm_traces = valgrind::split_at_blocks(warm_traces, block_bits);
+    }
+
+    let mut cache = match modulo_sets {
+        Some(num_sets) if num_sets > 0 => cache::Cache::with_sets(num_sets, lines, block_bits)?,
+        Some(_) => return Err("Error: --sets must be at least 1".into()),
+        None => cache::Cache::new(set_bits, lines, block_bits)?,
+    };
+    if let Some(addr_bits) = matches.opt_get::<u8>("addr-bits")? {
+        cache.set_addr_bits(addr_bits)?;
+        if matches.opt_present("auto-addr-bits") {
+            return Err("Error: --addr-bits can't be combined with --auto-addr-bits".into());
+        }
+        // Reject rather than silently drop the bits the architecture doesn't have
+        if let Some(trace) = traces.iter().chain(&warm_traces).find(|trace| trace.address.checked_shr(addr_bits as u32).unwrap_or(0) != 0) {
+            return Err(format!("Error: Address {:#x} doesn't fit in {} bits", trace.address, addr_bits).into());
+        }
+    } else if valgrind::fits_in_32_bits(&traces) && set_bits + block_bits <= 32 {
+        if matches.opt_present("auto-addr-bits") {
+            cache.set_addr_bits(32)?;
+        } else if !traces.is_empty() {
+            eprintln!("note: every address fits in 32 bits; --auto-addr-bits sizes tags for a 32-bit address space");
+        }
+    }
+    match matches.opt_str("hash").as_deref() {
+        None | Some("none") => {}
+        Some("xor-fold") => {
+            // Each rebuilds blocks from a tag and a set index taken straight from the address
+            if let Some(name) = ["sets", "vipt"].iter().find(|name| matches.opt_present(name)) {
+                return Err(format!("Error: --hash xor-fold can't be combined with --{}", name).into());
+            }
+            if matches.opt_str("policy").as_deref() == Some("opt") {
+                return Err("Error: --hash xor-fold can't be combined with --policy opt".into());
+            }
+            cache.set_xor_fold();
+        }
+        Some(other) => return Err(format!("Error: Unknown hash '{}'", other).into()),
+    }
+    if let Some(table) = virtual_index {
+        cache.set_virtual_index(table);
+    }
+
+    let seed = resolve_seed(&matches)?;
+    resolve(&matches, &mut defaults, "seed", seed);
+    resolve(&matches, &mut defaults, "policy", "lru");
+    match matches.opt_str("policy").as_deref() {
+        Some("opt") if repeat.is_some() => return Err("Error: --policy opt can't be combined with --repeat".into()),
+        Some("opt") if matches.opt_present("warm-trace") => return Err("Error: --policy opt can't be combined with --warm-trace".into()),
+        Some("opt") if report_phases && matches.opt_present("store-buffer") => return Err("Error: --policy opt can't be combined with --phases and --store-buffer".into()),
+        // Built below, once the store buffer that reorders the cache's accesses is known
+        Some("opt") => {}
+        // LRU replacement, with set dueling choosing between LRU and BIP insertion
+        Some("dip") if matches.opt_present("insertion") => return Err("Error: --policy dip chooses its own insertion policy".into()),
+        Some("dip") => {
+            let num_sets = modulo_sets.unwrap_or(1 << set_bits);
+            cache.set_insertion(insertion::Insertion::Dip(insertion::Dip::new(num_sets, seed)));
+        }
+        name => cache.set_policy(parse_policy(name, seed)?),
+    }
+
+    if let Some(spec) = matches.opt_str("op-weight") {
+        cache.set_op_weights(cache::OpWeights::parse(&spec)?);
+    }
+
+    if let Some(name) = matches.opt_str("insertion") {
+        match insertion::Insertion::from_name(&name, seed) {
+            Some(insertion) => cache.set_insertion(insertion),
+            None => return Err(format!("Error: Unknown insertion policy \"{}\"", name).into()),
+        }
+    }
+
+    if let Some(entries) = matches.opt_get::<usize>("victim-size")? {
+        let victim_policy = parse_policy(matches.opt_str("victim-policy").as_deref(), seed)?;
+        resolve(&matches, &mut defaults, "victim-policy", "lru");
+        cache.set_victim_cache(victim::VictimCache::new(entries, victim_policy));
+    }
+
+    if let Some(entries) = matches.opt_get::<usize>("store-buffer")? {
+        let drain = match matches.opt_str("drain-policy").as_deref() {
+            None | Some("full") => store_buffer::DrainPolicy::Full,
+            Some("eager") => store_buffer::DrainPolicy::Eager,
+            Some(other) => return Err(format!("Error: Unknown drain policy \"{}\"", other).into()),
+        };
+        resolve(&matches, &mut defaults, "drain-policy", "full");
+        cache.set_store_buffer(store_buffer::StoreBuffer::new(entries, drain, block_bits));
+    }
+    if matches.opt_str("policy").as_deref() == Some("opt") {
+        // OPT's future is the accesses that reach the cache; the buffer drains at the end of the warm-up
+        let references = match cache.store_buffer() {
+            Some(buffer) => [buffer.references(&traces[..warm_accesses]), buffer.references(&traces[warm_accesses..])].concat(),
+            None => traces.clone(),
+        };
+        cache.set_policy(Box::new(policy::Opt::new(&references, set_bits, block_bits)));
+    }
+
+    let critical_word_first = matches.opt_present("critical-word-first");
+    let access_log = matches.opt_str("access-log");
+    // Cycles of a hit and of a miss's fill, when given, for the average memory access time
+    let mut timing = None;
+    if critical_word_first || access_log.is_some() || matches.opt_present("hit-time") || matches.opt_present("miss-penalty") {
+        let hit_time = matches.opt_get_default("hit-time", 1_u64)?;
+        let miss_penalty = matches.opt_get_default("miss-penalty", 100_u64)?;
+        resolve(&matches, &mut defaults, "hit-time", hit_time);
+        resolve(&matches, &mut defaults, "miss-penalty", miss_penalty);
+        if matches.opt_present("hit-time") || matches.opt_present("miss-penalty") {
+            timing = Some((hit_time, miss_penalty));
+        }
+        cache.set_latency(latency::LatencyModel::new(hit_time, miss_penalty, critical_word_first, block_bits));
+    }
+
+    let store_miss_counts = matches.opt_get::<bool>("store-miss-counts")?;
+    if matches.opt_present("no-write-allocate") {
+        cache.set_no_write_allocate(store_miss_counts.unwrap_or(true));
+    } else if store_miss_counts.is_some() {
+        return Err("Error: --store-miss-counts requires --no-write-allocate".into());
+    }
+
+    if let Some(row_bits) = matches.opt_get::<u8>("dram-row-bits")? {
+        cache.set_row_buffer(dram::RowBuffer::new(row_bits));
+    }
+
+    if let Some(path) = access_log {
+        if matches.opt_present("csv") {
+            return Err("Error: --access-log and --csv can't both be given".into());
+        }
+        cache.set_access_log(access_log::AccessLog::new(Box::new(BufWriter::new(File::create(path)?))));
+    } else if let Some(path) = matches.opt_str("csv") {
+        cache.set_access_log(access_log::AccessLog::csv(Box::new(BufWriter::new(File::create(path)?))));
+    }
+    let columnar = matches.opt_str("columnar");
+    if columnar.is_some() {
+        cache.set_columns(columnar::Columns::default());
+    }
+
+    if let Some(index) = matches.opt_get::<u64>("trace-set")? {
+        cache.trace_set(index);
+    }
+
+    let top_evicted = matches.opt_get::<usize>("top-evicted")?;
+    if top_evicted.is_some() {
+        cache.track_evicted_tags();
+    }
+
+    if matches.opt_present("exclude-cold-sets") {
+        cache.set_exclude_cold_sets();
+    }
+    if matches.opt_present("byte-valid") {
+        cache.set_byte_valid();
+    }
+
+    if matches.opt_present("write-back") {
+        cache.track_dirty_evictions();
+    }
+    match matches.opt_str("prefetch").as_deref() {
+        None => {}
+        // Prefetches would stall the blocking pipeline, and are located by physical address
+        Some(_) if cache.latency().is_some() => return Err("Error: --prefetch can't be combined with a latency model".into()),
+        Some(_) if matches.opt_present("vipt") => return Err("Error: --prefetch can't be combined with --vipt".into()),
+        Some(_) if matches.opt_str("policy").as_deref() == Some("opt") => return Err("Error: --policy opt can't be combined with --prefetch".into()),
+        Some("next-line") => cache.set_next_line_prefetch(),
+        Some(other) => return Err(format!("Error: Unknown prefetcher '{}'", other).into()),
+    }
+    if let Some(depth) = matches.opt_get::<usize>("wb-buffer")? {
+        let drain_rate = matches.opt_get_default("wb-drain-rate", 1.0_f64)?;
+        if depth == 0 || drain_rate.is_nan() || drain_rate < 0.0 {
+            return Err("Error: --wb-buffer must be at least 1 and --wb-drain-rate not negative".into());
+        }
+        cache.set_write_back_buffer(write_back::WriteBackBuffer::new(depth, drain_rate));
+    } else if matches.opt_present("wb-drain-rate") {
+        return Err("Error: --wb-drain-rate requires --wb-buffer".into());
+    }
+
+    if let Some(path) = matches.opt_str("lock") {
+        for address in parse_lock_file(&fs::read_to_string(path)?)? {
+            cache.lock(address);
+        }
+    }
+
+    let read_ports = matches.opt_get::<u32>("read-ports")?;
+    let write_ports = matches.opt_get::<u32>("write-ports")?;
+    if read_ports.is_some() || write_ports.is_some() || matches.opt_present("issue-width") {
+        let (read_ports, write_ports) = (read_ports.unwrap_or(1), write_ports.unwrap_or(1));
+        let issue_width = matches.opt_get_default("issue-width", read_ports.max(write_ports))?;
+        if read_ports == 0 || write_ports == 0 || issue_width == 0 {
+            return Err("Error: Port counts and issue width must be at least 1".into());
+        }
+        cache.set_ports(ports::PortModel::new(read_ports, write_ports, issue_width));
+    }
 
-    let file_contents: String = fs::read_to_string(matches.opt_str("f").unwrap())?;
-    let traces = valgrind::parse(&file_contents)?;
+    let energy_options = ["energy-hit", "energy-miss", "energy-writeback", "energy-fill"];
+    if energy_options.iter().any(|name| matches.opt_present(name)) {
+        cache.set_energy(energy::EnergyModel::new(energy::EnergyCosts {
+            hit: matches.opt_get_default("energy-hit", 0.0)?,
+            miss: matches.opt_get_default("energy-miss", 0.0)?,
+            writeback: matches.opt_get_default("energy-writeback", 0.0)?,
+            fill: matches.opt_get_default("energy-fill", 0.0)?,
+        }));
+    }
 
-    let set_bits = matches.opt_str("s").unwrap().parse::<u8>()?;
-    let lines = matches.opt_str("E").unwrap().parse::<u8>()?;
-    let block_bits = matches.opt_str("b").unwrap().parse::<u8>()?;
+    let precision = matches.opt_get_default("precision", DEFAULT_PRECISION)?;
+    let flush_every = matches.opt_get::<u64>("flush-every")?;
+    match flush_every {
+        Some(0) => return Err("Error: --flush-every must be at least 1".into()),
+        Some(period) => cache.set_flush_every(period),
+        None => {}
+    }
 
-    let mut cache = cache::Cache::new(set_bits, lines, block_bits);
-    cache.operate_cache(traces);
+    if matches.opt_present("per-thread-stats") {
+        cache.track_threads();
+    }
+    if matches.opt_present("per-op-stats") {
+        cache.track_operations();
+    }
+    if matches.opt_present("classify-misses") {
+        cache.classify_misses();
+    }
 
-    dbg!(cache.stats);
+    if matches.opt_present("progress") {
+        cache.set_progress(match deterministic {
+            true => progress::Progress::untimed(PROGRESS_EVERY),
+            false => progress::Progress::new(PROGRESS_EVERY),
+        });
+    }
+    let snapshot_file = set_snapshot_interval(&matches, &mut cache)?;
+
+    let flamegraph = matches.opt_str("flamegraph");
+    if flamegraph.is_some() {
+        cache.track_miss_pcs();
+    }
+
+    if !warm_traces.is_empty() {
+        cache.warm(warm_traces);
+    }
+    if warm_accesses > 0 {
+        let window = traces.split_off(warm_accesses);
+        cache.warm(std::mem::replace(&mut traces, window));
+    }
+    if matches.opt_present("verbose") {
+        // Buffered stores reach the cache after later accesses, out of line order
+        if matches.opt_present("store-buffer") {
+            return Err("Error: --verbose can't be combined with --store-buffer".into());
+        }
+        cache.set_verbose();
+    }
+
+    let global = match matches.opt_get::<usize>("global-assoc")? {
+        Some(capacity) => {
+            let mut global = fully_associative::FullyAssociative::new(capacity, block_bits);
+            global.operate(&traces);
+            Some(global)
+        }
+        None => None,
+    };
+    let min_assoc = match matches.opt_present("min-assoc-for-zero-conflict") {
+        true => Some(fully_associative::min_assoc_for_zero_conflict(&traces, set_bits, block_bits)),
+        false => None,
+    };
+
+    let stride = match matches.opt_present("stride") {
+        true => Some(valgrind::dominant_stride(&traces)),
+        false => None,
+    };
+
+    let reuse = match matches.opt_present("reuse-per-block") {
+        true => Some(reuse::reuse_per_block(&traces, block_bits)),
+        false => None,
+    };
+
+    let distinct_blocks = match matches.opt_present("warn-footprint") {
+        true => Some(valgrind::distinct_blocks(&traces, block_bits)),
+        false => None,
+    };
+
+    let tag_warning = cache.tag_warning(&traces);
+    let mut stats = cache.stats;
+    let mut phase_stats = vec![];
+    if report_phases {
+        phase_stats = cache.operate_phases(traces, &phases);
+    } else if let Some(times) = repeat {
+        let shares = cache.operate_repeated(traces, times);
+        if report_last {
+            stats = shares[times - 1];
+        } else {
+            instructions *= times as u64;
+        }
+    } else {
+        cache.operate_cache(traces);
+    }
+    if !report_last {
+        stats = cache.stats;
+    }
+    if let Some(progress) = cache.progress() {
+        progress.finish();
+    }
+    if let Some(warning) = tag_warning {
+        eprintln!("{}", warning);
+    }
+    if let Some(warning) = distinct_blocks.and_then(|blocks| cache.footprint_warning(blocks)) {
+        eprintln!("{}", warning);
+    }
+    if let Some(path) = flamegraph {
+        fs::write(path, cache.folded_misses())?;
+    }
+    if let Some(log) = cache.take_access_log() {
+        log.finish()?;
+    }
+    if let (Some(path), Some(columns)) = (columnar, cache.take_columns()) {
+        fs::write(path, columns.encode())?;
+    }
+
+    let format = parse_format(&matches)?;
+    if let Some(verbose) = cache.take_verbose() {
+        if format != Format::Text {
+            return Err("Error: --verbose can't be combined with --format".into());
+        }
+        write!(out, "{}", verbose)?;
+    }
+    if matches.opt_present("canonical") {
+        if format != Format::Text {
+            return Err("Error: --canonical can't be combined with --format".into());
+        }
+        let amat = timing.map(|(hit_time, miss_penalty)| stats.amat(hit_time, miss_penalty));
+        return write_canonical(out, &cache, &stats, global.as_ref(), amat, instructions, precision);
+    }
+    write_summary(out, &stats, instructions, precision, format)?;
+    if let (Some((hit_time, miss_penalty)), Format::Text) = (timing, format) {
+        writeln!(out, "accesses: {}", stats.accesses())?;
+        writeln!(out, "amat: {} cycles", cache::format_rate(stats.amat(hit_time, miss_penalty), precision))?;
+    }
+    if let Some(energy) = cache.energy() {
+        writeln!(out, "energy: {}", cache::format_rate(energy.total(), precision))?;
+    }
+    for (name, stats) in &phase_stats {
+        let name = if name.is_empty() { "(before the first marker)" } else { name };
+        writeln!(out, "phase {}: {} hits, {} misses, {} evictions", name, stats.hits(), stats.misses(), stats.evictions())?;
+    }
+    if matches.opt_present("snapshot-interval") {
+        write_snapshots(out, snapshot_file.as_deref(), &cache.snapshots(), precision)?;
+    }
+    if let Some(path) = matches.opt_str("stats-binary") {
+        // The record's fields are a byte each
+        let lines = u8::try_from(lines).map_err(|_| "Error: --stats-binary records at most 255 lines per set")?;
+        stats_file::append(&path, &stats_file::StatsRecord {
+            set_bits,
+            lines,
+            block_bits,
+            hit: stats.hits(),
+            miss: stats.misses(),
+            eviction: stats.evictions(),
+        })?;
+    }
+    if let Some(model) = cache.latency() {
+        writeln!(out, "average miss latency: {:.2} cycles", model.average_miss_latency())?;
+    }
+    if matches.opt_present("replacement-state-dump") {
+        write!(out, "{}", cache.replacement_state())?;
+    }
+    if matches.opt_present("dump") {
+        write!(out, "{}", cache.dump())?;
+    }
+    for (thread, stats) in cache.thread_stats() {
+        writeln!(out, "thread {}: {} hits, {} misses", thread, stats.hit, stats.miss)?;
+    }
+    if let Some(operations) = cache.operation_stats() {
+        writeln!(out, "load: {} hits, {} misses", operations.load.hit, operations.load.miss)?;
+        writeln!(out, "store: {} hits, {} misses", operations.store.hit, operations.store.miss)?;
+    }
+    if flush_every.is_some() {
+        writeln!(out, "flushes: {}", cache.flushes())?;
+    }
+    if matches.opt_present("lock") {
+        writeln!(out, "bypasses: {}", cache.bypasses())?;
+    }
+    if matches.opt_present("write-back") {
+        writeln!(out, "dirty evictions: {}", stats.dirty_evictions())?;
+    }
+    if cache.classifies_misses() {
+        writeln!(out, "compulsory misses: {}, conflict and capacity misses: {}", stats.compulsory_misses(), stats.conflict_capacity_misses())?;
+    }
+    if matches.opt_present("prefetch") {
+        writeln!(out, "prefetches: {}, prefetch hits: {}", stats.prefetches(), stats.prefetch_hits())?;
+    }
+    if let Some(buffer) = cache.write_back_buffer() {
+        writeln!(out, "write-backs: {}, wb_stalls: {}", buffer.writebacks, buffer.stalls)?;
+    }
+    if matches.opt_present("badge") {
+        writeln!(out, "{}", csim::Geometry::new(set_bits, lines, block_bits).badge(&stats))?;
+    }
+    if let Some(ports) = cache.ports() {
+        writeln!(out, "{:#?}", ports.stats)?;
+    }
+    match stride {
+        Some(Some((stride, count, deltas))) => writeln!(out, "dominant stride: {:+} bytes ({} of {} strides)", stride, count, deltas)?,
+        Some(None) => writeln!(out, "dominant stride: none (no two consecutive accesses differ)")?,
+        None => {}
+    }
+    if let Some(blocks) = reuse {
+        writeln!(out, "reuse distance per block:")?;
+        for (block, reuse) in blocks {
+            match reuse.average() {
+                Some(average) => writeln!(out, "    {:#x}: average {}, max {}", block << block_bits, cache::format_rate(average, precision), reuse.max_distance)?,
+                None => writeln!(out, "    {:#x}: never reused", block << block_bits)?,
+            }
+        }
+    }
+    if let Some(global) = global {
+        writeln!(out, "fully-associative, {} lines: {:#?}", global.capacity(), global.stats)?;
+    }
+    match min_assoc {
+        Some(Some(lines)) => writeln!(out, "associativity with no conflict misses: {}", lines)?,
+        Some(None) => writeln!(out, "conflict misses at every associativity up to {}", fully_associative::MAX_SEARCH_ASSOC)?,
+        None => {}
+    }
+    if let Some(dram) = cache.row_buffer() {
+        writeln!(out, "{:#?}", dram.stats)?;
+    }
+    if matches.opt_present("heatmap") {
+        write!(out, "{}", cache.heatmap())?;
+    }
+    if matches.opt_present("set-miss-rates") {
+        for (set, (accesses, rate)) in cache.set_accesses().iter().zip(cache.set_miss_rates()).enumerate() {
+            writeln!(out, "set {}: {} accesses, miss rate {}", set, accesses, cache::format_rate(rate, precision))?;
+        }
+    }
+    for event in cache.events() {
+        writeln!(out, "{}", event)?;
+    }
+    if let Some(n) = top_evicted {
+        writeln!(out, "most evicted tags:")?;
+        for (tag, count) in cache.top_evicted(n) {
+            writeln!(out, "    {:#x}: {}", tag, count)?;
+        }
+    }
+    if matches.opt_present("emit-command") {
+        writeln!(out, "{}", command_line(argv, &defaults))?;
+    }
     Ok(())
 }
 
-fn print_usage() {
+/// A trace's accesses and phases, and the number of instruction fetches it holds
+type ParsedTrace = (Vec<valgrind::MemoryAccess>, Vec<valgrind::Phase>, u64);
+
+/// Parse the trace given by -f along with its phases
+fn read_trace(matches: &getopts::Matches, unified: bool, out: &mut dyn Write) -> Result<ParsedTrace, Box<dyn Error>> {
+    let file_contents = read_input(matches)?;
+    let format = trace_format(matches)?;
+    if format != valgrind::TraceFormat::Valgrind {
+        if let Some(name) = ["dump-parse", "collect-errors"].iter().find(|name| matches.opt_present(name)) {
+            return Err(format!("Error: --{} reads only valgrind traces", name).into());
+        }
+    }
+    if matches.opt_present("dump-parse") {
+        write!(out, "{}", valgrind::dump_parse(&file_contents, unified))?;
+    }
+    if matches.opt_present("collect-errors") {
+        valgrind::parse_collect(&file_contents, unified).map_err(|errors| {
+            for error in &errors {
+                eprintln!("{}", error);
+            }
+            format!("Error: {} malformed lines", errors.len())
+        })?;
+    }
+    parse_trace(&file_contents, format, unified)
+}
+
+/// Say how many accesses of size 0 the trace held, since they are dropped without a trace
+fn note_size_zero(count: u64) {
+    if count > 0 {
+        eprintln!("note: skipped {} accesses of size 0", count);
+    }
+}
+
+/// The format given by --trace-format, valgrind by default
+fn trace_format(matches: &getopts::Matches) -> Result<valgrind::TraceFormat, Box<dyn Error>> {
+    match matches.opt_str("trace-format") {
+        None => Ok(valgrind::TraceFormat::Valgrind),
+        Some(name) => valgrind::TraceFormat::from_name(&name)
+            .ok_or_else(|| format!("Error: Unknown trace format \"{}\"", name).into()),
+    }
+}
+
+fn parse_trace(contents: &str, format: valgrind::TraceFormat, unified: bool) -> Result<ParsedTrace, Box<dyn Error>> {
+    match format {
+        valgrind::TraceFormat::Valgrind => {
+            let (traces, phases, size_zero) = valgrind::parse_phases(contents, unified)?;
+            note_size_zero(size_zero);
+            Ok((traces, phases, valgrind::count_instructions(contents)))
+        }
+        // Only lackey traces mark phases, and Dinero's give no sizes, so none is dropped as 0
+        valgrind::TraceFormat::Dinero => {
+            let mut traces = valgrind::parse_dinero(contents, true)?;
+            let fetches = traces.iter().filter(|trace| trace.operation == valgrind::Operation::Instruction).count() as u64;
+            if !unified {
+                traces.retain(|trace| trace.operation != valgrind::Operation::Instruction);
+            }
+            Ok((traces, vec![], fetches))
+        }
+        valgrind::TraceFormat::Pin => {
+            let (traces, size_zero) = valgrind::parse_pin(contents)?;
+            note_size_zero(size_zero);
+            Ok((traces, vec![], 0))
+        }
+    }
+}
+
+/// The trace file given by -f, or `None` to read the trace from stdin
+fn trace_path(matches: &getopts::Matches) -> Option<String> {
+    matches.opt_str("f").filter(|path| path != "-")
+}
+
+/// The whole trace, from the -f file or else stdin
+fn read_input(matches: &getopts::Matches) -> Result<String, Box<dyn Error>> {
+    if let Some(path) = trace_path(matches) {
+        return Ok(read_trace_file(&path)?);
+    }
+    let stdin = io::stdin();
+    if stdin.is_terminal() {
+        return Err("Error: No trace given; pass -f <file> or pipe one to stdin".into());
+    }
+    let mut contents = String::new();
+    stdin.lock().read_to_string(&mut contents)?;
+    if contents.is_empty() {
+        return Err("Error: The trace read from stdin is empty".into());
+    }
+    Ok(contents)
+}
+
+/// A trace file, decompressed as it's read if its name ends in .gz
+fn open_trace(path: &str) -> io::Result<Box<dyn BufRead>> {
+    let file = BufReader::new(File::open(path)?);
+    if path.ends_with(".gz") {
+        Ok(Box::new(BufReader::new(gzip::GzipReader::new(file))))
+    } else {
+        Ok(Box::new(file))
+    }
+}
+
+fn read_trace_file(path: &str) -> io::Result<String> {
+    let mut contents = String::new();
+    open_trace(path)?.read_to_string(&mut contents)?;
+    Ok(contents)
+}
+
+/// Record `value` as the one taken for `name` if it was left off the command line
+fn resolve(matches: &getopts::Matches, defaults: &mut Vec<(&str, String)>, name: &'static str, value: impl ToString) {
+    if !matches.opt_present(name) {
+        defaults.push((name, value.to_string()));
+    }
+}
+
+/// The command line `argv` with the resolved `defaults` spelled out, minus --emit-command itself
+fn command_line(argv: &[String], defaults: &[(&str, String)]) -> String {
+    let given = argv.iter().filter(|arg| *arg != "--emit-command").cloned();
+    let resolved = defaults.iter().flat_map(|(name, value)| vec![format!("--{}", name), value.clone()]);
+
+    let words: Vec<String> = std::iter::once(String::from("csim")).chain(given).chain(resolved).map(|word| {
+        // Quote anything the shell would split or expand
+        if !word.is_empty() && word.chars().all(|c| c.is_ascii_alphanumeric() || "-_./=:,".contains(c)) {
+            word
+        } else {
+            format!("'{}'", word.replace('\'', "'\\''"))
+        }
+    }).collect();
+    words.join(" ")
+}
+
+/// How the summary of a run is printed
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Format {
+    Text,
+    Json,
+}
+
+fn parse_format(matches: &getopts::Matches) -> Result<Format, Box<dyn Error>> {
+    match matches.opt_str("format").as_deref() {
+        None | Some("text") => Ok(Format::Text),
+        Some("json") => Ok(Format::Json),
+        Some(other) => Err(format!("Error: Unknown --format '{}'", other).into()),
+    }
+}
+
+/// Print the counters in the CS:APP format followed by the hit and miss rates, and MPKI if
+/// `instructions` are known; or, for `Format::Json`, only the JSON object of the statistics
+fn write_summary(out: &mut dyn Write, stats: &cache::Statistics, instructions: u64, precision: usize, format: Format) -> Result<(), Box<dyn Error>> {
+    if format == Format::Json {
+        writeln!(out, "{}", stats.to_json())?;
+        return Ok(());
+    }
+    writeln!(out, "{}", stats)?;
+    if stats.victim_hits() > 0 {
+        writeln!(out, "victim hits: {}", stats.victim_hits())?;
+    }
+    if stats.forwarded() > 0 {
+        writeln!(out, "forwarded: {}", stats.forwarded())?;
+    }
+    writeln!(out, "hit rate: {}", cache::format_rate(stats.hit_rate(), precision))?;
+    writeln!(out, "miss rate: {}", cache::format_rate(stats.miss_rate(), precision))?;
+    if instructions > 0 {
+        writeln!(out, "mpki: {}", cache::format_rate(stats.mpki(instructions), precision))?;
+    }
+    Ok(())
+}
+
+/// The --seed given, else a fixed seed under --deterministic, else the time
+fn resolve_seed(matches: &getopts::Matches) -> Result<u64, Box<dyn Error>> {
+    match matches.opt_get::<u64>("seed")? {
+        Some(seed) => Ok(seed),
+        None if matches.opt_present("deterministic") => Ok(DETERMINISTIC_SEED),
+        None => Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_nanos() as u64),
+    }
+}
+
+/// Take snapshots every --snapshot-interval accesses, returning the --snapshot-file for them
+fn set_snapshot_interval(matches: &getopts::Matches, cache: &mut cache::Cache) -> Result<Option<String>, Box<dyn Error>> {
+    let snapshot_file = matches.opt_str("snapshot-file");
+    match matches.opt_get::<u64>("snapshot-interval")? {
+        Some(0) => return Err("Error: --snapshot-interval must be at least 1".into()),
+        Some(every) => cache.set_snapshot_interval(every),
+        None if snapshot_file.is_some() => return Err("Error: --snapshot-file needs --snapshot-interval".into()),
+        None => {}
+    }
+    Ok(snapshot_file)
+}
+
+/// The statistics of each --snapshot-interval, printed or else written to `path` as CSV
+fn write_snapshots(out: &mut dyn Write, path: Option<&str>, snapshots: &[cache::Statistics], precision: usize) -> Result<(), Box<dyn Error>> {
+    match path {
+        Some(path) => {
+            let mut file = BufWriter::new(File::create(path)?);
+            writeln!(file, "interval,hits,misses,evictions,miss_rate")?;
+            for (interval, stats) in snapshots.iter().enumerate() {
+                writeln!(file, "{},{},{},{},{}", interval, stats.hits(), stats.misses(), stats.evictions(), cache::format_rate(stats.miss_rate(), precision))?;
+            }
+            file.flush()?;
+        }
+        None => {
+            for (interval, stats) in snapshots.iter().enumerate() {
+                writeln!(out, "interval {}: {} hits, {} misses, {} evictions, miss rate {}", interval, stats.hits(), stats.misses(), stats.evictions(), cache::format_rate(stats.miss_rate(), precision))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Print every statistic as a sorted `key: value` line, with `n/a` for those of models not in use
+// The keys are the same whatever the options, so golden files of different runs line up
+fn write_canonical(
+    out: &mut dyn Write,
+    cache: &cache::Cache,
+    stats: &cache::Statistics,
+    global: Option<&fully_associative::FullyAssociative>,
+    amat: Option<f64>,
+    instructions: u64,
+    precision: usize,
+) -> Result<(), Box<dyn Error>> {
+    let rate = |rate: f64| cache::format_rate(rate, precision);
+    let mut values: BTreeMap<&str, Option<String>> = BTreeMap::new();
+
+    values.insert("accesses", Some(stats.accesses().to_string()));
+    values.insert("hits", Some(stats.hits().to_string()));
+    values.insert("misses", Some(stats.misses().to_string()));
+    values.insert("evictions", Some(stats.evictions().to_string()));
+    values.insert("victim_hits", Some(stats.victim_hits().to_string()));
+    values.insert("forwarded", Some(stats.forwarded().to_string()));
+    values.insert("dirty_evictions", Some(stats.dirty_evictions().to_string()));
+    values.insert("prefetches", Some(stats.prefetches().to_string()));
+    values.insert("prefetch_hits", Some(stats.prefetch_hits().to_string()));
+    values.insert("compulsory_misses", Some(stats.compulsory_misses().to_string()).filter(|_| cache.classifies_misses()));
+    values.insert("conflict_capacity_misses", Some(stats.conflict_capacity_misses().to_string()).filter(|_| cache.classifies_misses()));
+    values.insert("hit_rate", Some(rate(stats.hit_rate())));
+    values.insert("miss_rate", Some(rate(stats.miss_rate())));
+    values.insert("mpki", Some(rate(stats.mpki(instructions))).filter(|_| instructions > 0));
+    values.insert("bypasses", Some(cache.bypasses().to_string()));
+    values.insert("flushes", Some(cache.flushes().to_string()));
+
+    values.insert("amat", amat.map(rate));
+    values.insert("average_miss_latency", cache.latency().map(|model| rate(model.average_miss_latency())));
+    values.insert("energy", cache.energy().map(|energy| rate(energy.total())));
+    values.insert("writebacks", cache.write_back_buffer().map(|buffer| buffer.writebacks.to_string()));
+    values.insert("wb_stalls", cache.write_back_buffer().map(|buffer| buffer.stalls.to_string()));
+    values.insert("port_cycles", cache.ports().map(|ports| ports.stats.cycles.to_string()));
+    values.insert("port_read_stalls", cache.ports().map(|ports| ports.stats.read_stalls.to_string()));
+    values.insert("port_write_stalls", cache.ports().map(|ports| ports.stats.write_stalls.to_string()));
+    values.insert("dram_row_hits", cache.row_buffer().map(|dram| dram.stats.hit.to_string()));
+    values.insert("dram_row_misses", cache.row_buffer().map(|dram| dram.stats.miss.to_string()));
+    values.insert("dram_row_conflicts", cache.row_buffer().map(|dram| dram.stats.conflict.to_string()));
+    values.insert("global_hits", global.map(|global| global.stats.hit.to_string()));
+    values.insert("global_misses", global.map(|global| global.stats.miss.to_string()));
+    values.insert("global_evictions", global.map(|global| global.stats.eviction.to_string()));
+
+    for (key, value) in values {
+        writeln!(out, "{}: {}", key, value.as_deref().unwrap_or("n/a"))?;
+    }
+    Ok(())
+}
+
+/// Run each case of the manifest at `path`, or of the bundled manifest, reporting pass or fail
+// Traces in a manifest are read relative to the manifest's directory
+fn run_selftest(path: Option<String>, out: &mut dyn Write) -> Result<(), Box<dyn Error>> {
+    let (manifest, directory) = match &path {
+        Some(path) => (fs::read_to_string(path)?, std::path::Path::new(path).parent().map(|dir| dir.to_path_buf())),
+        None => (String::from(selftest::BUNDLED_MANIFEST), None),
+    };
+    let cases = selftest::parse_manifest(&manifest)?;
+
+    let mut failures = 0;
+    for case in &cases {
+        let contents = match &directory {
+            Some(directory) => fs::read_to_string(directory.join(&case.trace))?,
+            None => String::from(selftest::bundled_trace(&case.trace).ok_or("Error: Unknown bundled trace")?),
+        };
+        let result = selftest::run_case(case, &contents)?;
+        let verdict = if result == case.expected { "pass" } else { failures += 1; "FAIL" };
+        writeln!(
+            out, "{} s={} E={} b={} {}: expected {:?}, got {:?}",
+            verdict, case.set_bits, case.lines, case.block_bits, case.trace, case.expected, result,
+        )?;
+    }
+    writeln!(out, "{} of {} cases passed", cases.len() - failures, cases.len())?;
+    if failures > 0 {
+        return Err(format!("Error: {} self-test cases failed", failures).into());
+    }
+    Ok(())
+}
+
+/// The first option given that `--lightweight` can't stream the trace for
+fn lightweight_blocker(matches: &getopts::Matches) -> Option<String> {
+    if let Some(name) = HEAVYWEIGHT_OPTIONS.iter().find(|name| matches.opt_present(name)) {
+        return Some(format!("--{}", name));
+    }
+    if matches.opt_str("policy").as_deref() == Some("opt") {
+        return Some(String::from("--policy opt"));
+    }
+    None
+}
+
+/// Stream the trace through a cache keeping only its hit, miss and eviction counts
+fn run_lightweight(argv: &[String], matches: &getopts::Matches, out: &mut dyn Write) -> Result<(), Box<dyn Error>> {
+    if let Some(option) = lightweight_blocker(matches) {
+        return Err(format!("Error: {} is not available with --lightweight", option).into());
+    }
+
+    let (set_bits, lines, block_bits) = parse_geometry(matches, ["s", "E", "b"])?;
+    let mut cache = cache::Cache::new(set_bits, lines, block_bits)?;
+
+    let deterministic = matches.opt_present("deterministic");
+    let seed = resolve_seed(matches)?;
+    cache.set_policy(parse_policy(matches.opt_str("policy").as_deref(), seed)?);
+    let mut defaults = vec![];
+    resolve(matches, &mut defaults, "seed", seed);
+    resolve(matches, &mut defaults, "policy", "lru");
+
+    let input: Box<dyn BufRead> = match trace_path(matches) {
+        Some(path) => open_trace(&path)?,
+        None => Box::new(io::stdin().lock()),
+    };
+    if matches.opt_present("progress") {
+        cache.set_progress(match deterministic {
+            true => progress::Progress::untimed(PROGRESS_EVERY),
+            false => progress::Progress::new(PROGRESS_EVERY),
+        });
+    }
+    let snapshot_file = set_snapshot_interval(matches, &mut cache)?;
+
+    let mut reader = valgrind::parse_lines(input, matches.opt_present("unified"));
+    let mut error = None;
+    let max_accesses = match matches.opt_get_default("max-accesses", 0_usize)? {
+        0 => usize::MAX,
+        max => max,
+    };
+    // Stop reading at the cap, so the instructions counted are those before it
+    cache.operate_stream(reader.by_ref().map_while(|trace| {
+        trace.map_err(|reason| error = Some(reason)).ok()
+    }).take(max_accesses));
+    if let Some(error) = error {
+        return Err(error.into());
+    }
+    note_size_zero(reader.size_zero());
+
+    if let Some(progress) = cache.progress() {
+        progress.finish();
+    }
+
+    let instructions = matches.opt_get::<u64>("instruction-count")?.unwrap_or(reader.instructions());
+    let precision = matches.opt_get_default("precision", DEFAULT_PRECISION)?;
+    write_summary(out, &cache.stats, instructions, precision, parse_format(matches)?)?;
+    if matches.opt_present("snapshot-interval") {
+        write_snapshots(out, snapshot_file.as_deref(), &cache.snapshots(), precision)?;
+    }
+    if matches.opt_present("emit-command") {
+        writeln!(out, "{}", command_line(argv, &defaults))?;
+    }
+    Ok(())
+}
+
+/// Bytes a full run holds per byte of trace file: the file's contents plus the parsed accesses
+const TRACE_FOOTPRINT: u64 = 4;
+
+#[derive(Debug, PartialEq)]
+enum RunMode {
+    Full,
+    Lightweight,
+}
+
+/// Pick a full run if its estimated memory fits in `megabytes`, or else a lightweight one
+// The lightweight run holds only the cache; the full run adds the whole trace
+fn choose_mode(matches: &getopts::Matches, megabytes: f64) -> Result<RunMode, Box<dyn Error>> {
+    let path = match trace_path(matches) {
+        Some(path) => path,
+        None => return Err("Error: --mem-budget requires a trace file; stdin can't be sized".into()),
+    };
+    let trace_bytes = fs::metadata(path)?.len();
+    let sets = match matches.opt_get::<u64>("sets")? {
+        Some(num_sets) => num_sets,
+        None => 1_u64.checked_shl(matches.opt_str("s").unwrap().parse::<u32>()?).unwrap_or(u64::MAX),
+    };
+    let lines = matches.opt_str("E").unwrap().parse::<u64>()?;
+    let block_bytes = 1_u64.checked_shl(matches.opt_str("b").unwrap().parse::<u32>()?).unwrap_or(u64::MAX);
+    let cache_bytes = sets.saturating_mul(lines).saturating_mul(block_bytes.saturating_add(std::mem::size_of::<cache::Line>() as u64));
+
+    let budget = (megabytes * 1024.0 * 1024.0) as u64;
+    if cache_bytes.saturating_add(trace_bytes.saturating_mul(TRACE_FOOTPRINT)) <= budget && !matches.opt_present("lightweight") {
+        return Ok(RunMode::Full);
+    }
+    if cache_bytes > budget {
+        return Err(format!("Error: The cache alone needs about {} bytes, over the --mem-budget", cache_bytes).into());
+    }
+    let blocker = lightweight_blocker(matches)
+        .or_else(|| matches.opt_present("split-lw").then(|| String::from("--split-lw")))
+        .or_else(|| matches.opt_present("l2-s").then(|| String::from("--l2-s")))
+        .or_else(|| matches.opt_present("icache").then(|| String::from("--icache")));
+    match blocker {
+        Some(option) => Err(format!("Error: Over the --mem-budget, and {} needs the whole trace in memory", option).into()),
+        None => Ok(RunMode::Lightweight),
+    }
+}
+
+/// Simulate separate load and store caches, each configured like a plain cache
+fn run_split(matches: &getopts::Matches, unified: bool, out: &mut dyn Write) -> Result<(), Box<dyn Error>> {
+    if let Some(name) = HEAVYWEIGHT_OPTIONS.iter().chain(&STREAMED_OPTIONS).find(|name| matches.opt_present(name)) {
+        return Err(format!("Error: --{} is not available with --split-lw", name).into());
+    }
+    if matches.opt_present("emit-command") {
+        return Err("Error: --emit-command is not available with --split-lw".into());
+    }
+
+    let (set_bits, lines, block_bits) = parse_geometry(matches, ["s", "E", "b"])?;
+    let (traces, _, instructions) = read_trace(matches, unified, out)?;
+
+    let seed = resolve_seed(matches)?;
+    // OPT looks ahead over only the accesses its own cache will see
+    let (stores, loads): (Vec<_>, Vec<_>) = traces.iter().cloned().partition(|trace| trace.operation == valgrind::Operation::Store);
+    let build = |routed: &[valgrind::MemoryAccess]| -> Result<cache::Cache, Box<dyn Error>> {
+        let mut cache = cache::Cache::new(set_bits, lines, block_bits)?;
+        match matches.opt_str("policy").as_deref() {
+            Some("opt") => cache.set_policy(Box::new(policy::Opt::new(routed, set_bits, block_bits))),
+            Some("dip") => cache.set_insertion(insertion::Insertion::Dip(insertion::Dip::new(1 << set_bits, seed))),
+            name => cache.set_policy(parse_policy(name, seed)?),
+        }
+        Ok(cache)
+    };
+    let mut split = split::SplitCache::new(build(&loads)?, build(&stores)?);
+    split.operate_cache(traces);
+
+    writeln!(out, "load cache: {}", split.loads.stats)?;
+    writeln!(out, "store cache: {}", split.stores.stats)?;
+    let instructions = matches.opt_get::<u64>("instruction-count")?.unwrap_or(instructions);
+    write!(out, "combined: ")?;
+    write_summary(out, &split.combined(), instructions, matches.opt_get_default("precision", DEFAULT_PRECISION)?, parse_format(matches)?)?;
+    Ok(())
+}
+
+/// Simulate each of several -f traces, in a cache of their own or shared with --shared-cache
+fn run_files(matches: &getopts::Matches, unified: bool, out: &mut dyn Write) -> Result<(), Box<dyn Error>> {
+    let others = ["emit-command", "lightweight", "mem-budget", "replay-addresses", "split-lw", "icache", "l2-s", "l2-E", "l2-b", "l2-policy"];
+    if let Some(name) = HEAVYWEIGHT_OPTIONS.iter().chain(&STREAMED_OPTIONS).chain(&others).find(|name| matches.opt_present(name)) {
+        return Err(format!("Error: --{} is not available with several -f files", name).into());
+    }
+    // OPT would need every trace ahead of the run, and DIP's duel to start over with each
+    if let Some(name @ ("opt" | "dip")) = matches.opt_str("policy").as_deref() {
+        return Err(format!("Error: --policy {} is not available with several -f files", name).into());
+    }
+    let (set_bits, lines, block_bits) = parse_geometry(matches, ["s", "E", "b"])?;
+    let format = trace_format(matches)?;
+    let paths = matches.opt_strs("f");
+    let mut traces = Vec::with_capacity(paths.len());
+    let mut instructions = 0;
+    for path in &paths {
+        let (trace, _, fetches) = parse_trace(&read_trace_file(path)?, format, unified)?;
+        traces.push(trace);
+        instructions += fetches;
+    }
+
+    let seed = resolve_seed(matches)?;
+    let mut cache = cache::Cache::new(set_bits, lines, block_bits)?;
+    cache.set_policy(parse_policy(matches.opt_str("policy").as_deref(), seed)?);
+    let (each, total) = batch::simulate_all(&mut cache, traces, matches.opt_present("shared-cache"));
+
+    for (path, stats) in paths.iter().zip(&each) {
+        writeln!(out, "{}: {}", path, stats)?;
+    }
+    let instructions = matches.opt_get::<u64>("instruction-count")?.unwrap_or(instructions);
+    write!(out, "total: ")?;
+    write_summary(out, &total, instructions, matches.opt_get_default("precision", DEFAULT_PRECISION)?, parse_format(matches)?)?;
+    Ok(())
+}
+
+/// Simulate the trace under each of `compare::POLICIES`, printing a row of statistics for each
+fn run_compare(matches: &getopts::Matches, unified: bool, out: &mut dyn Write) -> Result<(), Box<dyn Error>> {
+    if let Some(name) = HEAVYWEIGHT_OPTIONS.iter().chain(&STREAMED_OPTIONS).chain(&["emit-command", "policy"]).find(|name| matches.opt_present(name)) {
+        return Err(format!("Error: --{} is not available with --compare-policies", name).into());
+    }
+    let (set_bits, lines, block_bits) = parse_geometry(matches, ["s", "E", "b"])?;
+    let (traces, _, _) = read_trace(matches, unified, out)?;
+    let seed = resolve_seed(matches)?;
+
+    let precision = matches.opt_get_default("precision", DEFAULT_PRECISION)?;
+    writeln!(out, "{:<8}{:>10}{:>10}{:>10}{:>11}", "policy", "hits", "misses", "evictions", "miss rate")?;
+    for (name, stats) in compare::compare_policies(set_bits, lines, block_bits, &traces, seed)? {
+        writeln!(out, "{:<8}{:>10}{:>10}{:>10}{:>11}", name, stats.hits(), stats.misses(), stats.evictions(), cache::format_rate(stats.miss_rate(), precision))?;
+    }
+    Ok(())
+}
+
+/// Simulate the cache as an L1 in front of an L2 of the geometry given by --l2-s, --l2-E and --l2-b
+fn run_hierarchy(matches: &getopts::Matches, unified: bool, out: &mut dyn Write) -> Result<(), Box<dyn Error>> {
+    if let Some(name) = HEAVYWEIGHT_OPTIONS.iter().chain(&STREAMED_OPTIONS).chain(&["emit-command", "split-lw", "icache"]).find(|name| matches.opt_present(name)) {
+        return Err(format!("Error: --{} is not available with an L2", name).into());
+    }
+    // Each level's OPT would need the stream it sees ahead of the run
+    if let Some(name @ ("opt" | "dip")) = matches.opt_str("policy").as_deref() {
+        return Err(format!("Error: --policy {} is not available with an L2", name).into());
+    }
+    if !["l2-s", "l2-E", "l2-b"].iter().all(|name| matches.opt_present(name)) {
+        return Err("Error: An L2 needs all of --l2-s, --l2-E and --l2-b".into());
+    }
+    let (set_bits, lines, block_bits) = parse_geometry(matches, ["s", "E", "b"])?;
+    let (l2_set_bits, l2_lines, l2_block_bits) = parse_geometry(matches, ["l2-s", "l2-E", "l2-b"])?;
+    let (traces, _, instructions) = read_trace(matches, unified, out)?;
+
+    let seed = resolve_seed(matches)?;
+    let mut l1 = cache::Cache::new(set_bits, lines, block_bits)?;
+    l1.set_policy(parse_policy(matches.opt_str("policy").as_deref(), seed)?);
+    let mut l2 = cache::Cache::new(l2_set_bits, l2_lines, l2_block_bits)?;
+    l2.set_policy(parse_policy(matches.opt_str("policy").as_deref(), seed.wrapping_add(1))?);
+    let mut hierarchy = hierarchy::CacheHierarchy::new(l1, l2);
+    if let Some(name) = matches.opt_str("l2-policy") {
+        let inclusion = hierarchy::Inclusion::from_name(&name).ok_or_else(|| format!("Error: Unknown --l2-policy '{}'", name))?;
+        // Blocks move whole between the levels
+        if inclusion == hierarchy::Inclusion::Exclusive && l2_block_bits != block_bits {
+            return Err("Error: --l2-policy exclusive needs --l2-b equal to -b".into());
+        }
+        hierarchy.set_inclusion(inclusion);
+    }
+    hierarchy.operate_cache(traces);
+
+    let instructions = matches.opt_get::<u64>("instruction-count")?.unwrap_or(instructions);
+    let precision = matches.opt_get_default("precision", DEFAULT_PRECISION)?;
+    let format = parse_format(matches)?;
+    write!(out, "L1: ")?;
+    write_summary(out, &hierarchy.l1.stats, instructions, precision, format)?;
+    write!(out, "L2: ")?;
+    write_summary(out, &hierarchy.l2.stats, instructions, precision, format)?;
+    Ok(())
+}
+
+/// Simulate instruction fetches in a cache of their own, apart from the data accesses
+fn run_icache(matches: &getopts::Matches, out: &mut dyn Write) -> Result<(), Box<dyn Error>> {
+    if let Some(name) = HEAVYWEIGHT_OPTIONS.iter().chain(&STREAMED_OPTIONS).chain(&["emit-command", "split-lw", "unified"]).find(|name| matches.opt_present(name)) {
+        return Err(format!("Error: --{} is not available with --icache", name).into());
+    }
+    if let Some(name @ ("opt" | "dip")) = matches.opt_str("policy").as_deref() {
+        return Err(format!("Error: --policy {} is not available with --icache", name).into());
+    }
+    let (set_bits, lines, block_bits) = parse_geometry(matches, ["s", "E", "b"])?;
+    // The instruction cache takes the data cache's shape unless given its own
+    let or_data = |name: &'static str, data: &'static str| if matches.opt_present(name) { name } else { data };
+    let (i_set_bits, i_lines, i_block_bits) = parse_geometry(matches, [or_data("icache-s", "s"), or_data("icache-E", "E"), or_data("icache-b", "b")])?;
+    let (traces, _, instructions) = read_trace(matches, true, out)?;
+    let (fetches, data): (Vec<_>, Vec<_>) = traces.into_iter().partition(|trace| trace.operation == valgrind::Operation::Instruction);
+
+    let seed = resolve_seed(matches)?;
+    let mut icache = cache::Cache::new(i_set_bits, i_lines, i_block_bits)?;
+    icache.set_policy(parse_policy(matches.opt_str("policy").as_deref(), seed.wrapping_add(1))?);
+    icache.operate_cache(fetches);
+    let mut dcache = cache::Cache::new(set_bits, lines, block_bits)?;
+    dcache.set_policy(parse_policy(matches.opt_str("policy").as_deref(), seed)?);
+    dcache.operate_cache(data);
+
+    let instructions = matches.opt_get::<u64>("instruction-count")?.unwrap_or(instructions);
+    let precision = matches.opt_get_default("precision", DEFAULT_PRECISION)?;
+    let format = parse_format(matches)?;
+    write!(out, "icache: ")?;
+    write_summary(out, &icache.stats, instructions, precision, format)?;
+    write!(out, "dcache: ")?;
+    write_summary(out, &dcache.stats, instructions, precision, format)?;
+    Ok(())
+}
+
+/// The set bits, lines and block bits given by the options `names`
+fn parse_geometry(matches: &getopts::Matches, names: [&str; 3]) -> Result<(u8, u32, u8), Box<dyn Error>> {
+    let value = |name: &str| geometry_value(name, &matches.opt_str(name).unwrap());
+    Ok((value(names[0])? as u8, value(names[1])?, value(names[2])? as u8))
+}
+
+/// Explain why the cache the geometry options `names` describe, of `num_sets` sets if given by
+/// --sets, is too large to build
+fn check_geometry_size(matches: &getopts::Matches, names: [&str; 3], num_sets: Option<u64>) -> Result<(), Box<dyn Error>> {
+    let lines = geometry_value(names[1], &matches.opt_str(names[1]).unwrap())?;
+    let block_bits = bits_value(names[2], &matches.opt_str(names[2]).unwrap())?;
+    let num_sets = match num_sets {
+        Some(num_sets) => num_sets,
+        None => 1 << bits_value(names[0], &matches.opt_str(names[0]).unwrap())?,
+    };
+    cache::check_size(num_sets, lines, block_bits)
+}
+
+/// Print the geometry the options configure, as the cache would derive it
+fn show_config(matches: &getopts::Matches, out: &mut dyn Write) -> Result<(), Box<dyn Error>> {
+    let lines = geometry_value("E", &matches.opt_str("E").unwrap())?;
+    let block_bits = bits_value("b", &matches.opt_str("b").unwrap())?;
+    let (num_sets, set_bits) = match matches.opt_get::<u64>("sets")? {
+        Some(0) => return Err("Error: --sets must be at least 1".into()),
+        Some(num_sets) => (num_sets, 0),
+        None => {
+            let set_bits = bits_value("s", &matches.opt_str("s").unwrap())?;
+            (1 << set_bits, set_bits)
+        }
+    };
+    let addr_bits = matches.opt_get_default("addr-bits", 64_u8)?;
+    if addr_bits == 0 || addr_bits > 64 || set_bits + block_bits > addr_bits {
+        return Err(format!("Error: {} set bits and {} block bits don't fit in a {}-bit address", set_bits, block_bits, addr_bits).into());
+    }
+
+    writeln!(out, "sets: {}", num_sets)?;
+    writeln!(out, "lines per set: {}", lines)?;
+    writeln!(out, "bytes per block: {}", 1_u64 << block_bits)?;
+    writeln!(out, "tag bits: {}", cache::tag_bits(addr_bits, set_bits, block_bits))?;
+    writeln!(out, "data bytes: {}", cache::data_bytes(num_sets, lines, block_bits))?;
+    Ok(())
+}
+
+/// `argv` with --block-bytes and --cache-size replaced by the -b and -s they come to
+fn in_bits(argv: &[String], matches: &getopts::Matches) -> Result<Vec<String>, Box<dyn Error>> {
+    let mut block_bits = match matches.opt_str("b") {
+        Some(bits) => Some(bits_value("b", &bits)?),
+        None => None,
+    };
+    if let Some(bytes) = matches.opt_get::<u64>("block-bytes")? {
+        if block_bits.is_some() {
+            return Err("Error: -b and --block-bytes can't both be given".into());
+        }
+        block_bits = Some(cache::bits_for_bytes(bytes, "Block size")?);
+    }
+
+    let mut set_bits = None;
+    if let Some(size) = matches.opt_get::<u64>("cache-size")? {
+        if let Some(name) = ["s", "sets"].iter().find(|name| matches.opt_present(name)) {
+            return Err(format!("Error: --cache-size can't be combined with -{}", name).into());
+        }
+        let (lines, block_bits) = match (matches.opt_str("E"), block_bits) {
+            (Some(lines), Some(block_bits)) => (geometry_value("E", &lines)?, block_bits),
+            _ => return Err("Error: --cache-size needs -E and -b or --block-bytes".into()),
+        };
+        let set_bytes = (lines as u64) << block_bits;
+        if size % set_bytes != 0 || !(size / set_bytes).is_power_of_two() {
+            return Err(format!("Error: A {}-byte cache isn't a power-of-two number of {}-byte sets", size, set_bytes).into());
+        }
+        set_bits = Some((size / set_bytes).trailing_zeros());
+    }
+
+    let mut rewritten = vec![];
+    let mut args = argv.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--block-bytes" | "--cache-size" => {
+                args.next();
+            }
+            arg if arg.starts_with("--block-bytes=") || arg.starts_with("--cache-size=") => {}
+            // -b is given again below, from --block-bytes if not already
+            "-b" | "--block" => {
+                args.next();
+            }
+            arg if arg.starts_with("-b") || arg.starts_with("--block=") => {}
+            arg => rewritten.push(arg.to_string()),
+        }
+    }
+    if let Some(bits) = block_bits {
+        rewritten.extend([String::from("-b"), bits.to_string()]);
+    }
+    if let Some(bits) = set_bits {
+        rewritten.extend([String::from("-s"), bits.to_string()]);
+    }
+    Ok(rewritten)
+}
+
+/// The value of the geometry option `name`, one of the s, E or b of a cache, if it's in range
+// Every line allocates its block, so blocks are held to 16 MB; `check_geometry_size`
+// bounds the cache as a whole
+fn geometry_value(name: &str, value: &str) -> Result<u32, String> {
+    let flag = if name.len() == 1 { format!("-{}", name) } else { format!("--{}", name) };
+    let (what, range) = match name.rsplit('-').next() {
+        Some("s") => ("set bits", 0..=32),
+        Some("E") => ("lines per set", 1..=MAX_LINES),
+        _ => ("block bits", 0..=24),
+    };
+    match value.parse::<u32>() {
+        Ok(parsed) if range.contains(&parsed) => Ok(parsed),
+        _ => Err(format!("Error: {} {}: {} must be between {} and {}", flag, value, what, range.start(), range.end())),
+    }
+}
+
+/// The value of the s or b geometry option `name`, a number of bits
+fn bits_value(name: &str, value: &str) -> Result<u8, String> {
+    geometry_value(name, value).map(|bits| bits as u8)
+}
+
+/// The accesses `start..end` named by a `start:end` window over a trace of `len` accesses
+fn parse_window(window: &str, len: usize) -> Result<(usize, usize), Box<dyn Error>> {
+    let bounds = window.split_once(':').and_then(|(start, end)| Some((start.parse().ok()?, end.parse().ok()?)));
+    match bounds {
+        Some((start, end)) if start <= end && end <= len => Ok((start, end)),
+        Some(_) => Err(format!("Error: Trace window '{}' doesn't fit the trace's {} accesses", window, len).into()),
+        None => Err(format!("Error: Invalid trace window '{}', expected start:end", window).into()),
+    }
+}
+
+/// Read one hex address per line, skipping blank lines
+fn parse_lock_file(input: &str) -> Result<Vec<u64>, Box<dyn Error>> {
+    let mut addresses = vec![];
+    for (index, line) in input.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        match u64::from_str_radix(line.trim_start_matches("0x"), 16) {
+            Ok(address) => addresses.push(address),
+            Err(_) => return Err(format!("Error: Invalid lock address on line {}: \"{}\"", index + 1, line).into()),
+        }
+    }
+    Ok(addresses)
+}
+
+fn parse_policy(name: Option<&str>, seed: u64) -> Result<Box<dyn policy::ReplacementPolicy>, Box<dyn Error>> {
+    let name = name.unwrap_or("lru");
+    match policy::from_name(name, seed) {
+        Some(policy) => Ok(policy),
+        None => Err(format!("Error: Unknown replacement policy \"{}\"", name).into()),
+    }
+}
+
+fn print_usage(out: &mut dyn Write) -> io::Result<()> {
     let usage = "Usage: csim -s <num> -E <num> -b <num> -f <file>\n\
     Options:
         -s <num>  Number of set index bits.
         -E <num>  Number of lines per set.
         -b <num>  Number of lines per set.
-        -f <file> Valgrind instruction log.\
+        -f <file> Valgrind instruction log; read from stdin if omitted or -. Give -f more
+                  than once to report each file and their total.
+        -v        Print each trace line and its outcome (hit, miss, miss eviction) as the
+                  CS:APP reference simulator does.
+        --shared-cache         With -f given more than once, simulate the files one after
+                               another in one cache rather than a cold cache each. Each file's
+                               statistics are printed, then their total.
+        --show-config          Print the sets, lines per set, block size, tag bits and data
+                               size the options configure, and exit without reading a trace.
+        --block-bytes <num>    Block size in bytes in place of -b; must be a power of two.
+        --cache-size <num>     Cache size in bytes in place of -s, divided into sets of -E
+                               lines of the block size; the sets must number a power of two.
+        --sets <num>           Number of sets in place of -s; need not be a power of two, as
+                               blocks are placed by block address modulo the number of sets.
+        --hit-time <num>       Cycles taken by a cache hit (default 1).
+        --miss-penalty <num>   Cycles taken to fill a block (default 100). Either one reports
+                               the accesses and the average memory access time, the hit time
+                               plus the miss rate times the miss penalty.
+        --critical-word-first  Deliver the missed word before the rest of the block.
+        --heatmap              Print a heatmap of per-set access counts.
+        --set-miss-rates       Print the accesses and miss rate of each set.
+        --page-table <file>    Translate addresses through a page table (hex `vpn ppn` lines).
+        --page-bits <num>      Number of page offset bits (default 12).
+        --strict-page-table    Treat accesses to unmapped pages as errors.
+        --vipt                 Index sets by virtual address but tag lines by physical
+                               address, exposing aliases when index bits exceed the page offset.
+        --policy <name>        Replacement policy: lru (default), mru, fifo, lfu, random, opt
+                               (Belady's optimal, which reads the whole trace ahead of the run),
+                               or dip (LRU, inserting by LRU or BIP as set dueling decides).
+        --seed <num>           Seed for the random policy; runs with equal seeds are identical.
+        --victim-size <num>    Number of entries in a fully-associative victim cache.
+        --victim-policy <name> Replacement policy for the victim cache (default lru).
+        --store-buffer <num>   Number of entries in a store buffer ahead of the cache.
+        --drain-policy <name>  Drain the store buffer when full (default) or eagerly.
+        --deterministic        Seed the random policy with a fixed value, so repeated runs are
+                               identical; --progress then leaves out the run's time.
+        --insertion <name>     Recency position of filled lines: lru (MRU, default), lip
+                               (LRU position) or bip (LRU position, occasionally MRU).
+        --top-evicted <num>    Print the N most frequently evicted tags.
+        --auto-addr-bits       Size tags for 32-bit addresses if every access fits in 32 bits.
+        --addr-bits <num>      Width of an address in bits (default 64); accesses to addresses
+                               wider than that are errors.
+        --trace-set <index>    Log every access, hit, fill and eviction in one set.
+        --collect-errors       Report every malformed trace line rather than stopping at the first.
+        --trace-format <name>  Format of the trace: valgrind lackey output (default); dinero,
+                               DineroIV's lines of an access type (0 read, 1 write, 2 fetch)
+                               and a hex address; or pin, the output of Pin's pinatrace.
+        --dump-parse           Print the tokens of each trace line and the accesses parsed from
+                               it, or why there were none, before simulating.
+        --access-log <file>    Log every access with its set, tag, outcome and latency in cycles.
+        --csv <file>           Write a CSV row for every access, a modify's load and store
+                               apart, of its index, op, hex address, set, hex tag and outcome.
+        --no-write-allocate    Write stores that miss through to memory without filling a line.
+        --store-miss-counts <true|false>
+                               Whether those stores count as misses (default true).
+        --dram-row-bits <num>  Count row buffer hits, misses and conflicts of a DRAM bank
+                               with 2^N-byte rows serving the cache's misses.
+        --flamegraph <file>    Write misses per instruction (from each access's preceding
+                               I line) in the folded-stack format of flamegraph tools.
+        --global-assoc <num>   Also simulate a fully-associative LRU cache of N lines,
+                               whose misses are the trace's compulsory and capacity misses.
+        --min-assoc-for-zero-conflict
+                               Report the fewest lines per set at which LRU misses no more
+                               than a fully-associative LRU cache of the same size.
+        --per-thread-stats     Report hits and misses for each thread, given as an optional
+                               leading decimal field on each trace line.
+        --classify-misses      Report misses on a block's first access, the compulsory misses,
+                               apart from the conflict and capacity misses on blocks seen before.
+        --progress             Print the number of accesses simulated to stderr after every
+                               million, then the total and the time the run took.
+        --per-op-stats         Report hits and misses of loads and of stores separately; a
+                               modify counts as one of each.
+        --replacement-state-dump
+                               Print the replacement policy's state of each set after the run.
+        --dump                 Print the tag and recency stamp of each valid line after the run,
+                               set by set; empty sets are left out.
+        --stats-binary <file>  Append a 16-byte record of s, E, b, hits, misses and evictions.
+        --read-stats <file>    Print the records of a --stats-binary file as a table; no other
+                               options are needed.
+        --selftest[=<manifest>]
+                               Check the results of the cases in <manifest>, one
+                               \"trace s E b hits misses evictions\" a line, or of the bundled
+                               CS:APP cases; no other options are needed.
+        --warn-footprint       Warn when nothing was evicted because every block of the
+                               trace fits in the cache at once.
+        --op-weight <weights>  Number of times the policy must choose a line filled by a load
+                               or a store before it is evicted, e.g. load:1,store:2 (default 1).
+        --lightweight          Stream the trace and report only hits, misses and evictions, in
+                               memory bounded by the cache size. Takes only -s, -E, -b, -f,
+                               --policy lru|mru|fifo|lfu|random, --seed, --deterministic,
+                               --max-accesses, --progress, --snapshot-interval,
+                               --snapshot-file and --unified.
+        --format <name>        Print the summary as text (default) or as a JSON object of the
+                               hits, misses, evictions and miss rate.
+        --canonical            Print every statistic, core and detailed, as one `key: value`
+                               line each, sorted by key, with `n/a` for analyses not enabled.
+        --emit-command         Print the csim command line, with every default it took
+                               spelled out, that reproduces this run.
+        --replay-addresses <file>
+                               Simulate the addresses recorded by --access-log in place of -f,
+                               to see the same stream under another geometry.
+        --stride               Report the most common nonzero difference between consecutive
+                               addresses and how often it occurs.
+        --flush-every <num>    Invalidate every line after each N accesses, modelling the
+                               cost of context switches.
+        --skip-null            Drop accesses to address 0, which some tracers use for null.
+        --snapshot-interval <num>
+                               Report hits, misses, evictions and the miss rate of each run
+                               of N accesses in turn, after the totals.
+        --snapshot-file <file> Write the --snapshot-interval series to this file as CSV
+                               instead.
+        --phases               Report hits, misses and evictions for each phase of the trace,
+                               each begun by a `# PHASE <name>` line.
+        --repeat <num>         Simulate the trace this many times over, keeping the cache warm
+                               between iterations.
+        --repeat-report <which>
+                               Statistics to report with --repeat: all iterations (default) or
+                               only the last.
+        --precision <num>      Decimal places in reported rates (default 2).
+        --reuse-per-block      Print each block's average and maximum reuse distance: the
+                               number of distinct blocks accessed between uses of it.
+        --hash <name>          Set index hash: none (default) or xor-fold, which XORs each
+                               set-width slice of the tag into the set index.
+        --trace-window <start:end>
+                               Count only the accesses from index <start> up to <end>.
+        --warm-before          Simulate the accesses before the trace window, without counting
+                               them, so the window starts with a warm cache.
+        --max-accesses <num>   Simulate only the first N accesses of the trace, warm-up
+                               included, as if it ended there; 0 (default) simulates them all.
+        --warmup <num>         Simulate the first N accesses without counting them, measuring
+                               the rest from a warm cache.
+        --warm-trace <file>    Simulate another trace first, without counting it, so the -f
+                               trace starts with that trace's blocks in the cache.
+        --instruction-count <num>
+                               Instructions the trace covers, for misses per thousand
+                               instructions; by default the trace's I lines are counted.
+        --mem-budget <MB>      Estimate the run's memory and switch to --lightweight if a full
+                               run wouldn't fit in <MB> megabytes, saying so on stderr.
+        --exclude-cold-sets    Leave the first miss in each set out of the statistics.
+        --columnar <file>      Write every access and its outcome to <file> as binary columns
+                               of index, op, address, set, tag and outcome; the layout is
+                               described in src/columnar.rs.
+        --compare-policies     Simulate the trace under LRU, FIFO and random replacement in
+                               one pass, in caches of the same geometry, and print a row of
+                               hits, misses, evictions and miss rate for each.
+        --icache               Simulate instruction fetches in an instruction cache of their
+                               own, reporting it apart from the data cache of -s, -E and -b.
+        --icache-s <num>       Number of set index bits of the instruction cache (default -s).
+        --icache-E <num>       Number of lines per set of the instruction cache (default -E).
+        --icache-b <num>       Number of block offset bits of the instruction cache
+                               (default -b).
+        --l2-s <num>           Number of set index bits of an L2 behind the cache. Misses are
+                               looked up in the L2 before memory, and both levels' statistics
+                               are reported. Needs --l2-E and --l2-b.
+        --l2-E <num>           Number of lines per set of the L2.
+        --l2-b <num>           Number of block offset bits of the L2.
+        --l2-policy <name>     How L1's contents relate to the L2's: non-inclusive (default),
+                               each level filling and evicting on its own; inclusive, every L1
+                               block also in the L2, so an L2 eviction invalidates it in L1; or
+                               exclusive, each block in one level only, L1's evictions moving
+                               into the L2. Exclusive needs --l2-b equal to -b.
+        --split-lw             Give loads and stores a cache each, both of the given shape,
+                               and report them separately and combined. Instruction fetches
+                               go to the load cache.
+        --badge                End with a one-line summary of the hit rate and geometry.
+        --byte-valid           Keep a valid bit for every byte of a line. A store miss allocates
+                               only the bytes it writes, and a load touching an invalid byte
+                               misses. Needs a bit of memory per cached byte.
+        --prefetch <name>      Prefetcher: next-line fills the block after each one missed,
+                               counting prefetches and the demand hits they serve apart
+                               from hits and misses.
+        --write-back           Count evictions of lines written since their fill, each of
+                               which a write-back cache writes back to the next level.
+        --wb-buffer <num>      Queue dirty evictions in a write-back buffer of <num> entries,
+                               counting the evictions that stall on a full buffer.
+        --wb-drain-rate <num>  Write-back buffer entries drained per access, possibly
+                               fractional (default 1).
+        --lock <file>          Lock the lines of the addresses listed in <file>, one hex address
+                               a line, once they are filled. A miss to a set whose lines are all
+                               locked bypasses the cache.
+        --energy-hit <num>     Energy of a hit, in any unit; the run's energy is estimated
+                               as the weighted sum of its events (default 0).
+        --energy-miss <num>    Energy of a miss's lookup (default 0).
+        --energy-writeback <num>
+                               Energy of writing back a dirty line (default 0).
+        --energy-fill <num>    Energy of filling a line (default 0).
+        --read-ports <num>     Number of read ports (default 1), for counting structural stalls.
+        --write-ports <num>    Number of write ports (default 1).
+        --issue-width <num>    Accesses issued to the cache a cycle, in trace order (default
+                               the larger port count).
+        --unified              Count instruction fetches as accesses, as in a unified cache.
+        --split-accesses       Split an access that spans blocks, by its size, into one access
+                               per block, each counted as a hit or miss. By default, as in the
+                               CS:APP reference, only the block of the address is looked up.
+        --coalesce-instructions
+                               With --unified, merge consecutive instruction fetches
+                               from one block into a single access.\
     ";
-    println!("{}", usage);
+    writeln!(out, "{}", usage)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn run_args(args: &str) -> String {
+        let argv: Vec<String> = args.split_whitespace().map(String::from).collect();
+        let mut out = Vec::new();
+        run(&argv, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn deterministic_runs_are_identical() {
+        let args = "-s 2 -E 4 -b 3 -f test/trans.trace --policy random --victim-size 2 --deterministic";
+        let first = run_args(args);
+
+        assert!(first.starts_with("hits:"));
+        assert_eq!(first, run_args(args));
+    }
+
+    #[test]
+    fn emitted_command_reproduces_run() {
+        let output = run_args("-s 2 -E 4 -b 3 -f test/trans.trace --policy random --hit-time 2 --emit-command");
+        let (stats, command) = output.trim_end().rsplit_once('\n').unwrap();
+        assert!(command.starts_with("csim -s 2") && command.contains("--seed") && command.contains("--miss-penalty 100"));
+
+        let rerun = run_args(command.strip_prefix("csim ").unwrap());
+        assert_eq!(rerun.trim_end(), stats);
+    }
+
+    #[test]
+    fn selftest_passes() {
+        for args in &["--selftest", "--selftest=test/selftest.manifest"] {
+            let output = run_args(args);
+            assert!(output.lines().all(|line| line.starts_with("pass") || line.ends_with("cases passed")), "{}", output);
+        }
+    }
+
+    #[test]
+    fn tiny_budget_forces_lightweight() {
+        let parse = |args: &str| options().parse(args.split_whitespace()).unwrap();
+
+        let args = "-s 2 -E 1 -b 3 -f test/trans.trace";
+        assert_eq!(choose_mode(&parse(args), 1.0).unwrap(), RunMode::Full);
+        assert_eq!(choose_mode(&parse(args), 0.01).unwrap(), RunMode::Lightweight);
+        assert!(choose_mode(&parse(&format!("{} --policy opt", args)), 0.01).is_err());
+    }
+
+    #[test]
+    fn warm_trace_leaves_its_blocks_cached() {
+        // Warming with the trace itself is its second pass over a warm cache
+        let args = "-s 4 -E 1 -b 4 -f test/trans.trace";
+        let warmed = run_args(&format!("{} --warm-trace test/trans.trace", args));
+        assert_eq!(warmed, run_args(&format!("{} --repeat 2 --repeat-report last", args)));
+        assert_ne!(warmed, run_args(args));
+    }
+
+    #[test]
+    fn warmup_hides_cold_misses() {
+        let path = std::env::temp_dir().join(format!("csim-warmup-{}.trace", std::process::id()));
+        fs::write(&path, " L 0,1\n L 10,1\n L 0,1\n L 10,1\n").unwrap();
+        let args = format!("-s 1 -E 1 -b 4 -f {}", path.display());
+
+        assert!(run_args(&format!("{} --warmup 2", args)).starts_with("hits:2 misses:0 evictions:0"));
+        assert!(run_args(&format!("{} --warmup 9", args)).starts_with("hits:0 misses:0 evictions:0"));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn max_accesses_truncates_trace() {
+        let capped = run_args("-s 1 -E 1 -b 4 -f test/trans.trace --max-accesses 100");
+        assert_eq!(capped, run_args("-s 1 -E 1 -b 4 -f test/trans.trace --trace-window 0:100"));
+        assert_ne!(capped, run_args("-s 1 -E 1 -b 4 -f test/trans.trace"));
+        assert_eq!(run_args("-s 1 -E 1 -b 4 -f test/trans.trace --max-accesses 0"), run_args("-s 1 -E 1 -b 4 -f test/trans.trace"));
+        let streamed = run_args("-s 1 -E 1 -b 4 -f test/trans.trace --max-accesses 100 --lightweight");
+        assert_eq!(streamed.lines().next(), capped.lines().next());
+    }
+
+    #[test]
+    fn compare_policies_table() {
+        let table = run_args("-s 1 -E 2 -b 4 -f test/trans.trace --compare-policies --seed 1");
+        let rows: Vec<&str> = table.lines().collect();
+        assert_eq!(rows.len(), 1 + compare::POLICIES.len());
+        let lru = run_args("-s 1 -E 2 -b 4 -f test/trans.trace");
+        let hits = lru.split_whitespace().next().unwrap().trim_start_matches("hits:");
+        assert_eq!(rows[1].split_whitespace().nth(1), Some(hits));
+    }
+
+    #[test]
+    fn snapshots_when_streamed() {
+        let full = run_args("-s 1 -E 1 -b 4 -f test/trans.trace --snapshot-interval 100");
+        assert_eq!(full.lines().filter(|line| line.starts_with("interval ")).count(), 3);
+        assert_eq!(run_args("-s 1 -E 1 -b 4 -f test/trans.trace --snapshot-interval 100 --lightweight"), full);
+    }
+
+    #[test]
+    fn gzipped_trace_matches_plain() {
+        // The trace in stored blocks; src/gzip.rs tests the compressed kinds
+        let trace = fs::read("test/trans.trace").unwrap();
+        let crc = !trace.iter().fold(!0_u32, |crc, &byte| {
+            (0..8).fold(crc ^ u32::from(byte), |crc, _| if crc & 1 == 1 { 0xedb8_8320 ^ (crc >> 1) } else { crc >> 1 })
+        });
+        let mut member = vec![0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 0xff];
+        let chunks: Vec<&[u8]> = trace.chunks(u16::MAX as usize).collect();
+        for (index, chunk) in chunks.iter().enumerate() {
+            let length = chunk.len() as u16;
+            member.push((index + 1 == chunks.len()) as u8);
+            member.extend(length.to_le_bytes().iter().chain(&(!length).to_le_bytes()).chain(chunk.iter()));
+        }
+        member.extend(crc.to_le_bytes().iter().chain(&(trace.len() as u32).to_le_bytes()));
+        let path = std::env::temp_dir().join(format!("csim-gzip-{}.trace.gz", std::process::id()));
+        fs::write(&path, member).unwrap();
+
+        for extra in ["", " --lightweight"] {
+            let plain = run_args(&format!("-s 1 -E 1 -b 4 -f test/trans.trace{}", extra));
+            assert_eq!(run_args(&format!("-s 1 -E 1 -b 4 -f {}{}", path.display(), extra)), plain);
+        }
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn geometry_out_of_range() {
+        assert_eq!(bits_value("s", "300"), Err(String::from("Error: -s 300: set bits must be between 0 and 32")));
+        assert_eq!(geometry_value("E", "0"), Err(String::from("Error: -E 0: lines per set must be between 1 and 1048576")));
+        assert_eq!(geometry_value("E", "1024"), Ok(1024));
+        assert_eq!(geometry_value("l2-b", "x"), Err(String::from("Error: --l2-b x: block bits must be between 0 and 24")));
+        assert_eq!(bits_value("b", "6"), Ok(6));
+    }
+
+    #[test]
+    fn geometry_too_large_to_build() {
+        for args in ["-s 32 -E 1 -b 0", "-s 20 -E 1048576 -b 24", "-s 1 -E 1 -b 4 --icache --icache-b 24 --icache-E 1024"] {
+            let argv: Vec<String> = format!("{} -f test/trans.trace", args).split_whitespace().map(String::from).collect();
+            let mut out = Vec::new();
+            let error = run(&argv, &mut out).unwrap_err().to_string();
+            assert!(error.contains("a cache may"), "{}: {}", args, error);
+            assert!(String::from_utf8(out).unwrap().starts_with("Usage"), "{}", args);
+        }
+    }
+
+    #[test]
+    fn show_config_needs_no_trace() {
+        assert_eq!(
+            run_args("--show-config -s 4 -E 2 -b 6"),
+            "sets: 16\nlines per set: 2\nbytes per block: 64\ntag bits: 54\ndata bytes: 2048\n",
+        );
+        assert!(run_args("--show-config --sets 3 -E 1 -b 4 --addr-bits 32").contains("tag bits: 28\ndata bytes: 48\n"));
+    }
+
+    #[test]
+    fn sizes_in_bytes_match_bits() {
+        let bits = run_args("-s 2 -E 2 -b 4 -f test/trans.trace");
+        assert_eq!(run_args("-s 2 -E 2 --block-bytes 16 -f test/trans.trace"), bits);
+        assert_eq!(run_args("--cache-size 128 -E 2 -b 4 -f test/trans.trace"), bits);
+
+        let error = |args: &str| {
+            let argv: Vec<String> = args.split_whitespace().map(String::from).collect();
+            run(&argv, &mut Vec::new()).unwrap_err().to_string()
+        };
+        assert_eq!(error("-s 2 -E 2 --block-bytes 24 -f test/trans.trace"), "Error: Block size of 24 bytes isn't a power of two");
+        assert!(error("--cache-size 96 -E 2 -b 4 -f test/trans.trace").contains("power-of-two number"));
+    }
+
+    #[test]
+    fn canonical_output_is_stable() {
+        let args = "-s 2 -E 2 -b 3 -f test/trans.trace --canonical --energy-hit 1";
+        let output = run_args(args);
+        assert_eq!(output, run_args(args));
+
+        let keys: Vec<&str> = output.lines().map(|line| line.split(':').next().unwrap()).collect();
+        let mut sorted = keys.clone();
+        sorted.sort();
+        assert_eq!(keys, sorted);
+        assert!(output.contains("\nenergy: 201.00\n") && output.contains("\nglobal_hits: n/a\n"));
+    }
+
+    #[test]
+    fn icache_leaves_data_results_alone() {
+        let args = "-s 2 -E 1 -b 3 -f test/trans.trace";
+        let split = run_args(&format!("{} --icache --icache-s 1", args));
+        let (icache, dcache) = split.split_once("dcache: ").unwrap();
+
+        assert!(icache.starts_with("icache: hits:"));
+        assert_eq!(dcache, run_args(args));
+    }
+
+    #[test]
+    fn lightweight_matches_full_run() {
+        let args = "-s 2 -E 2 -b 3 -f test/trans.trace --deterministic";
+        assert_eq!(run_args(&format!("{} --lightweight", args)), run_args(args));
+    }
 }
@@ -0,0 +1,334 @@
+//! Replacement policies choosing which line of a full set to evict
+
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use crate::cache::Line;
+use crate::rng::Rng;
+use crate::valgrind::MemoryAccess;
+
+pub trait ReplacementPolicy: Debug {
+    /// Called as each access to `block_address` reaches the cache, before its lookup
+    fn access(&mut self, _block_address: u64) {}
+
+    /// Index of the line to evict from a set whose lines are all valid, never a locked one;
+    /// at least one line is unlocked
+    fn choose_victim(&mut self, lines: &[Line]) -> usize;
+
+    /// The policy's view of set `set`, for debugging
+    fn describe_state(&self, _set: u64, _lines: &[Line]) -> String {
+        String::from("no per-set state")
+    }
+}
+
+/// Build a policy from its command-line name; `opt` needs the trace and is built with `Opt::new`
+pub fn from_name(name: &str, seed: u64) -> Option<Box<dyn ReplacementPolicy>> {
+    match name {
+        "lru" => Some(Box::new(Lru)),
+        "mru" => Some(Box::new(Mru)),
+        "fifo" => Some(Box::new(Fifo)),
+        "lfu" => Some(Box::new(Lfu)),
+        "random" => Some(Box::new(Random::new(seed))),
+        _ => None,
+    }
+}
+
+/// The lines a policy may evict, with their indices in the set
+fn unlocked(lines: &[Line]) -> impl Iterator<Item = (usize, &Line)> {
+    lines.iter().enumerate().filter(|(_, line)| !line.is_locked())
+}
+
+/// Evict the least recently used line, the lowest-indexed among equally recent ones
+#[derive(Debug)]
+pub struct Lru;
+
+impl ReplacementPolicy for Lru {
+    fn choose_victim(&mut self, lines: &[Line]) -> usize {
+        // The first of equal minima, so ties go to the lowest index
+        unlocked(lines).min_by_key(|(_, line)| line.last_access()).unwrap().0
+    }
+
+    fn describe_state(&self, _set: u64, lines: &[Line]) -> String {
+        let mut order: Vec<(usize, &Line)> = lines.iter().enumerate().filter(|(_, line)| line.is_valid()).collect();
+        order.sort_by_key(|(_, line)| std::cmp::Reverse(line.last_access()));
+
+        let order: Vec<String> = order.iter().map(|(id, line)| format!("line {} ({:#x})", id, line.tag())).collect();
+        format!("most recent first: {}", order.join(", "))
+    }
+}
+
+/// Evict the most recently used line, the lowest-indexed among equally recent ones
+// A loop over more blocks than a set holds misses every time under LRU; MRU
+// keeps all but one of them resident instead.
+#[derive(Debug)]
+pub struct Mru;
+
+impl ReplacementPolicy for Mru {
+    fn choose_victim(&mut self, lines: &[Line]) -> usize {
+        unlocked(lines).min_by_key(|(_, line)| Reverse(line.last_access())).unwrap().0
+    }
+}
+
+/// Evict the line filled longest ago, however recently it was used
+#[derive(Debug)]
+pub struct Fifo;
+
+impl ReplacementPolicy for Fifo {
+    fn choose_victim(&mut self, lines: &[Line]) -> usize {
+        unlocked(lines).min_by_key(|(_, line)| line.insert_order()).unwrap().0
+    }
+}
+
+/// Evict the line hit least often since its fill, the least recently used among equals
+#[derive(Debug)]
+pub struct Lfu;
+
+impl ReplacementPolicy for Lfu {
+    fn choose_victim(&mut self, lines: &[Line]) -> usize {
+        unlocked(lines).min_by_key(|(_, line)| (line.access_count(), line.last_access())).unwrap().0
+    }
+}
+
+/// Evict a uniformly random line, reproducibly for a given seed
+#[derive(Debug)]
+pub struct Random {
+    rng: Rng,
+}
+
+impl Random {
+    pub fn new(seed: u64) -> Random {
+        Random { rng: Rng::new(seed) }
+    }
+}
+
+impl ReplacementPolicy for Random {
+    fn choose_victim(&mut self, lines: &[Line]) -> usize {
+        let choice = self.rng.below(unlocked(lines).count() as u64) as usize;
+        unlocked(lines).nth(choice).unwrap().0
+    }
+}
+
+/// Belady's optimal policy: evict the line whose block is next used furthest in the future
+// Needs the whole trace up front to know when each block is next used, so it
+// only applies to accesses simulated in trace order.
+#[derive(Debug)]
+pub struct Opt {
+    set_mask: u64,
+    set_bits: u8,
+    // For each access, the position of the next access to the same block
+    next_use: Vec<usize>,
+    position: usize,
+    // Position of the next access to each block, as of the current access
+    next_access: HashMap<u64, usize>,
+    current_set: u64,
+}
+
+impl Opt {
+    pub fn new(traces: &[MemoryAccess], set_bits: u8, block_bits: u8) -> Opt {
+        let mut next_use = vec![usize::MAX; traces.len()];
+        let mut later: HashMap<u64, usize> = HashMap::new();
+
+        for (position, trace) in traces.iter().enumerate().rev() {
+            let block_address = trace.address >> block_bits;
+            if let Some(&next) = later.get(&block_address) {
+                next_use[position] = next;
+            }
+            later.insert(block_address, position);
+        }
+
+        Opt {
+            set_mask: (1_u64 << set_bits) - 1,
+            set_bits,
+            next_use,
+            position: 0,
+            next_access: HashMap::new(),
+            current_set: 0,
+        }
+    }
+}
+
+impl ReplacementPolicy for Opt {
+    fn access(&mut self, block_address: u64) {
+        let next = self.next_use.get(self.position).copied().unwrap_or(usize::MAX);
+        self.next_access.insert(block_address, next);
+        self.current_set = block_address & self.set_mask;
+        self.position += 1;
+    }
+
+    fn choose_victim(&mut self, lines: &[Line]) -> usize {
+        let next_use = |line: &Line| {
+            let block_address = (line.tag() << self.set_bits) | self.current_set;
+            self.next_access.get(&block_address).copied().unwrap_or(usize::MAX)
+        };
+
+        unlocked(lines).min_by_key(|(_, line)| Reverse(next_use(line))).unwrap().0
+    }
+
+    fn describe_state(&self, set: u64, lines: &[Line]) -> String {
+        let uses: Vec<String> = lines.iter().enumerate().filter(|(_, line)| line.is_valid()).map(|(id, line)| {
+            let block_address = (line.tag() << self.set_bits) | set;
+            match self.next_access.get(&block_address) {
+                Some(&next) if next != usize::MAX => format!("line {} ({:#x}) next at access {}", id, line.tag(), next),
+                _ => format!("line {} ({:#x}) never reused", id, line.tag()),
+            }
+        }).collect();
+        uses.join(", ")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cache::Cache;
+    use crate::valgrind::parse;
+
+    fn misses(trace: &str, policy: Option<Opt>) -> u32 {
+        let mut cache = Cache::new(1, 2, 4).unwrap();
+        if let Some(policy) = policy {
+            cache.set_policy(Box::new(policy));
+        }
+        cache.operate_cache(parse(trace).unwrap());
+        cache.stats.miss
+    }
+
+    #[test]
+    fn opt_evicts_furthest_next_use() {
+        // Blocks A, B and C share set 0 of a two-way cache; on the fill of C,
+        // A is needed again soonest, so OPT evicts B where LRU evicts A
+        let trace = " L 0,1\n L 20,1\n L 40,1\n L 0,1\n L 40,1\n L 20,1";
+        let traces = parse(trace).unwrap();
+
+        let mut opt = Opt::new(&traces, 1, 4);
+        for trace in &traces[..3] {
+            opt.access(trace.address >> 4);
+        }
+        let lines: Vec<Line> = [0, 1].iter().map(|&tag| {
+            let mut line = Line::new(0);
+            line.fill(tag, 0);
+            line
+        }).collect();
+        assert_eq!(opt.choose_victim(&lines), 1);
+    }
+
+    #[test]
+    fn opt_misses_hand_computed() {
+        // A B C A C B in one two-way set: OPT keeps A for its reuse and misses on
+        // A, B, C and the final B; LRU evicts A for C and misses on all but C
+        let trace = " L 0,1\n L 20,1\n L 40,1\n L 0,1\n L 40,1\n L 20,1";
+        let opt = Opt::new(&parse(trace).unwrap(), 1, 4);
+        assert_eq!(misses(trace, Some(opt)), 4);
+        assert_eq!(misses(trace, None), 5);
+    }
+
+    #[test]
+    fn opt_beats_lru_behind_store_buffer() {
+        // The buffer forwards the load of E and holds its store until the end, so the cache
+        // sees A B C A C B E: OPT keeps A for its reuse where LRU evicts it
+        use crate::store_buffer::{DrainPolicy, StoreBuffer};
+        let trace = parse(" S 40,1\n L 40,1\n L 0,1\n L 10,1\n L 20,1\n L 0,1\n L 20,1\n L 10,1").unwrap();
+        let buffer = StoreBuffer::new(1, DrainPolicy::Full, 4);
+        let misses = |policy: Option<Opt>| {
+            let mut cache = Cache::new(0, 2, 4).unwrap();
+            cache.set_store_buffer(StoreBuffer::new(1, DrainPolicy::Full, 4));
+            if let Some(policy) = policy {
+                cache.set_policy(Box::new(policy));
+            }
+            cache.operate_cache(trace.clone());
+            cache.stats.miss
+        };
+        assert_eq!(misses(Some(Opt::new(&buffer.references(&trace), 0, 4))), 5);
+        assert_eq!(misses(None), 6);
+    }
+
+    #[test]
+    fn lru_ties_go_to_lowest_index() {
+        let lines: Vec<Line> = [(0, 7), (1, 3), (2, 3), (3, 3)].iter().map(|&(tag, now)| {
+            let mut line = Line::new(0);
+            line.fill(tag, now);
+            line
+        }).collect();
+        assert_eq!(Lru.choose_victim(&lines), 1);
+        assert_eq!(Lru.choose_victim(&lines[1..]), 0);
+    }
+
+    #[test]
+    fn mru_survives_loop_larger_than_set() {
+        // Looping over A, B and C in one two-way set: LRU always evicts the block
+        // needed next, while MRU evicts the block just used, needed last
+        let trace = parse(&" L 0,1\n L 10,1\n L 20,1\n".repeat(4)).unwrap();
+        let mut cache = Cache::new(0, 2, 4).unwrap();
+        cache.set_policy(Box::new(Mru));
+        cache.operate_cache(trace.clone());
+        assert_eq!((cache.stats.hit, cache.stats.miss), (5, 7));
+
+        let mut lru = Cache::new(0, 2, 4).unwrap();
+        lru.operate_cache(trace);
+        assert_eq!((lru.stats.hit, lru.stats.miss), (0, 12));
+    }
+
+    #[test]
+    fn fifo_evicts_earliest_filled() {
+        let lines: Vec<Line> = [(0, 5), (1, 2), (2, 7)].iter().map(|&(tag, now)| {
+            let mut line = Line::new(0);
+            line.fill(tag, now);
+            line
+        }).collect();
+        assert_eq!(Fifo.choose_victim(&lines), 1);
+    }
+
+    #[test]
+    fn fifo_ignores_hits() {
+        // A, B, A, C into one two-way set: the hit on A doesn't protect it, so C
+        // evicts A under FIFO and the B that follows hits
+        let trace = parse(" L 0,1\n L 10,1\n L 0,1\n L 20,1\n L 10,1").unwrap();
+        let mut cache = Cache::new(0, 2, 4).unwrap();
+        cache.set_policy(Box::new(Fifo));
+        cache.operate_cache(trace.clone());
+        assert_eq!((cache.stats.hit, cache.stats.miss), (2, 3));
+
+        let mut lru = Cache::new(0, 2, 4).unwrap();
+        lru.operate_cache(trace);
+        assert_eq!((lru.stats.hit, lru.stats.miss), (1, 4));
+    }
+
+    #[test]
+    fn lfu_keeps_frequently_hit_line() {
+        // A is hit twice before B and C arrive: LRU evicts A for C, LFU evicts the
+        // unhit B, so the final A hits only under LFU
+        let trace = parse(" L 0,1\n L 0,1\n L 0,1\n L 10,1\n L 20,1\n L 0,1").unwrap();
+        let mut cache = Cache::new(0, 2, 4).unwrap();
+        cache.set_policy(Box::new(Lfu));
+        cache.operate_cache(trace.clone());
+        assert_eq!((cache.stats.hit, cache.stats.miss), (3, 3));
+
+        let mut lru = Cache::new(0, 2, 4).unwrap();
+        lru.operate_cache(trace);
+        assert_eq!((lru.stats.hit, lru.stats.miss), (2, 4));
+    }
+
+    #[test]
+    fn random_victims_follow_seed() {
+        let lines: Vec<Line> = (0..4).map(|tag| {
+            let mut line = Line::new(0);
+            line.fill(tag, tag);
+            line
+        }).collect();
+        let victims = |seed| {
+            let mut random = Random::new(seed);
+            (0..32).map(|_| random.choose_victim(&lines)).collect::<Vec<usize>>()
+        };
+
+        assert_eq!(victims(42), victims(42));
+        assert_ne!(victims(42), victims(43));
+        assert!(victims(42).iter().all(|&id| id < lines.len()));
+    }
+
+    #[test]
+    fn opt_never_misses_more_than_lru() {
+        let traces: Vec<String> = (0..400).map(|i| format!(" L {:x},1", ((i * i + i / 3) % 7) << 5)).collect();
+        let trace = traces.join("\n");
+
+        let opt = Opt::new(&parse(&trace).unwrap(), 1, 4);
+        assert!(misses(&trace, Some(opt)) <= misses(&trace, None));
+    }
+}
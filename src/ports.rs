@@ -0,0 +1,90 @@
+//! Model structural stalls on a cache's read and write ports
+// Accesses issue in trace order, up to `issue_width` per cycle. Loads and
+// instruction fetches take a read port and stores a write port; an access
+// whose kind of port is used up for the cycle stalls until the next, and
+// everything behind it waits too.
+
+use crate::valgrind::Operation;
+
+#[derive(Debug, Default, PartialEq)]
+pub struct PortStatistics {
+    pub cycles: u64,
+    pub read_stalls: u64,
+    pub write_stalls: u64,
+}
+
+#[derive(Debug)]
+pub struct PortModel {
+    read_ports: u32,
+    write_ports: u32,
+    issue_width: u32,
+    reads: u32,
+    writes: u32,
+    pub stats: PortStatistics,
+}
+
+impl PortModel {
+    /// A cache with the given ports, fed up to `issue_width` accesses a cycle
+    pub fn new(read_ports: u32, write_ports: u32, issue_width: u32) -> PortModel {
+        PortModel {
+            read_ports,
+            write_ports,
+            issue_width,
+            reads: 0,
+            writes: 0,
+            stats: PortStatistics::default(),
+        }
+    }
+
+    /// Start again from before the first cycle
+    pub fn reset(&mut self) {
+        self.reads = 0;
+        self.writes = 0;
+        self.stats = PortStatistics::default();
+    }
+
+    pub fn access(&mut self, operation: Operation) {
+        let write = operation == Operation::Store;
+        if self.stats.cycles == 0 || self.reads + self.writes == self.issue_width {
+            self.next_cycle();
+        } else if write && self.writes == self.write_ports {
+            self.stats.write_stalls += 1;
+            self.next_cycle();
+        } else if !write && self.reads == self.read_ports {
+            self.stats.read_stalls += 1;
+            self.next_cycle();
+        }
+
+        if write {
+            self.writes += 1;
+        } else {
+            self.reads += 1;
+        }
+    }
+
+    fn next_cycle(&mut self) {
+        self.stats.cycles += 1;
+        self.reads = 0;
+        self.writes = 0;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn write_burst_stalls() {
+        // Two read ports and one write port, two accesses a cycle
+        let mut ports = PortModel::new(2, 1, 2);
+        for _ in 0..4 {
+            ports.access(Operation::Load);
+        }
+        assert_eq!(ports.stats, PortStatistics { cycles: 2, read_stalls: 0, write_stalls: 0 });
+
+        for _ in 0..4 {
+            ports.access(Operation::Store);
+        }
+        assert_eq!(ports.stats, PortStatistics { cycles: 6, read_stalls: 0, write_stalls: 3 });
+    }
+}
@@ -0,0 +1,84 @@
+//! Report on stderr how far a long run has got
+// Written to stderr so the statistics on stdout stay clean for scripts. An
+// untimed report leaves out the run's wall-clock time, so that it repeats
+// exactly from run to run as --deterministic promises.
+
+use std::time::{Duration, Instant};
+
+#[derive(Debug)]
+pub struct Progress {
+    every: u64,
+    accesses: u64,
+    start: Option<Instant>,
+}
+
+impl Progress {
+    /// Report after each `every` accesses, timing the run from now
+    pub fn new(every: u64) -> Progress {
+        Progress { every, accesses: 0, start: Some(Instant::now()) }
+    }
+
+    /// Report after each `every` accesses, without timing the run
+    pub fn untimed(every: u64) -> Progress {
+        Progress { every, accesses: 0, start: None }
+    }
+
+    /// Count from zero again, timing the run from now if it's timed
+    pub fn reset(&mut self) {
+        self.accesses = 0;
+        self.start = self.start.map(|_| Instant::now());
+    }
+
+    /// Count an access, reporting if it completes another `every`
+    pub fn tick(&mut self) {
+        self.accesses += 1;
+        if self.accesses.is_multiple_of(self.every) {
+            eprintln!("progress: {} accesses", self.accesses);
+        }
+    }
+
+    pub fn accesses(&self) -> u64 {
+        self.accesses
+    }
+
+    /// Report the accesses simulated and the time they took
+    pub fn finish(&self) {
+        eprintln!("{}", self.summary());
+    }
+
+    /// The report of `finish`
+    pub fn summary(&self) -> String {
+        match self.elapsed() {
+            Some(elapsed) => format!("progress: {} accesses in {:.2}s", self.accesses, elapsed.as_secs_f64()),
+            None => format!("progress: {} accesses", self.accesses),
+        }
+    }
+
+    /// Time since the run started, unless it's untimed
+    pub fn elapsed(&self) -> Option<Duration> {
+        self.start.map(|start| start.elapsed())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cache::Cache;
+    use crate::valgrind::parse;
+
+    #[test]
+    fn counts_every_access() {
+        let mut cache = Cache::new(0, 1, 4).unwrap();
+        cache.set_progress(Progress::new(2));
+        cache.operate_cache(parse(" L 0,1\n M 10,1\n S 20,1").unwrap());
+        assert_eq!(cache.progress().unwrap().accesses(), 4);
+    }
+
+    #[test]
+    fn untimed_summary_repeats() {
+        let mut progress = Progress::untimed(2);
+        (0..3).for_each(|_| progress.tick());
+        assert_eq!(progress.summary(), "progress: 3 accesses");
+        assert!(Progress::new(2).summary().ends_with("s"));
+    }
+}
@@ -0,0 +1,66 @@
+//! Measure the reuse distance of each block in a trace
+// A block's reuse distance is the number of distinct other blocks accessed
+// since its previous access: a fully-associative LRU cache of more lines than
+// that would hit. Distances are read off an LRU stack of every block seen.
+
+use std::collections::BTreeMap;
+use crate::valgrind::MemoryAccess;
+
+/// Reuse distances of one block over the trace
+#[derive(Debug, Default, PartialEq)]
+pub struct BlockReuse {
+    /// Accesses after the block's first
+    pub reuses: u64,
+    pub total_distance: u64,
+    pub max_distance: u64,
+}
+
+impl BlockReuse {
+    /// Mean distance over the block's reuses, or `None` for a block used only once
+    pub fn average(&self) -> Option<f64> {
+        match self.reuses {
+            0 => None,
+            reuses => Some(self.total_distance as f64 / reuses as f64),
+        }
+    }
+}
+
+/// Reuse distances of every block the trace touches, by block address
+pub fn reuse_per_block(traces: &[MemoryAccess], block_bits: u8) -> BTreeMap<u64, BlockReuse> {
+    // Most recently used last
+    let mut stack: Vec<u64> = vec![];
+    let mut blocks: BTreeMap<u64, BlockReuse> = BTreeMap::new();
+
+    for trace in traces {
+        let block = trace.address >> block_bits;
+        let reuse = blocks.entry(block).or_default();
+        if let Some(position) = stack.iter().rposition(|&resident| resident == block) {
+            let distance = (stack.len() - 1 - position) as u64;
+            reuse.reuses += 1;
+            reuse.total_distance += distance;
+            reuse.max_distance = reuse.max_distance.max(distance);
+            stack.remove(position);
+        }
+        stack.push(block);
+    }
+    blocks
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::valgrind::parse;
+
+    #[test]
+    fn distances_per_block() {
+        // Blocks A B A C B A, with 16-byte blocks
+        let traces = parse(" L 0,1\n L 10,1\n L 4,1\n L 20,1\n L 18,1\n L 8,1").unwrap();
+        let blocks = reuse_per_block(&traces, 4);
+
+        assert_eq!(blocks[&0], BlockReuse { reuses: 2, total_distance: 3, max_distance: 2 });
+        assert_eq!(blocks[&1], BlockReuse { reuses: 1, total_distance: 2, max_distance: 2 });
+        assert_eq!(blocks[&2], BlockReuse::default());
+        assert_eq!(blocks[&0].average(), Some(1.5));
+        assert_eq!(blocks[&2].average(), None);
+    }
+}
@@ -0,0 +1,35 @@
+//! Small deterministic pseudo-random number generator
+// xorshift64* (Vigna, "An experimental exploration of Marsaglia's xorshift
+// generators, scrambled", 2016). The state is seeded through one round of
+// splitmix64 so that small or similar seeds still give unrelated streams, and
+// so a zero seed does not collapse onto xorshift's all-zero fixed point. The
+// sequence depends only on the seed, never on the OS or the clock.
+
+#[derive(Debug, Clone)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        let mut z = seed.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^= z >> 31;
+
+        Rng { state: if z == 0 { 1 } else { z } }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    /// Uniform value in `0..bound`; `bound` must be non-zero
+    pub fn below(&mut self, bound: u64) -> u64 {
+        // Multiply-shift keeps the high bits, which are the best-mixed ones
+        ((self.next_u64() as u128 * bound as u128) >> 64) as u64
+    }
+}
@@ -0,0 +1,71 @@
+//! Check the simulator against known results, as the CS:APP lab's test-csim does
+// A manifest holds one case per line, `trace s E b hits misses evictions`,
+// with blank lines and `#` comments skipped. The cases bundled into the
+// binary are those of test/selftest.manifest.
+
+use std::error::Error;
+use crate::{simulate, Geometry};
+
+pub const BUNDLED_MANIFEST: &str = include_str!("../test/selftest.manifest");
+const BUNDLED_TRACES: [(&str, &str); 1] = [("trans.trace", include_str!("../test/trans.trace"))];
+
+#[derive(Debug, PartialEq)]
+pub struct Case {
+    pub trace: String,
+    pub set_bits: u8,
+    pub lines: u32,
+    pub block_bits: u8,
+    /// Hits, misses and evictions
+    pub expected: (u32, u32, u32),
+}
+
+pub fn parse_manifest(input: &str) -> Result<Vec<Case>, Box<dyn Error>> {
+    let mut cases = vec![];
+    for (index, line) in input.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let malformed = || format!("Error: Malformed self-test case on line {}: \"{}\"", index + 1, line);
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() != 7 {
+            return Err(malformed().into());
+        }
+        let number = |at: usize| fields[at].parse::<u32>().map_err(|_| malformed());
+        let bits = |at: usize| fields[at].parse::<u8>().map_err(|_| malformed());
+        cases.push(Case {
+            trace: fields[0].to_string(),
+            set_bits: bits(1)?,
+            lines: number(2)?,
+            block_bits: bits(3)?,
+            expected: (number(4)?, number(5)?, number(6)?),
+        });
+    }
+    Ok(cases)
+}
+
+/// The contents of a trace bundled into the binary
+pub fn bundled_trace(name: &str) -> Option<&'static str> {
+    BUNDLED_TRACES.iter().find(|(bundled, _)| *bundled == name).map(|(_, contents)| *contents)
+}
+
+/// Simulate a case on the trace `contents`, returning the hits, misses and evictions it gave
+pub fn run_case(case: &Case, contents: &str) -> Result<(u32, u32, u32), Box<dyn Error>> {
+    let stats = simulate(Geometry::new(case.set_bits, case.lines, case.block_bits), contents)?;
+    Ok((stats.hit, stats.miss, stats.eviction))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn malformed_case() {
+        assert!(parse_manifest("trans.trace 2 1 3 167 71").is_err());
+        // Bit counts past a u8 are rejected rather than wrapped
+        let error = parse_manifest("trans.trace 300 1 3 167 71 67").unwrap_err();
+        assert_eq!(error.to_string(), "Error: Malformed self-test case on line 1: \"trans.trace 300 1 3 167 71 67\"");
+        assert!(parse_manifest("trans.trace 2 1 259 167 71 67").is_err());
+        assert_eq!(parse_manifest("# comment\n\ntrans.trace 2 1 3 167 71 67").unwrap().len(), 1);
+    }
+}
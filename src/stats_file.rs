@@ -0,0 +1,95 @@
+//! Compact binary records of run statistics for large sweeps
+// Each run appends one fixed 16-byte record:
+//     s, E, b, a reserved zero byte, then hits, misses and evictions as
+//     little-endian u32s
+
+use std::error::Error;
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+
+const RECORD_BYTES: usize = 16;
+
+#[derive(Debug, PartialEq)]
+pub struct StatsRecord {
+    pub set_bits: u8,
+    pub lines: u8,
+    pub block_bits: u8,
+    pub hit: u32,
+    pub miss: u32,
+    pub eviction: u32,
+}
+
+impl StatsRecord {
+    fn encode(&self) -> [u8; RECORD_BYTES] {
+        let mut bytes = [0; RECORD_BYTES];
+        bytes[..3].copy_from_slice(&[self.set_bits, self.lines, self.block_bits]);
+        bytes[4..8].copy_from_slice(&self.hit.to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.miss.to_le_bytes());
+        bytes[12..].copy_from_slice(&self.eviction.to_le_bytes());
+        bytes
+    }
+
+    fn decode(bytes: &[u8]) -> StatsRecord {
+        let word = |at: usize| u32::from_le_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]]);
+        StatsRecord {
+            set_bits: bytes[0],
+            lines: bytes[1],
+            block_bits: bytes[2],
+            hit: word(4),
+            miss: word(8),
+            eviction: word(12),
+        }
+    }
+}
+
+/// Append `record` to the file at `path`, creating it if needed
+pub fn append(path: &str, record: &StatsRecord) -> io::Result<()> {
+    OpenOptions::new().create(true).append(true).open(path)?.write_all(&record.encode())
+}
+
+pub fn read_all(bytes: &[u8]) -> Result<Vec<StatsRecord>, Box<dyn Error>> {
+    if !bytes.len().is_multiple_of(RECORD_BYTES) {
+        return Err(format!("Error: Stats file is not a whole number of {}-byte records", RECORD_BYTES).into());
+    }
+    Ok(bytes.chunks(RECORD_BYTES).map(StatsRecord::decode).collect())
+}
+
+/// Lay the records out as a table, one run per row
+pub fn table(records: &[StatsRecord]) -> String {
+    let mut table = format!("{:>3} {:>3} {:>3} {:>10} {:>10} {:>10}\n", "s", "E", "b", "hits", "misses", "evictions");
+    for record in records {
+        table += &format!(
+            "{:>3} {:>3} {:>3} {:>10} {:>10} {:>10}\n",
+            record.set_bits, record.lines, record.block_bits, record.hit, record.miss, record.eviction,
+        );
+    }
+    table
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn round_trip() {
+        let path = std::env::temp_dir().join(format!("csim-stats-{}.bin", std::process::id()));
+        let path = path.to_str().unwrap();
+        let records = vec![
+            StatsRecord { set_bits: 2, lines: 1, block_bits: 3, hit: 167, miss: 71, eviction: 67 },
+            StatsRecord { set_bits: 2, lines: 4, block_bits: 3, hit: 212, miss: 26, eviction: 10 },
+            StatsRecord { set_bits: 5, lines: 1, block_bits: 5, hit: u32::MAX, miss: 0, eviction: 1 << 20 },
+        ];
+
+        let _ = fs::remove_file(path);
+        for record in &records {
+            append(path, record).unwrap();
+        }
+        let bytes = fs::read(path).unwrap();
+        fs::remove_file(path).unwrap();
+
+        assert_eq!(bytes.len(), 3 * RECORD_BYTES);
+        assert_eq!(read_all(&bytes).unwrap(), records);
+        assert!(read_all(&bytes[1..]).is_err());
+    }
+}
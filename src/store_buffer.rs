@@ -0,0 +1,141 @@
+//! Buffer pending stores in front of the cache
+// Stores retire into the buffer and only reach the cache when drained. A load
+// to a block with a pending store is satisfied from the buffer (store-to-load
+// forwarding) without consulting the cache.
+
+use std::collections::VecDeque;
+use crate::valgrind::{MemoryAccess, Operation};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DrainPolicy {
+    /// Drain the oldest store only when a new store finds the buffer full
+    Full,
+    /// Drain the oldest store after every access
+    Eager,
+}
+
+#[derive(Debug)]
+pub struct StoreBuffer {
+    pending: VecDeque<u64>,
+    capacity: usize,
+    drain: DrainPolicy,
+    block_bits: u8,
+}
+
+impl StoreBuffer {
+    pub fn new(capacity: usize, drain: DrainPolicy, block_bits: u8) -> StoreBuffer {
+        StoreBuffer {
+            pending: VecDeque::with_capacity(capacity),
+            capacity,
+            drain,
+            block_bits,
+        }
+    }
+
+    /// Drop the stores pending, without writing them to the cache
+    pub fn clear(&mut self) {
+        self.pending.clear();
+    }
+
+    pub fn drain_policy(&self) -> DrainPolicy {
+        self.drain
+    }
+
+    /// Whether a load of `address` can be forwarded from a pending store
+    pub fn forwards(&self, address: u64) -> bool {
+        let block = address >> self.block_bits;
+        self.pending.iter().any(|&pending| pending >> self.block_bits == block)
+    }
+
+    /// Queue a store, returning the address of any store pushed out to make room
+    pub fn push(&mut self, address: u64) -> Option<u64> {
+        if self.capacity == 0 {
+            return Some(address);
+        }
+
+        let drained = if self.pending.len() == self.capacity { self.pending.pop_front() } else { None };
+        self.pending.push_back(address);
+        drained
+    }
+
+    /// Remove the oldest pending store
+    pub fn pop(&mut self) -> Option<u64> {
+        self.pending.pop_front()
+    }
+
+    /// The accesses of `traces` that reach the cache behind an empty buffer like this one, in
+    /// the order they reach it, ending with the stores still pending
+    // Which stores wait and which loads are forwarded depends on the trace alone, never on the
+    // cache, so OPT can look ahead over exactly the accesses its cache will see
+    pub fn references(&self, traces: &[MemoryAccess]) -> Vec<MemoryAccess> {
+        let mut buffer = StoreBuffer::new(self.capacity, self.drain, self.block_bits);
+        let mut references = Vec::with_capacity(traces.len());
+        for trace in traces {
+            let mut drained = None;
+            match trace.operation {
+                Operation::Store => drained = buffer.push(trace.address),
+                Operation::Load if buffer.forwards(trace.address) => {}
+                _ => references.push(trace.clone()),
+            }
+            if drained.is_none() && buffer.drain == DrainPolicy::Eager {
+                drained = buffer.pop();
+            }
+            references.extend(drained.map(|address| MemoryAccess::new(Operation::Store, address)));
+        }
+        while let Some(address) = buffer.pop() {
+            references.push(MemoryAccess::new(Operation::Store, address));
+        }
+        references
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cache::Cache;
+    use crate::valgrind::parse;
+
+    #[test]
+    fn load_forwards_from_pending_store() {
+        let mut cache = Cache::new(2, 1, 4).unwrap();
+        cache.set_store_buffer(StoreBuffer::new(4, DrainPolicy::Full, 4));
+        cache.operate_cache(parse(" S 20,4\n L 24,4\n L 40,4").unwrap());
+
+        // The load of 0x24 shares a block with the buffered store; the store
+        // itself reaches the cache only when the buffer drains at the end
+        assert_eq!(cache.stats.forwarded, 1);
+        assert_eq!(cache.stats.miss, 2);
+        assert_eq!(cache.stats.hit, 0);
+    }
+
+    #[test]
+    fn eager_drain_empties_buffer() {
+        let mut cache = Cache::new(2, 1, 4).unwrap();
+        cache.set_store_buffer(StoreBuffer::new(4, DrainPolicy::Eager, 4));
+        cache.operate_cache(parse(" S 20,4\n L 24,4").unwrap());
+
+        // The store drained into the cache right away, so the load hits the cache
+        assert_eq!(cache.stats.forwarded, 0);
+        assert_eq!(cache.stats.hit, 1);
+    }
+
+    #[test]
+    fn full_buffer_pushes_out_oldest() {
+        let mut buffer = StoreBuffer::new(2, DrainPolicy::Full, 4);
+        assert_eq!(buffer.push(0x10), None);
+        assert_eq!(buffer.push(0x20), None);
+        assert_eq!(buffer.push(0x30), Some(0x10));
+        assert!(!buffer.forwards(0x18));
+        assert!(buffer.forwards(0x3c));
+    }
+
+    #[test]
+    fn references_behind_buffer() {
+        // The load of 0x14 is forwarded, and the store to 0x10 reaches the cache only when
+        // the store to 0x30 pushes it out
+        let buffer = StoreBuffer::new(1, DrainPolicy::Full, 4);
+        let references = buffer.references(&parse(" S 10,4\n L 14,4\n L 20,4\n S 30,4").unwrap());
+        let addresses: Vec<(Operation, u64)> = references.iter().map(|trace| (trace.operation, trace.address)).collect();
+        assert_eq!(addresses, [(Operation::Load, 0x20), (Operation::Store, 0x10), (Operation::Store, 0x30)]);
+    }
+}
@@ -0,0 +1,103 @@
+//! Translate virtual addresses to physical addresses through a page table
+// The page table file maps one virtual page number to a physical page number
+// per line, both in hex:
+//     7ff000 1a2
+// Blank lines and lines starting with `#` are ignored.
+
+use std::collections::HashMap;
+use std::error::Error;
+use crate::valgrind::MemoryAccess;
+
+#[derive(Debug)]
+pub struct PageTable {
+    page_bits: u8,
+    pages: HashMap<u64, u64>,
+    strict: bool,
+}
+
+impl PageTable {
+    /// Parse a page table; with `strict` set, unmapped pages are an error rather than identity-mapped
+    pub fn parse(input: &str, page_bits: u8, strict: bool) -> Result<PageTable, Box<dyn Error>> {
+        if page_bits >= 64 {
+            return Err("Error: Page bits must be less than 64".into());
+        }
+
+        let mut pages = HashMap::new();
+        for line in input.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() != 2 {
+                return Err(format!("Error: Malformed page table entry \"{}\"", line).into());
+            }
+            let virtual_page = u64::from_str_radix(fields[0], 16)?;
+            let physical_page = u64::from_str_radix(fields[1], 16)?;
+            pages.insert(virtual_page, physical_page);
+        }
+
+        Ok(PageTable { page_bits, pages, strict })
+    }
+
+    pub fn page_bits(&self) -> u8 {
+        self.page_bits
+    }
+
+    pub fn translate(&self, address: u64) -> Result<u64, Box<dyn Error>> {
+        let virtual_page = address >> self.page_bits;
+        let offset = address & ((1_u64 << self.page_bits) - 1);
+
+        match self.pages.get(&virtual_page) {
+            Some(physical_page) => Ok((physical_page << self.page_bits) | offset),
+            None if self.strict => Err(format!("Error: Unmapped virtual page {:#x}", virtual_page).into()),
+            None => Ok(address),
+        }
+    }
+
+    /// Rewrite every access in place with its physical address
+    pub fn translate_all(&self, traces: &mut [MemoryAccess]) -> Result<(), Box<dyn Error>> {
+        for trace in traces.iter_mut() {
+            trace.address = self.translate(trace.address)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cache::Cache;
+    use crate::valgrind::parse;
+
+    #[test]
+    fn remap_changes_set() {
+        // 16-byte pages and blocks: virtual page 1 lands in set 1, physical page 2 in set 2
+        let trace = " L 20,1\n L 10,1";
+
+        let mut identity = Cache::new(2, 1, 4).unwrap();
+        identity.operate_cache(parse(trace).unwrap());
+        assert_eq!(identity.set_accesses(), vec![0, 1, 1, 0]);
+        assert_eq!(identity.stats.hit, 0);
+
+        let table = PageTable::parse("1 2\n", 4, false).unwrap();
+        let mut traces = parse(trace).unwrap();
+        table.translate_all(&mut traces).unwrap();
+
+        let mut remapped = Cache::new(2, 1, 4).unwrap();
+        remapped.operate_cache(traces);
+        assert_eq!(remapped.set_accesses(), vec![0, 0, 2, 0]);
+        assert_eq!(remapped.stats.hit, 1);
+    }
+
+    #[test]
+    fn unmapped_pages() {
+        let table = PageTable::parse("# vpn ppn\n1 2\n", 4, false).unwrap();
+        assert_eq!(table.translate(0x35).unwrap(), 0x35);
+        assert_eq!(table.translate(0x1f).unwrap(), 0x2f);
+
+        let strict = PageTable::parse("1 2\n", 4, true).unwrap();
+        assert!(strict.translate(0x35).is_err());
+    }
+}
@@ -1,10 +1,13 @@
-/// Parse Valgrind memory trace outputs
+//! Parse Valgrind memory trace outputs, and the traces of other tools
 // To generate:
 //     valgrind --log-fd=1 --tool=lackey -v --trace-mem=yes <program>
 
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::error::Error;
+use std::fmt;
+use std::io::{BufRead, Lines};
 
-#[derive(PartialEq, Debug)]
+#[derive(PartialEq, Debug, Clone, Copy)]
 pub enum Operation {
     Load,
     Store,
@@ -12,79 +15,531 @@ pub enum Operation {
     Instruction, 
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub struct MemoryAccess {
     pub operation: Operation,
     pub address: u64,
     size: u8,
+    /// Address of the instruction that made the access, from the preceding `I` line
+    pub pc: Option<u64>,
+    /// Thread that made the access, from an optional leading decimal field
+    pub thread: Option<u32>,
+    /// Half of the load and store a modify line became
+    modify: bool,
+}
+
+impl MemoryAccess {
+    /// An access of unknown size, made by no known instruction or thread; the cache
+    /// simulates it as the one byte at `address`
+    pub fn new(operation: Operation, address: u64) -> MemoryAccess {
+        MemoryAccess { operation, address, size: 0, pc: None, thread: None, modify: false }
+    }
+
+    /// The access of `size` bytes, for accesses made other than by parsing a trace
+    pub fn with_size(mut self, size: u8) -> MemoryAccess {
+        self.size = size;
+        self
+    }
+
+    /// Bytes accessed, or 0 if unknown
+    // Traces that give a size of 0 are dropped as they are parsed, so 0 here
+    // is only ever an unknown size
+    pub fn size(&self) -> u8 {
+        self.size
+    }
+
+    /// Whether the access is the load or the store of a modify line
+    pub fn is_modify(&self) -> bool {
+        self.modify
+    }
+}
+
+/// A malformed line in a trace
+#[derive(Debug, PartialEq)]
+pub struct ParseError {
+    /// 1-based line number
+    pub line: usize,
+    pub content: String,
+    pub reason: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} on line {}: \"{}\"", self.reason, self.line, self.content)
+    }
+}
+
+impl Error for ParseError {}
+
+impl ParseError {
+    /// Record `reason` against the 0-based line `index`
+    fn new(index: usize, content: &str, reason: Box<dyn Error>) -> ParseError {
+        ParseError {
+            line: index + 1,
+            content: content.to_string(),
+            reason: reason.to_string(),
+        }
+    }
+}
+
+pub fn parse(trace_input: &str) -> Result<Vec<MemoryAccess>, ParseError> {
+    parse_lines(trace_input.as_bytes(), false).collect()
+}
+
+/// Parse the trace, keeping instruction fetches as accesses to a unified cache
+pub fn parse_unified(trace_input: &str) -> Result<Vec<MemoryAccess>, ParseError> {
+    parse_lines(trace_input.as_bytes(), true).collect()
 }
 
-pub fn parse(trace_input: &str) -> Result<Vec<MemoryAccess>, Box<dyn Error>> {
+/// The accesses of the trace read from `input`, parsed as they are read
+pub fn parse_lines<R: BufRead>(input: R, unified: bool) -> TraceReader<R> {
+    TraceReader::new(input, unified)
+}
+
+/// A named stretch of the trace, begun by a `# PHASE <name>` marker line
+#[derive(Debug, PartialEq)]
+pub struct Phase {
+    pub name: String,
+    /// Index of the phase's first access
+    pub start: usize,
+}
+
+/// A trace's accesses and phases, and the number of accesses of size 0 dropped from it
+pub type PhasedTrace = (Vec<MemoryAccess>, Vec<Phase>, u64);
+
+/// Parse the trace along with the phases its marker lines divide it into
+pub fn parse_phases(trace_input: &str, unified: bool) -> Result<PhasedTrace, Box<dyn Error>> {
+    let mut traces: Vec<MemoryAccess> = vec![];
+    let mut phases: Vec<Phase> = vec![];
+    let mut size_zero = 0;
+    let mut pc = None;
+
+    for (number, line) in trace_input.lines().enumerate() {
+        match phase_marker(line) {
+            Some(name) => phases.push(Phase { name: name.to_string(), start: traces.len() }),
+            None => {
+                let dropped = parse_line(line, &mut traces, unified, &mut pc).map_err(|reason| ParseError::new(number, line, reason))?;
+                size_zero += dropped as u64;
+            }
+        }
+    }
+
+    Ok((traces, phases, size_zero))
+}
+
+/// The phase named by a `# PHASE <name>` line
+fn phase_marker(line: &str) -> Option<&str> {
+    line.trim().strip_prefix("# PHASE").map(str::trim)
+}
+
+/// Parse the whole trace, reporting every malformed line rather than only the first
+pub fn parse_collect(trace_input: &str, unified: bool) -> Result<Vec<MemoryAccess>, Vec<ParseError>> {
+    let mut traces: Vec<MemoryAccess> = vec![];
+    let mut errors: Vec<ParseError> = vec![];
+    let mut pc = None;
+
+    for (number, line) in trace_input.lines().enumerate() {
+        if let Err(reason) = parse_line(line, &mut traces, unified, &mut pc) {
+            errors.push(ParseError::new(number, line, reason));
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(traces)
+    } else {
+        Err(errors)
+    }
+}
+
+/// Parse the trace, skipping malformed lines; returns the accesses and how many lines were skipped
+pub fn parse_lenient(trace_input: &str, unified: bool) -> (Vec<MemoryAccess>, usize) {
     let mut traces: Vec<MemoryAccess> = vec![];
+    let mut skipped = 0;
+    let mut pc = None;
 
     for line in trace_input.lines() {
-        let trace: Vec<&str> = line.trim().split(" ").filter(|el| !el.is_empty()).collect();
+        // A malformed line appends nothing, so the accesses so far stand
+        if parse_line(line, &mut traces, unified, &mut pc).is_err() {
+            skipped += 1;
+        }
+    }
+    (traces, skipped)
+}
+
+/// Tool a trace file was written by
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TraceFormat {
+    /// Valgrind lackey's ` L 1234,8` lines
+    Valgrind,
+    /// DineroIV's `<type> <hex address>` lines
+    Dinero,
+    /// Pin pinatrace's `<pc>: R <address> <size>` lines
+    Pin,
+}
 
-        if trace.len() != 2 {
-            return Err("Error: Parsing".into());
+impl TraceFormat {
+    /// The format of a `--trace-format` name
+    pub fn from_name(name: &str) -> Option<TraceFormat> {
+        match name {
+            "valgrind" => Some(TraceFormat::Valgrind),
+            "dinero" => Some(TraceFormat::Dinero),
+            "pin" => Some(TraceFormat::Pin),
+            _ => None,
         }
-        
-        let operation = parse_operation(trace[0])?;
-        let (address, size) = parse_address_size(trace[1])?;
+    }
+}
 
-        if operation == Operation::Modify {
-            // A modify is a load and store
-            traces.push(MemoryAccess {
-                operation: Operation::Load,
-                address: address,
-                size: size,
-            });
+/// Parse a DineroIV trace, whose access types are 0 for a read, 1 for a write and 2 for an
+/// instruction fetch
+// Dinero records no sizes, so every access is of unknown size. Fields after the
+// address are ignored, as Dinero itself ignores them.
+pub fn parse_dinero(trace_input: &str, unified: bool) -> Result<Vec<MemoryAccess>, ParseError> {
+    let mut traces: Vec<MemoryAccess> = vec![];
+    let mut pc = None;
 
-            traces.push(MemoryAccess {
-                operation: Operation::Store,
-                address: address,
-                size: size,
-            });
-        } else if operation == Operation::Instruction {
-            // Ignore instruction accesses
-        } else {
-            traces.push(MemoryAccess {
-                operation: operation,
-                address: address,
-                size: size,
-            });
+    for (number, line) in trace_input.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let access = parse_dinero_line(line).map_err(|reason| ParseError::new(number, line, reason))?;
+        if access.operation == Operation::Instruction {
+            pc = Some(access.address);
+            if !unified {
+                continue;
+            }
         }
+        traces.push(MemoryAccess { pc, ..access });
     }
-    
     Ok(traces)
 }
 
+fn parse_dinero_line(line: &str) -> Result<MemoryAccess, Box<dyn Error>> {
+    let mut fields = line.split_whitespace();
+    let operation = match fields.next() {
+        Some("0") => Operation::Load,
+        Some("1") => Operation::Store,
+        Some("2") => Operation::Instruction,
+        _ => return Err("unknown access type".into()),
+    };
+    let address = match fields.next() {
+        Some(address) => parse_hex(address)?,
+        None => return Err("missing address".into()),
+    };
+    Ok(MemoryAccess::new(operation, address))
+}
+
+/// Parse the output of Pin's pinatrace tool, whose lines give the instruction pointer, R or W,
+/// and the effective address and size of each data access; returns the accesses and how many
+/// of size 0 were dropped
+// pinatrace traces no fetches, and the trailing `#eof` and any other `#` line
+// are comments. The instruction pointer is left out of the access, and accesses
+// of size 0 are dropped as lackey's are.
+pub fn parse_pin(trace_input: &str) -> Result<(Vec<MemoryAccess>, u64), ParseError> {
+    let mut traces: Vec<MemoryAccess> = trace_input.lines().enumerate()
+        .filter(|(_, line)| !line.trim().is_empty() && !line.trim_start().starts_with('#'))
+        .map(|(number, line)| parse_pin_line(line).map_err(|reason| ParseError::new(number, line, reason)))
+        .collect::<Result<_, _>>()?;
+    let parsed = traces.len();
+    traces.retain(|trace| trace.size > 0);
+    let size_zero = (parsed - traces.len()) as u64;
+    Ok((traces, size_zero))
+}
+
+fn parse_pin_line(line: &str) -> Result<MemoryAccess, Box<dyn Error>> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    let (operation, address, size) = match fields[..] {
+        [pc, operation, address, size] if pc.ends_with(':') => (operation, address, size),
+        _ => return Err("malformed access".into()),
+    };
+    let operation = match operation {
+        "R" => Operation::Load,
+        "W" => Operation::Store,
+        _ => return Err("unknown operation".into()),
+    };
+    let size = size.parse::<u8>().map_err(|_| "invalid access size")?;
+    Ok(MemoryAccess { size, ..MemoryAccess::new(operation, parse_hex(address)?) })
+}
+
+/// Parse a trace line by line as it is read, holding at most one line's accesses
+pub struct TraceReader<R> {
+    lines: Lines<R>,
+    index: usize,
+    pc: Option<u64>,
+    unified: bool,
+    pending: VecDeque<MemoryAccess>,
+    /// The accesses of the line being parsed, kept to save allocating for every line
+    scratch: Vec<MemoryAccess>,
+    instructions: u64,
+    size_zero: u64,
+}
+
+impl<R: BufRead> TraceReader<R> {
+    pub fn new(input: R, unified: bool) -> TraceReader<R> {
+        TraceReader { lines: input.lines(), index: 0, pc: None, unified, pending: VecDeque::new(), scratch: Vec::with_capacity(2), instructions: 0, size_zero: 0 }
+    }
+
+    /// Number of instruction fetches read so far, whether or not they were simulated
+    pub fn instructions(&self) -> u64 {
+        self.instructions
+    }
+
+    /// Number of accesses of size 0 read so far, which were dropped
+    pub fn size_zero(&self) -> u64 {
+        self.size_zero
+    }
+}
+
+impl<R: BufRead> Iterator for TraceReader<R> {
+    type Item = Result<MemoryAccess, ParseError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.pending.is_empty() {
+            let index = self.index;
+            self.index += 1;
+            // A line that can't be read has no content to quote
+            let line = match self.lines.next()? {
+                Ok(line) => line,
+                Err(error) => return Some(Err(ParseError::new(index, "", error.into()))),
+            };
+
+            match parse_line(&line, &mut self.scratch, self.unified, &mut self.pc) {
+                Ok(size_zero) => self.size_zero += size_zero as u64,
+                Err(reason) => return Some(Err(ParseError::new(index, &line, reason))),
+            }
+            self.instructions += count_instructions(&line);
+            self.pending.extend(self.scratch.drain(..));
+        }
+        self.pending.pop_front().map(Ok)
+    }
+}
+
+/// Describe how each line of the trace parses: its tokens, then the accesses it became or why
+/// it became none
+pub fn dump_parse(trace_input: &str, unified: bool) -> String {
+    let mut dump = String::new();
+    let mut pc = None;
+
+    for (number, line) in trace_input.lines().enumerate() {
+        let tokens: Vec<&str> = line.split(|c: char| c.is_whitespace() || c == ',').filter(|token| !token.is_empty()).collect();
+        dump.push_str(&format!("line {}: {:?}\n", number + 1, tokens));
+
+        let mut traces = vec![];
+        let outcome = match parse_line(line, &mut traces, unified, &mut pc) {
+            Err(reason) => format!("error: {}", reason),
+            Ok(true) => String::from("skipped access of size 0"),
+            Ok(_) if phase_marker(line).is_some() => String::from("phase marker"),
+            Ok(_) if line.trim().is_empty() => String::from("blank line"),
+            Ok(_) if line.trim_start().starts_with('#') => String::from("comment"),
+            Ok(_) if traces.is_empty() => String::from("skipped instruction fetch"),
+            Ok(_) if traces.len() > 1 => String::from("modify expanded to a load and a store"),
+            Ok(_) => String::from("one access"),
+        };
+        dump.push_str(&format!("    {}\n", outcome));
+        for trace in traces {
+            dump.push_str(&format!("    {:?} {:#x},{}", trace.operation, trace.address, trace.size));
+            if let Some(thread) = trace.thread {
+                dump.push_str(&format!(" thread {}", thread));
+            }
+            dump.push('\n');
+        }
+    }
+    dump
+}
+
+/// Parse one line of the trace, appending the accesses it describes to `traces`; returns
+/// whether the line was an access of size 0, which appends none
+// `pc` carries the address of the last instruction fetch from line to line
+fn parse_line(line: &str, traces: &mut Vec<MemoryAccess>, unified: bool, pc: &mut Option<u64>) -> Result<bool, Box<dyn Error>> {
+    // Lines of a hand-written trace may be `#` comments, phase markers among them
+    let mut line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return Ok(false);
+    }
+    let mut thread = None;
+    if let Some((field, rest)) = line.split_once(char::is_whitespace) {
+        if let Ok(id) = field.parse::<u32>() {
+            thread = Some(id);
+            line = rest.trim_start();
+        }
+    }
+
+    // Whitespace may surround the operands as well as the operation
+    let (operation, operands) = match line.split_once(char::is_whitespace) {
+        Some(trace) => trace,
+        None => return Err("malformed access".into()),
+    };
+    
+    let operation = parse_operation(operation)?;
+    let (address, size) = parse_address_size(operands.trim())?;
+    if operation == Operation::Instruction {
+        *pc = Some(address);
+    }
+    let pc = *pc;
+    // Some tracers record barriers as accesses of no bytes, which reach no block
+    if size == 0 {
+        return Ok(true);
+    }
+
+    if operation == Operation::Modify {
+        // A modify is a load and store
+        traces.push(MemoryAccess {
+            operation: Operation::Load,
+            address,
+            size,
+            pc,
+            thread,
+            modify: true,
+        });
+
+        traces.push(MemoryAccess {
+            operation: Operation::Store,
+            address,
+            size,
+            pc,
+            thread,
+            modify: true,
+        });
+    } else if operation == Operation::Instruction && !unified {
+        // Ignore instruction accesses
+    } else {
+        traces.push(MemoryAccess {
+            operation,
+            address,
+            size,
+            pc,
+            thread,
+            modify: false,
+        });
+    }
+
+    Ok(false)
+}
+
+/// Drop instruction fetches from the block fetched by the previous instruction
+// Models the line-fill buffer of an instruction cache: sequential fetches
+// within one block are served by a single access. Data accesses between two
+// fetches don't break the run.
+pub fn coalesce_instructions(traces: Vec<MemoryAccess>, block_bits: u8) -> Vec<MemoryAccess> {
+    let mut fetched_block = None;
+
+    traces.into_iter().filter(|trace| {
+        if trace.operation != Operation::Instruction {
+            return true;
+        }
+        let block = trace.address >> block_bits;
+        fetched_block.replace(block) != Some(block)
+    }).collect()
+}
+
+/// Split each access that spans blocks of `2^block_bits` bytes into one access per block touched
+// An access of unknown size touches only the block of its address
+pub fn split_at_blocks(traces: Vec<MemoryAccess>, block_bits: u8) -> Vec<MemoryAccess> {
+    let mut split = Vec::with_capacity(traces.len());
+    for trace in traces {
+        let end = trace.address.saturating_add(trace.size.max(1) as u64 - 1);
+        if trace.address >> block_bits == end >> block_bits {
+            split.push(trace);
+            continue;
+        }
+        let mut address = trace.address;
+        while address <= end {
+            let block_end = (address | ((1_u64 << block_bits) - 1)).min(end);
+            split.push(MemoryAccess { address, size: (block_end - address + 1) as u8, ..trace.clone() });
+            match block_end.checked_add(1) {
+                Some(next) => address = next,
+                None => break,
+            }
+        }
+    }
+    split
+}
+
+/// Number of instruction fetches in a trace, whether or not the simulation keeps them
+pub fn count_instructions(input: &str) -> u64 {
+    input.lines().filter(|line| {
+        let mut fields = line.split_whitespace();
+        match fields.next() {
+            // Skip a leading thread ID
+            Some(field) if field.parse::<u32>().is_ok() => fields.next() == Some("I"),
+            field => field == Some("I"),
+        }
+    }).count() as u64
+}
+
+/// Drop accesses to address 0, which some tracers emit for null accesses
+pub fn skip_null(traces: &mut Vec<MemoryAccess>) {
+    traces.retain(|trace| trace.address != 0);
+}
+
+/// Number of distinct `2^block_bits`-byte blocks the trace touches
+pub fn distinct_blocks(traces: &[MemoryAccess], block_bits: u8) -> usize {
+    traces.iter().map(|trace| trace.address >> block_bits).collect::<HashSet<u64>>().len()
+}
+
+/// The most common nonzero difference between consecutive addresses, with how many times it
+/// occurs, and the number of nonzero differences
+// Repeated addresses, such as the load and store of a modify, say nothing about
+// striding and are skipped. Ties go to the stride of least magnitude, then the positive one.
+pub fn dominant_stride(traces: &[MemoryAccess]) -> Option<(i64, usize, usize)> {
+    let mut deltas: HashMap<i64, usize> = HashMap::new();
+    for pair in traces.windows(2) {
+        let delta = pair[1].address.wrapping_sub(pair[0].address) as i64;
+        if delta != 0 {
+            *deltas.entry(delta).or_insert(0) += 1;
+        }
+    }
+    let total = deltas.values().sum();
+
+    deltas.into_iter().max_by(|(a, a_count), (b, b_count)| {
+        a_count.cmp(b_count)
+            .then(b.unsigned_abs().cmp(&a.unsigned_abs()))
+            .then(a.cmp(b))
+    }).map(|(stride, count)| (stride, count, total))
+}
+
+/// Whether every access lies within a 32-bit address space
+pub fn fits_in_32_bits(traces: &[MemoryAccess]) -> bool {
+    traces.iter().all(|trace| trace.address <= u32::MAX as u64)
+}
+
 fn parse_address_size(item: &str) -> Result<(u64, u8), Box<dyn Error>> {
-    let operands: Vec<&str> = item.split(",").collect();
+    let operands: Vec<&str> = item.split(",").map(str::trim).collect();
 
     if operands.len() != 2 {
-        return Err("Error: Parsing".into());
+        return Err("malformed address and size".into());
     }
 
-    let address = u64::from_str_radix(operands[0], 16)?;
-    let size = u8::from_str_radix(operands[1], 10)?;
+    let address = match operands[0] {
+        "" => return Err("empty address field".into()),
+        address => parse_hex(address)?,
+    };
+    let size = match operands[1] {
+        "" => return Err("empty size field".into()),
+        size => size.parse::<u8>().map_err(|_| "invalid access size")?,
+    };
 
     Ok((address, size))
 }
 
+/// A hex address, bare as lackey writes it or with the `0x` prefix of other tools
+fn parse_hex(address: &str) -> Result<u64, Box<dyn Error>> {
+    let digits = address.strip_prefix("0x").or_else(|| address.strip_prefix("0X")).unwrap_or(address);
+    u64::from_str_radix(digits, 16).map_err(|_| "invalid hex address".into())
+}
+
 fn parse_operation(op: &str) -> Result<Operation, Box<dyn Error>> {
     match op {
         "L" => Ok(Operation::Load),
         "S" => Ok(Operation::Store),
         "M" => Ok(Operation::Modify),
         "I" => Ok(Operation::Instruction),
-        _ => Err("Error: Parsing".into())
+        _ => Err("unknown operation".into())
     }
 }
 
 #[cfg(test)]
 mod test {
-    use crate::valgrind::{parse, MemoryAccess, Operation};
+    use crate::valgrind::{Phase, TraceReader, coalesce_instructions, dominant_stride, dump_parse, split_at_blocks, parse_phases, skip_null, fits_in_32_bits, parse, parse_collect, parse_dinero, parse_lenient, parse_pin, parse_unified, MemoryAccess, Operation, ParseError};
 
     #[test]
     fn basic_parsing() {
@@ -105,54 +560,286 @@ I 10,1
                 operation: Operation::Load,
                 address: 0x10,
                 size: 1,
+                pc: Some(0x10),
+                thread: None,
+                modify: false,
             },
             MemoryAccess {
                 operation: Operation::Load,
                 address: 0x20,
                 size: 1,
+                pc: Some(0x10),
+                thread: None,
+                modify: true,
             },
             MemoryAccess {
                 operation: Operation::Store,
                 address: 0x20,
                 size: 1,
+                pc: Some(0x10),
+                thread: None,
+                modify: true,
             },
             MemoryAccess {
                 operation: Operation::Load,
                 address: 0x22,
                 size: 1,
+                pc: Some(0x10),
+                thread: None,
+                modify: false,
             },
             MemoryAccess {
                 operation: Operation::Store,
                 address: 0x18,
                 size: 1,
+                pc: Some(0x10),
+                thread: None,
+                modify: false,
             },
             MemoryAccess {
                 operation: Operation::Load,
                 address: 0x110, 
                 size: 1,
+                pc: Some(0x10),
+                thread: None,
+                modify: false,
             },
             MemoryAccess {
                 operation: Operation::Load,
                 address: 0x210,
                 size: 1,
+                pc: Some(0x10),
+                thread: None,
+                modify: false,
             },
             MemoryAccess {
                 operation: Operation::Load,
                 address: 0x12,
                 size: 1,
+                pc: Some(0x10),
+                thread: None,
+                modify: true,
             },
             MemoryAccess {
                 operation: Operation::Store,
                 address: 0x12,
                 size: 1,
+                pc: Some(0x10),
+                thread: None,
+                modify: true,
             },
         ]);
     }
     
+    #[test]
+    fn fixed_stride() {
+        let trace: String = (0..10).map(|i| format!(" L {:x},8\n", 0x1000 + i * 0x40)).collect();
+        assert_eq!(dominant_stride(&parse(&trace).unwrap()), Some((0x40, 9, 9)));
+
+        // Walking down a column of a table: -16 five times beats +64 once
+        assert_eq!(dominant_stride(&parse(" L 60,1\n L 50,1\n L 40,1\n L 30,1\n L 70,1\n L 60,1\n L 50,1").unwrap()), Some((-16, 5, 6)));
+        assert_eq!(dominant_stride(&parse(" L 0,1\n S 0,1").unwrap()), None);
+    }
+
+    #[test]
+    fn null_accesses_skipped() {
+        let mut traces = parse(" L 0,1\n S 8,1\n M 0,4").unwrap();
+        skip_null(&mut traces);
+        assert_eq!(traces.len(), 1);
+        assert_eq!(traces[0].address, 8);
+    }
+
+    #[test]
+    fn phase_markers() {
+        let instructions = " L 10,1\n# PHASE compute\n M 20,1\n# PHASE io\n S 18,1";
+        let (traces, phases, _) = parse_phases(instructions, false).unwrap();
+
+        assert_eq!(traces.len(), 4);
+        assert_eq!(phases, vec![
+            Phase { name: String::from("compute"), start: 1 },
+            Phase { name: String::from("io"), start: 3 },
+        ]);
+        assert_eq!(parse(instructions).unwrap(), traces);
+    }
+
+    #[test]
+    fn accesses_split_at_block_boundaries() {
+        // Eight bytes from 0x4 straddle the 8-byte blocks at 0x0 and 0x8; four from 0x10 don't
+        let split = split_at_blocks(parse(" L 4,8\n S 10,4").unwrap(), 3);
+        let pieces: Vec<(Operation, u64, u8)> = split.iter().map(|trace| (trace.operation, trace.address, trace.size())).collect();
+        assert_eq!(pieces, vec![(Operation::Load, 0x4, 4), (Operation::Load, 0x8, 4), (Operation::Store, 0x10, 4)]);
+    }
+
+    #[test]
+    fn access_of_two_blocks_splits_in_two() {
+        // Sixteen bytes from 0x8 fill both 8-byte blocks exactly; from 0xc they reach a third
+        let pieces = |trace| split_at_blocks(parse(trace).unwrap(), 3).iter().map(|trace| (trace.address, trace.size())).collect::<Vec<(u64, u8)>>();
+        assert_eq!(pieces(" L 8,16"), vec![(0x8, 8), (0x10, 8)]);
+        assert_eq!(pieces(" L c,16"), vec![(0xc, 4), (0x10, 8), (0x18, 4)]);
+    }
+
+    #[test]
+    fn size_zero_accesses_skipped() {
+        let trace = " L 10,0\n S 20,4\n M 30,0";
+        assert_eq!(parse(trace).unwrap(), parse(" S 20,4").unwrap());
+        assert!(dump_parse(trace, false).contains("line 1: [\"L\", \"10\", \"0\"]\n    skipped access of size 0\n"));
+        assert_eq!(parse_pin("0x40: W 0x10 0\n0x44: R 0x20 8\n#eof").unwrap().1, 1);
+        // The label follows the size parsed, not the token as written
+        assert!(dump_parse(" L 10,00", false).contains("skipped access of size 0"));
+        assert!(dump_parse(" L 10,10", false).contains("one access"));
+
+        assert_eq!(parse_phases(trace, false).unwrap().2, 2);
+        let mut reader = TraceReader::new(trace.as_bytes(), false);
+        assert_eq!(reader.by_ref().count(), 1);
+        assert_eq!(reader.size_zero(), 2);
+    }
+
+    #[test]
+    fn parse_dump() {
+        let dump = dump_parse("I 400,2\n M 20,1\n2 L 10,4\n X 10,1", false);
+        assert_eq!(dump, "\
+line 1: [\"I\", \"400\", \"2\"]
+    skipped instruction fetch
+line 2: [\"M\", \"20\", \"1\"]
+    modify expanded to a load and a store
+    Load 0x20,1
+    Store 0x20,1
+line 3: [\"2\", \"L\", \"10\", \"4\"]
+    one access
+    Load 0x10,4 thread 2
+line 4: [\"X\", \"10\", \"1\"]
+    error: unknown operation
+");
+    }
+
+    #[test]
+    fn address_width() {
+        assert!(fits_in_32_bits(&parse(" L ffffffff,1").unwrap()));
+        assert!(!fits_in_32_bits(&parse(" L 100000000,1\n L 10,1").unwrap()));
+    }
+
+    #[test]
+    fn collect_all_errors() {
+        let instructions = " L 10,1\n X 10,1\n S 18,1\n L zz,1\n L 10";
+        let errors = parse_collect(instructions, false).unwrap_err();
+
+        let lines: Vec<usize> = errors.iter().map(|error| error.line).collect();
+        assert_eq!(lines, vec![2, 4, 5]);
+        assert_eq!(errors[2].content, " L 10");
+        assert!(parse_collect(" L 10,1\n S 18,1", false).is_ok());
+    }
+
+    #[test]
+    fn lenient_skips_garbage() {
+        let (traces, skipped) = parse_lenient(" L 10,1\n==123== Warning: noted but unhandled ioctl\n S 18,1", false);
+        assert_eq!(traces, parse(" L 10,1\n S 18,1").unwrap());
+        assert_eq!(skipped, 1);
+    }
+
+    #[test]
+    fn thread_field() {
+        let threads: Vec<Option<u32>> = parse(" L 10,1\n1 L 10,1\n 12  S 18,1").unwrap().iter()
+            .map(|trace| trace.thread)
+            .collect();
+        assert_eq!(threads, vec![None, Some(1), Some(12)]);
+    }
+
+    #[test]
+    fn operand_whitespace() {
+        assert_eq!(parse(" L 10 , 1 ").unwrap(), parse(" L 10,1").unwrap());
+
+        let error = |line: &str| parse(line).unwrap_err().to_string();
+        assert_eq!(error(" L 10,1\n L 10,"), "empty size field on line 2: \" L 10,\"");
+        assert_eq!(error(" L ,1"), "empty address field on line 1: \" L ,1\"");
+        assert_eq!(error(" L 10, "), "empty size field on line 1: \" L 10, \"");
+    }
+
+    #[test]
+    fn parse_error_locates_line() {
+        let error = parse(" L 10,1\n S 18,1\nL ").unwrap_err();
+        assert_eq!(error, ParseError { line: 3, content: String::from("L "), reason: String::from("malformed access") });
+        assert_eq!(error.to_string(), "malformed access on line 3: \"L \"");
+    }
+
+    #[test]
+    fn dinero_traces() {
+        let expected = vec![MemoryAccess::new(Operation::Load, 0x7fffabc), MemoryAccess::new(Operation::Store, 0x7fffac0)];
+        assert_eq!(parse_dinero("0 7fffabc\n1 7fffac0", false).unwrap(), expected);
+
+        // A fetch is the pc of the accesses after it, and an access itself only in a unified cache
+        let traces = parse_dinero("2 400100\n0 7fffabc\n", true).unwrap();
+        let fetches: Vec<(Operation, Option<u64>)> = traces.iter().map(|trace| (trace.operation, trace.pc)).collect();
+        assert_eq!(fetches, vec![(Operation::Instruction, Some(0x400100)), (Operation::Load, Some(0x400100))]);
+        assert_eq!(parse_dinero("4 7fffabc", false).unwrap_err().line, 1);
+    }
+
+    #[test]
+    fn pinatrace_output() {
+        let trace = "0x7f3c9a2b1093: W 0x7ffd4c3e1a68 8\n0x400526: R 0x601040 4\n#eof\n";
+        assert_eq!(parse_pin(trace).unwrap(), (parse(" S 7ffd4c3e1a68,8\n L 601040,4").unwrap(), 0));
+        assert_eq!(parse_pin("0x400526: X 0x601040 4").unwrap_err().line, 1);
+    }
+
+    #[test]
+    fn prefixed_addresses_and_tabs() {
+        assert_eq!(parse(" L 0x10,1\n L 0X10,1").unwrap(), parse(" L 10,1\n L 10,1").unwrap());
+        assert_eq!(parse("\tS\t18,1").unwrap(), parse(" S 18,1").unwrap());
+        assert!(parse(" L 0x,1").is_err());
+    }
+
+    #[test]
+    fn coalesced_fetches() {
+        // Four sequential fetches in the 16-byte block at 0x10, a load, then a fetch from the next block
+        let instructions = "I 10,4\nI 14,4\n L 40,8\nI 18,4\nI 1c,4\nI 20,4";
+        let traces = parse_unified(instructions).unwrap();
+        assert_eq!(traces.len(), 6);
+
+        let accesses: Vec<(Operation, u64)> = coalesce_instructions(traces, 4).iter()
+            .map(|trace| (trace.operation, trace.address))
+            .collect();
+        assert_eq!(accesses, vec![
+            (Operation::Instruction, 0x10),
+            (Operation::Load, 0x40),
+            (Operation::Instruction, 0x20),
+        ]);
+    }
+
+    #[test]
+    fn streamed_matches_parsed() {
+        let instructions = "I 10,1\n M 20,1\n L 22,1\n S 18,1";
+        let streamed: Vec<MemoryAccess> = TraceReader::new(instructions.as_bytes(), false)
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(streamed, parse(instructions).unwrap());
+
+        let mut reader = TraceReader::new(" L 10,1\n L 10".as_bytes(), false);
+        assert!(reader.next().unwrap().is_ok());
+        assert_eq!(reader.next().unwrap().unwrap_err().to_string(), "malformed address and size on line 2: \" L 10\"");
+    }
+
     #[test]
     fn noop() {
         let instructions = "I 10,1";
         let result = parse(instructions).unwrap();
         assert_eq!(result, vec![]);
     }
+
+    #[test]
+    fn blank_lines_skipped() {
+        assert_eq!(parse("").unwrap(), vec![]);
+        assert_eq!(parse("\n\n \t\n").unwrap(), vec![]);
+
+        // A trace file's usual trailing newline, and any blank lines after it
+        let trace = " L 10,1\n\n S 20,1\n\n  \n";
+        assert_eq!(parse(trace).unwrap(), parse(" L 10,1\n S 20,1").unwrap());
+        let streamed: Vec<MemoryAccess> = TraceReader::new(trace.as_bytes(), false).map(Result::unwrap).collect();
+        assert_eq!(streamed.len(), 2);
+    }
+
+    #[test]
+    fn comment_lines_skipped() {
+        let trace = "# phase 1\n L 10,1\n  # the store misses\n S 20,1\n#";
+        assert_eq!(parse(trace).unwrap(), parse(" L 10,1\n S 20,1").unwrap());
+        assert!(dump_parse(trace, false).contains("line 3: [\"#\", \"the\", \"store\", \"misses\"]\n    comment\n"));
+    }
 }
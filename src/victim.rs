@@ -0,0 +1,100 @@
+//! Fully-associative victim cache catching blocks evicted from the main cache
+
+use crate::cache::Line;
+use crate::policy::ReplacementPolicy;
+
+#[derive(Debug)]
+pub struct VictimCache {
+    // Each line's tag is the full block address of the evicted block
+    lines: Box<[Line]>,
+    policy: Box<dyn ReplacementPolicy>,
+    insertions: u64,
+}
+
+impl VictimCache {
+    pub fn new(entries: usize, policy: Box<dyn ReplacementPolicy>) -> VictimCache {
+        let lines: Vec<Line> = (0..entries).map(|_| Line::new(0)).collect();
+        VictimCache { lines: lines.into_boxed_slice(), policy, insertions: 0 }
+    }
+
+    /// Remove `block_address` from the buffer, returning whether it was present
+    pub fn take(&mut self, block_address: u64) -> bool {
+        for line in self.lines.iter_mut() {
+            if line.is_valid() && line.tag() == block_address {
+                line.invalidate();
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Drop every held block
+    pub fn flush(&mut self) {
+        self.lines.iter_mut().for_each(Line::invalidate);
+    }
+
+    /// Hold a block evicted from the main cache, displacing another if full
+    pub fn insert(&mut self, block_address: u64) {
+        if self.lines.is_empty() {
+            return;
+        }
+
+        let id = match self.lines.iter().position(|line| !line.is_valid()) {
+            Some(id) => id,
+            None => self.policy.choose_victim(&self.lines),
+        };
+        // Entries leave on a hit, so insertion order is the only recency there is
+        self.insertions += 1;
+        self.lines[id].fill(block_address, self.insertions);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cache::Cache;
+    use crate::policy::{Lru, Random};
+    use crate::valgrind::parse;
+
+    /// Victim hits when four blocks thrash one direct-mapped set backed by a two-entry victim cache
+    fn thrash(policy: Box<dyn ReplacementPolicy>) -> u32 {
+        let trace: Vec<String> = (0..200).map(|i| format!(" L {:x},1", (i % 4) << 5)).collect();
+
+        let mut cache = Cache::new(1, 1, 4).unwrap();
+        cache.set_victim_cache(VictimCache::new(2, policy));
+        cache.operate_cache(parse(&trace.join("\n")).unwrap());
+        cache.stats.victim_hit
+    }
+
+    #[test]
+    fn victim_policies_differ() {
+        // The cycle is one block longer than the capacity, so LRU always throws
+        // out the next block needed while random eviction sometimes keeps it
+        assert_eq!(thrash(Box::new(Lru)), 0);
+        assert!(thrash(Box::new(Random::new(1))) > 0);
+    }
+
+    #[test]
+    fn victim_hit_swaps_block_back() {
+        let mut cache = Cache::new(1, 1, 4).unwrap();
+        cache.set_victim_cache(VictimCache::new(1, Box::new(Lru)));
+        cache.operate_cache(parse(" L 0,1\n L 20,1\n L 0,1\n L 20,1").unwrap());
+
+        assert_eq!(cache.stats.miss, 2);
+        assert_eq!(cache.stats.victim_hit, 2);
+    }
+
+    #[test]
+    fn relieves_conflict_thrashing() {
+        // Two blocks alternating in one direct-mapped set miss every time without a victim cache
+        let trace = parse(&(0..20).map(|i| format!(" L {:x},1", (i % 2) << 5)).collect::<Vec<String>>().join("\n")).unwrap();
+        let mut plain = Cache::new(1, 1, 4).unwrap();
+        plain.operate_cache(trace.clone());
+        assert_eq!((plain.stats.miss, plain.stats.victim_hit), (20, 0));
+
+        let mut cache = Cache::new(1, 1, 4).unwrap();
+        cache.set_victim_cache(VictimCache::new(1, Box::new(Lru)));
+        cache.operate_cache(trace);
+        assert_eq!((cache.stats.miss, cache.stats.victim_hit), (2, 18));
+    }
+}
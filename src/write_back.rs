@@ -0,0 +1,78 @@
+//! Model a finite buffer of dirty blocks on their way back to memory
+// Each dirty eviction takes an entry, and entries drain at `drain_rate` per
+// access. An eviction that finds the buffer full stalls until one entry
+// drains and takes its place. An empty buffer banks no drain credit.
+
+#[derive(Debug)]
+pub struct WriteBackBuffer {
+    depth: usize,
+    drain_rate: f64,
+    credit: f64,
+    occupancy: usize,
+    pub writebacks: u64,
+    pub stalls: u64,
+}
+
+impl WriteBackBuffer {
+    pub fn new(depth: usize, drain_rate: f64) -> WriteBackBuffer {
+        WriteBackBuffer { depth, drain_rate, credit: 0.0, occupancy: 0, writebacks: 0, stalls: 0 }
+    }
+
+    /// Empty the buffer and forget the writebacks and stalls counted
+    pub fn reset(&mut self) {
+        self.credit = 0.0;
+        self.occupancy = 0;
+        self.writebacks = 0;
+        self.stalls = 0;
+    }
+
+    /// Drain the buffer by one access's worth
+    pub fn tick(&mut self) {
+        self.credit += self.drain_rate;
+        let drained = (self.credit as usize).min(self.occupancy);
+        self.occupancy -= drained;
+        self.credit -= drained as f64;
+        if self.occupancy == 0 {
+            self.credit = 0.0;
+        }
+    }
+
+    /// Queue a dirty block for writing back
+    pub fn enqueue(&mut self) {
+        self.writebacks += 1;
+        if self.occupancy == self.depth {
+            self.stalls += 1;
+            self.credit = 0.0;
+        } else {
+            self.occupancy += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cache::Cache;
+    use crate::valgrind::parse;
+
+    #[test]
+    fn dirty_burst_overflows() {
+        // Six blocks through one direct-mapped line, the first five stored:
+        // five dirty evictions into a two-entry buffer draining every other
+        // access, of which the last two find it full
+        let mut cache = Cache::new(1, 1, 4).unwrap();
+        cache.set_write_back_buffer(WriteBackBuffer::new(2, 0.5));
+        cache.operate_cache(parse(" S 0,1\n S 20,1\n S 40,1\n S 60,1\n S 80,1\n L 0,1").unwrap());
+
+        let buffer = cache.write_back_buffer().unwrap();
+        assert_eq!((buffer.writebacks, buffer.stalls), (5, 2));
+    }
+
+    #[test]
+    fn clean_evictions_skip_the_buffer() {
+        let mut cache = Cache::new(1, 1, 4).unwrap();
+        cache.set_write_back_buffer(WriteBackBuffer::new(1, 0.0));
+        cache.operate_cache(parse(" L 0,1\n L 20,1\n L 40,1").unwrap());
+        assert_eq!(cache.write_back_buffer().unwrap().writebacks, 0);
+    }
+}